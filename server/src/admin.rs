@@ -0,0 +1,422 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use cidr::IpCidr;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::error::LrthromeResult;
+use crate::export::Export;
+
+/// Command issued by an operator, computed on the main thread.
+pub enum Command {
+    /// Snapshot the tree for export.
+    Export(oneshot::Sender<Export>),
+
+    /// Render metrics in Prometheus text format.
+    Metrics(oneshot::Sender<String>),
+
+    /// Adjust a tunable, replied to with its previous value.
+    Set(Tunable, oneshot::Sender<String>),
+
+    /// Disconnect matching peers, replied to with the number disconnected.
+    Kick(Kick, oneshot::Sender<usize>),
+
+    /// Ban a network for the duration, or permanently if none,
+    /// replied to with the number of its peers disconnected.
+    Ban(IpCidr, Option<Duration>, oneshot::Sender<usize>),
+
+    /// Lift the ban of a network, replied to with whether it was banned.
+    Unban(IpCidr, oneshot::Sender<bool>),
+
+    /// List the bans, one per line.
+    Bans(oneshot::Sender<String>),
+
+    /// Temper now, swapping the tree in even if it shrunk below `min_tree_percent`.
+    ForceTemper(oneshot::Sender<()>),
+}
+
+/// Peers to disconnect, and whether their address is banned from reconnecting.
+#[derive(Debug, PartialEq)]
+pub struct Kick {
+    pub target: KickTarget,
+
+    /// Sent to the peers as an error before disconnecting, if any.
+    pub reason: Option<String>,
+
+    /// Refuse connections from the address for the configured ban duration.
+    pub ban: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KickTarget {
+    /// A single connection.
+    Peer(SocketAddr),
+
+    /// Every connection of the address.
+    Ip(IpAddr),
+
+    /// Every connection within the network.
+    Network(IpCidr),
+}
+
+impl Kick {
+    /// Parse `kick <socketaddr>`, `kick-ip <ip>` & `ban-ip <ip>`,
+    /// the remaining arguments being the reason.
+    pub fn parse<'a, I>(command: &str, mut args: I) -> Result<Self, String>
+    where
+        I: Iterator<Item = &'a str>,
+    {
+        let target = args
+            .next()
+            .ok_or_else(|| format!("usage: {} <address> [reason]", command))?;
+
+        let invalid = |_| format!("invalid address {}", target);
+
+        let (target, ban) = match command {
+            "kick" => (KickTarget::Peer(target.parse().map_err(invalid)?), false),
+            "kick-ip" => (KickTarget::Ip(target.parse().map_err(invalid)?), false),
+            "ban-ip" => (KickTarget::Ip(target.parse().map_err(invalid)?), true),
+            _ => return Err(format!("unknown command {}", command)),
+        };
+
+        let reason = args.collect::<Vec<_>>().join(" ");
+
+        Ok(Self {
+            target,
+            reason: Some(reason).filter(|r| !r.is_empty()),
+            ban,
+        })
+    }
+}
+
+/// Setting adjustable at runtime, applying to new connections & requests.
+#[derive(Debug, PartialEq)]
+pub enum Tunable {
+    /// Maximum rate over the span of the rate limit window per IP address.
+    ///
+    /// Address meters are reset, identity meters are unaffected.
+    RateLimit(NonZeroU32),
+
+    /// Number of requests a new connection may make before rate limits are enforced.
+    RateLimitGrace(u32),
+
+    /// Number of in-flight requests & queued responses a new connection may have.
+    MaxInFlight(u32),
+
+    /// Most clients connected at once, 0 if unlimited.
+    ///
+    /// Connected clients over the limit are kept, new ones refused.
+    MaxConnections(u32),
+
+    /// Most clients connected at once from a single IP address, 0 if unlimited.
+    MaxConnectionsPerIp(u32),
+}
+
+impl Tunable {
+    pub fn parse(name: &str, value: &str) -> Result<Self, String> {
+        let invalid = |_| format!("invalid value {} for {}", value, name);
+
+        match name {
+            "rate_limit" => value.parse().map(Tunable::RateLimit).map_err(invalid),
+            "rate_limit_grace" => value.parse().map(Tunable::RateLimitGrace).map_err(invalid),
+            "max_in_flight" => match value.parse().map_err(invalid)? {
+                0 => Err(
+                    "max_in_flight must be at least 1, or clients are never read from".to_string(),
+                ),
+                max => Ok(Tunable::MaxInFlight(max)),
+            },
+            "max_connections" => value.parse().map(Tunable::MaxConnections).map_err(invalid),
+            "max_connections_per_ip" => value
+                .parse()
+                .map(Tunable::MaxConnectionsPerIp)
+                .map_err(invalid),
+            _ => Err(format!("unknown tunable {}", name)),
+        }
+    }
+}
+
+/// Network & duration of a `ban`, permanent if no seconds are given.
+fn parse_ban(cidr: &str, secs: Option<&str>) -> Result<(IpCidr, Option<Duration>), String> {
+    let cidr = cidr
+        .parse()
+        .map_err(|_| format!("invalid network {}", cidr))?;
+
+    let dur = secs
+        .map(|secs| {
+            secs.parse()
+                .map(Duration::from_secs)
+                .map_err(|_| format!("invalid seconds {}", secs))
+        })
+        .transpose()?;
+
+    Ok((cidr, dur))
+}
+
+/// Accept operator connections on the admin listener.
+///
+/// Each line received is a command, such as `export`,
+/// replied to in plain text so the interface is usable with netcat.
+/// There is no authentication, the listener must only be reachable by operators.
+pub async fn serve(listener: TcpListener, tx: mpsc::UnboundedSender<Command>) {
+    info!("Started processing admin connections");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                debug!("Admin has connected (addr = {})", addr);
+
+                let tx = tx.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = process(stream, tx).await {
+                        warn!("Admin connection failed (addr = {}): {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => error!("Unable to accept admin connection: {}", e),
+        }
+    }
+}
+
+async fn process(stream: TcpStream, tx: mpsc::UnboundedSender<Command>) -> LrthromeResult<()> {
+    let (reader, writer) = stream.into_split();
+
+    let mut lines = BufReader::new(reader).lines();
+    let mut writer = BufWriter::new(writer);
+
+    while let Some(line) = lines.next_line().await? {
+        let mut args = line.split_whitespace();
+
+        match args.next() {
+            Some("export") => {
+                let (tx_export, rx_export) = oneshot::channel();
+
+                if tx.send(Command::Export(tx_export)).is_err() {
+                    break;
+                }
+
+                // Streamed from the snapshot, the tree itself is not held while writing.
+                if let Ok(export) = rx_export.await {
+                    for cidr in export {
+                        writer.write_all(format!("{}\n", cidr).as_bytes()).await?;
+                    }
+                }
+            }
+            Some("metrics") => {
+                let (tx_metrics, rx_metrics) = oneshot::channel();
+
+                if tx.send(Command::Metrics(tx_metrics)).is_err() {
+                    break;
+                }
+
+                if let Ok(metrics) = rx_metrics.await {
+                    writer.write_all(metrics.as_bytes()).await?;
+                }
+            }
+            Some("set") => {
+                let tunable = match (args.next(), args.next()) {
+                    (Some(name), Some(value)) => Tunable::parse(name, value),
+                    _ => Err("usage: set <tunable> <value>".to_string()),
+                };
+
+                match tunable {
+                    Ok(tunable) => {
+                        let (tx_set, rx_set) = oneshot::channel();
+
+                        if tx.send(Command::Set(tunable, tx_set)).is_err() {
+                            break;
+                        }
+
+                        if let Ok(previous) = rx_set.await {
+                            writer
+                                .write_all(format!("OK was {}\n", previous).as_bytes())
+                                .await?;
+                        }
+                    }
+                    Err(e) => writer.write_all(format!("ERR {}\n", e).as_bytes()).await?,
+                }
+            }
+            Some(command @ ("kick" | "kick-ip" | "ban-ip")) => match Kick::parse(command, args) {
+                Ok(kick) => {
+                    let (tx_kick, rx_kick) = oneshot::channel();
+
+                    if tx.send(Command::Kick(kick, tx_kick)).is_err() {
+                        break;
+                    }
+
+                    if let Ok(kicked) = rx_kick.await {
+                        writer
+                            .write_all(format!("OK kicked {}\n", kicked).as_bytes())
+                            .await?;
+                    }
+                }
+                Err(e) => writer.write_all(format!("ERR {}\n", e).as_bytes()).await?,
+            },
+            Some("ban") => {
+                let ban = match (args.next(), args.next()) {
+                    (Some(cidr), secs) => parse_ban(cidr, secs),
+                    _ => Err("usage: ban <cidr> [secs]".to_string()),
+                };
+
+                match ban {
+                    Ok((cidr, dur)) => {
+                        let (tx_ban, rx_ban) = oneshot::channel();
+
+                        if tx.send(Command::Ban(cidr, dur, tx_ban)).is_err() {
+                            break;
+                        }
+
+                        if let Ok(kicked) = rx_ban.await {
+                            writer
+                                .write_all(format!("OK kicked {}\n", kicked).as_bytes())
+                                .await?;
+                        }
+                    }
+                    Err(e) => writer.write_all(format!("ERR {}\n", e).as_bytes()).await?,
+                }
+            }
+            Some("unban") => match args.next().map(str::parse::<IpCidr>) {
+                Some(Ok(cidr)) => {
+                    let (tx_unban, rx_unban) = oneshot::channel();
+
+                    if tx.send(Command::Unban(cidr, tx_unban)).is_err() {
+                        break;
+                    }
+
+                    match rx_unban.await {
+                        Ok(true) => writer.write_all(b"OK\n").await?,
+                        Ok(false) => writer.write_all(b"ERR not banned\n").await?,
+                        Err(_) => (),
+                    }
+                }
+                _ => writer.write_all(b"ERR usage: unban <cidr>\n").await?,
+            },
+            Some("bans") => {
+                let (tx_bans, rx_bans) = oneshot::channel();
+
+                if tx.send(Command::Bans(tx_bans)).is_err() {
+                    break;
+                }
+
+                if let Ok(bans) = rx_bans.await {
+                    writer.write_all(bans.as_bytes()).await?;
+                }
+            }
+            Some("force-temper") => {
+                let (tx_temper, rx_temper) = oneshot::channel();
+
+                if tx.send(Command::ForceTemper(tx_temper)).is_err() {
+                    break;
+                }
+
+                if rx_temper.await.is_ok() {
+                    writer.write_all(b"OK\n").await?;
+                }
+            }
+            Some(command) => {
+                writer
+                    .write_all(format!("ERR unknown command {}\n", command).as_bytes())
+                    .await?;
+            }
+            None => continue,
+        }
+
+        writer.flush().await?;
+    }
+
+    Ok(())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parse_tunables() {
+        assert_eq!(
+            Tunable::parse("rate_limit", "200"),
+            Ok(Tunable::RateLimit(NonZeroU32::new(200).unwrap()))
+        );
+        assert_eq!(
+            Tunable::parse("max_in_flight", "8"),
+            Ok(Tunable::MaxInFlight(8))
+        );
+        assert_eq!(
+            Tunable::parse("max_connections_per_ip", "4"),
+            Ok(Tunable::MaxConnectionsPerIp(4))
+        );
+        assert_eq!(
+            Tunable::parse("max_connections", "0"),
+            Ok(Tunable::MaxConnections(0))
+        );
+        assert!(Tunable::parse("max_in_flight", "0").is_err());
+        assert!(Tunable::parse("rate_limit", "0").is_err());
+        assert!(Tunable::parse("rate_limit_grace", "-1").is_err());
+        assert!(Tunable::parse("banner", "fish").is_err());
+    }
+
+    #[test]
+    fn parse_kicks() {
+        let parse = |line: &str| {
+            let mut args = line.split_whitespace();
+
+            Kick::parse(args.next().unwrap(), args)
+        };
+
+        assert_eq!(
+            parse("kick 10.0.0.1:27015"),
+            Ok(Kick {
+                target: KickTarget::Peer("10.0.0.1:27015".parse().unwrap()),
+                reason: None,
+                ban: false,
+            })
+        );
+        assert_eq!(
+            parse("ban-ip 10.0.0.1 request flood"),
+            Ok(Kick {
+                target: KickTarget::Ip("10.0.0.1".parse().unwrap()),
+                reason: Some("request flood".to_string()),
+                ban: true,
+            })
+        );
+        assert!(parse("kick 10.0.0.1").is_err());
+        assert!(parse("kick-ip").is_err());
+    }
+
+    #[test]
+    fn parse_bans() {
+        assert_eq!(
+            parse_ban("10.0.0.0/8", None),
+            Ok(("10.0.0.0/8".parse().unwrap(), None))
+        );
+        assert_eq!(
+            parse_ban("10.0.0.1", Some("60")),
+            Ok((
+                "10.0.0.1/32".parse().unwrap(),
+                Some(Duration::from_secs(60))
+            ))
+        );
+        assert!(parse_ban("10.0.0.1/8", None).is_err());
+        assert!(parse_ban("10.0.0.0/8", Some("forever")).is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+
+use serde::Deserialize;
+
+use crate::error::LrthromeResult;
+
+/// Identity a peer authenticates as with an `Identify` token.
+#[derive(Clone)]
+pub struct Identity {
+    /// Name of the token's table, attributed in logs instead of the token itself.
+    pub name: String,
+
+    /// Maximum rate over the span of the rate limit window, in place of the IP address based limit.
+    pub rate_limit: NonZeroU32,
+
+    /// Permitted to request lookups disregarding the allowlist.
+    pub ignore_allow: bool,
+
+    /// Key responses are signed with once identified.
+    pub signing_key: Option<SigningKey>,
+}
+
+/// Pre-shared key responses are signed with.
+///
+/// The HMAC-SHA256 of the frame, header included, is appended to it.
+#[derive(Clone)]
+pub struct SigningKey(PKey<Private>);
+
+impl SigningKey {
+    /// Length of the appended signature.
+    pub const SIGNATURE_LEN: usize = 32;
+
+    pub fn new(secret: &[u8]) -> LrthromeResult<Self> {
+        Ok(Self(PKey::hmac(secret)?))
+    }
+
+    /// Append the signature of the frame.
+    pub fn sign(&self, frame: Bytes) -> LrthromeResult<Bytes> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.0)?;
+
+        signer.update(&frame)?;
+
+        let mut buf = BytesMut::with_capacity(frame.len() + Self::SIGNATURE_LEN);
+
+        buf.put_slice(&frame);
+        buf.put_slice(&signer.sign_to_vec()?);
+
+        Ok(buf.freeze())
+    }
+}
+
+/// Entry of the token file, keyed by identity name.
+#[derive(Deserialize)]
+struct TokenEntry {
+    token: String,
+
+    rate_limit: NonZeroU32,
+
+    #[serde(default)]
+    ignore_allow: bool,
+
+    signing_key: Option<String>,
+}
+
+/// Identify tokens, loaded from a file kept apart from the main config.
+pub struct Auth {
+    token_file: PathBuf,
+
+    tokens: Vec<(String, Identity)>,
+}
+
+impl Auth {
+    pub fn load<P: Into<PathBuf>>(token_file: P) -> LrthromeResult<Self> {
+        let mut auth = Self {
+            token_file: token_file.into(),
+            tokens: Vec::new(),
+        };
+
+        auth.reload()?;
+
+        Ok(auth)
+    }
+
+    /// Re-read the token file, the current tokens are retained if it cannot be read.
+    pub fn reload(&mut self) -> LrthromeResult<()> {
+        warn_permissions(&self.token_file);
+
+        let entries: HashMap<String, TokenEntry> =
+            toml::from_slice(&std::fs::read(&self.token_file)?)?;
+
+        self.tokens = entries
+            .into_iter()
+            .map(|(name, entry)| {
+                let signing_key = match entry.signing_key {
+                    Some(secret) => Some(SigningKey::new(secret.as_bytes())?),
+                    None => None,
+                };
+
+                Ok((
+                    entry.token,
+                    Identity {
+                        name,
+                        rate_limit: entry.rate_limit,
+                        ignore_allow: entry.ignore_allow,
+                        signing_key,
+                    },
+                ))
+            })
+            .collect::<LrthromeResult<_>>()?;
+
+        info!(
+            "Loaded {} identify tokens from {}",
+            self.tokens.len(),
+            self.token_file.display()
+        );
+
+        Ok(())
+    }
+
+    /// Identity of the token.
+    ///
+    /// Every token is compared in full, so the time taken does not reveal
+    /// how much of a valid token was guessed.
+    pub fn identify(&self, token: &str) -> Option<&Identity> {
+        let mut found = None;
+
+        for (candidate, identity) in &self.tokens {
+            if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                found = Some(identity);
+            }
+        }
+
+        found
+    }
+
+    /// Identity by name, for peers that already identified.
+    pub fn find(&self, name: &str) -> Option<&Identity> {
+        self.identities().find(|identity| identity.name == name)
+    }
+
+    pub fn identities(&self) -> impl Iterator<Item = &Identity> {
+        self.tokens.iter().map(|(_, identity)| identity)
+    }
+}
+
+/// Compare without short-circuiting on the first differing byte.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+
+        diff |= (x ^ y) as usize;
+    }
+
+    diff == 0
+}
+
+#[cfg(unix)]
+fn warn_permissions(path: &std::path::Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(meta) = std::fs::metadata(path) {
+        if meta.permissions().mode() & 0o004 != 0 {
+            warn!(
+                "Token file {} is world-readable, consider restricting it to the server's user",
+                path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_permissions(_path: &std::path::Path) {}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn compare_tokens() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn load_and_identify() {
+        let file = std::env::temp_dir().join(format!("lrthrome-tokens-{}", std::process::id()));
+
+        std::fs::write(
+            &file,
+            "[partner]\ntoken = \"partner-secret\"\nrate_limit = 100\nignore_allow = true\n",
+        )
+        .unwrap();
+
+        let auth = Auth::load(&file).unwrap();
+
+        let identity = auth.identify("partner-secret").unwrap();
+
+        assert_eq!(identity.name, "partner");
+        assert_eq!(identity.rate_limit.get(), 100);
+        assert!(identity.ignore_allow);
+        assert!(identity.signing_key.is_none());
+
+        assert!(auth.identify("partner-secreT").is_none());
+        assert!(auth.identify("").is_none());
+        assert!(auth.find("partner").is_some());
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn sign_frame() {
+        let key = SigningKey::new(b"Jefe").unwrap();
+
+        let signed = key
+            .sign(Bytes::from_static(b"what do ya want for nothing?"))
+            .unwrap();
+
+        // RFC 4231, test case 2.
+        assert_eq!(&signed[..28], b"what do ya want for nothing?");
+        assert_eq!(
+            &signed[28..],
+            &[
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95,
+                0x75, 0xc7, 0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9,
+                0x64, 0xec, 0x38, 0x43,
+            ]
+        );
+    }
+}
@@ -0,0 +1,309 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Addresses banned by operators, refused at accept before the handshake.
+//!
+//! Unlike the tree, which answers peers' lookups, bans protect the server itself.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cidr::{Cidr, Inet, IpCidr, IpInet};
+
+use tokio::task::JoinHandle;
+
+use treebitmap::IpLookupTable;
+
+use crate::error::LrthromeResult;
+
+/// Banned networks with the unix timestamp in seconds their ban expires, none if permanent.
+pub struct Bans {
+    entries: BTreeMap<IpCidr, Option<u64>>,
+
+    /// Bans of `entries` by family, so a check upon accept is a lookup rather than a scan.
+    v4: IpLookupTable<Ipv4Addr, Option<u64>>,
+
+    v6: IpLookupTable<Ipv6Addr, Option<u64>>,
+
+    /// File the bans are persisted to upon every change, kept in memory only if absent.
+    file: Option<PathBuf>,
+
+    /// Latest write of the file, awaited by the next so writes land in order.
+    writing: Option<JoinHandle<()>>,
+}
+
+impl Bans {
+    /// Load the bans persisted to the file, none if it does not exist yet.
+    ///
+    /// Each line is a network followed by its expiry, or `never` if permanent.
+    pub fn load<P: Into<PathBuf>>(file: P) -> LrthromeResult<Self> {
+        let file = file.into();
+
+        let body = match std::fs::read_to_string(&file) {
+            Ok(body) => body,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut bans = Self {
+            file: Some(file),
+            ..Self::default()
+        };
+
+        for line in body.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+
+            let cidr = fields.next().unwrap_or_default().parse()?;
+            let expiry = match fields.next() {
+                None | Some("never") => None,
+                Some(expiry) => Some(expiry.parse()?),
+            };
+
+            bans.insert(cidr, expiry);
+        }
+
+        let expired = bans.expire();
+
+        info!(
+            "Loaded {} bans, {} of which expired while stopped",
+            bans.entries.len() + expired,
+            expired
+        );
+
+        Ok(bans)
+    }
+
+    /// Ban the network for the duration, or permanently if none.
+    pub fn ban(&mut self, cidr: IpCidr, dur: Option<Duration>) {
+        self.insert(cidr, dur.map(|dur| unix_now() + dur.as_secs()));
+
+        self.persist();
+    }
+
+    /// Lift the ban of exactly the network, whether it was banned.
+    pub fn unban(&mut self, cidr: &IpCidr) -> bool {
+        let banned = self.remove(cidr);
+
+        if banned {
+            self.persist();
+        }
+
+        banned
+    }
+
+    /// Whether the address falls within a ban yet to expire.
+    ///
+    /// Peer addresses are expected normalized beforehand.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let now = unix_now();
+        let live = |expiry: &Option<u64>| expiry.is_none_or(|e| e > now);
+
+        let longest = match ip {
+            IpAddr::V4(v4) => self.v4.longest_match(v4).map(|(_, len, e)| (len, *e)),
+            IpAddr::V6(v6) => self.v6.longest_match(v6).map(|(_, len, e)| (len, *e)),
+        };
+
+        match longest {
+            None => false,
+            Some((_, expiry)) if live(&expiry) => true,
+            // Expired yet to be forgotten, a broader ban may still apply.
+            Some((len, _)) => (0..len as u8).rev().any(|len| {
+                let network = IpInet::new(ip, len).unwrap().network();
+
+                self.entries.get(&network).is_some_and(live)
+            }),
+        }
+    }
+
+    /// Forget expired bans, returning the number forgotten.
+    pub fn expire(&mut self) -> usize {
+        let now = unix_now();
+        let expired = self
+            .entries
+            .iter()
+            .filter(|(_, expiry)| expiry.is_some_and(|e| e <= now))
+            .map(|(cidr, _)| cidr.clone())
+            .collect::<Vec<_>>();
+
+        for cidr in &expired {
+            self.remove(cidr);
+        }
+
+        let expired = expired.len();
+
+        if expired > 0 {
+            debug!("Expired {} bans", expired);
+
+            self.persist();
+        }
+
+        expired
+    }
+
+    /// One ban per line, as persisted.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+
+        for (cidr, expiry) in &self.entries {
+            let _ = match expiry {
+                Some(expiry) => writeln!(out, "{} {}", cidr, expiry),
+                None => writeln!(out, "{} never", cidr),
+            };
+        }
+
+        out
+    }
+
+    /// Wait for the latest write of the file to land.
+    pub async fn flush(&mut self) {
+        if let Some(writing) = self.writing.take() {
+            let _ = writing.await;
+        }
+    }
+
+    fn insert(&mut self, cidr: IpCidr, expiry: Option<u64>) {
+        let len = cidr.network_length() as u32;
+
+        match &cidr {
+            IpCidr::V4(c) => self.v4.insert(c.first_address(), len, expiry),
+            IpCidr::V6(c) => self.v6.insert(c.first_address(), len, expiry),
+        };
+
+        self.entries.insert(cidr, expiry);
+    }
+
+    fn remove(&mut self, cidr: &IpCidr) -> bool {
+        let len = cidr.network_length() as u32;
+
+        match cidr {
+            IpCidr::V4(c) => self.v4.remove(c.first_address(), len),
+            IpCidr::V6(c) => self.v6.remove(c.first_address(), len),
+        };
+
+        self.entries.remove(cidr).is_some()
+    }
+
+    /// Write the bans out in the background, replacing the file only once fully written.
+    ///
+    /// A failure is logged, the bans still apply until restart.
+    fn persist(&mut self) {
+        let file = match &self.file {
+            Some(file) => file.clone(),
+            None => return,
+        };
+
+        let text = self.to_text();
+        let previous = self.writing.take();
+
+        self.writing = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+
+            let tmp = file.with_extension("tmp");
+
+            let result = match tokio::fs::write(&tmp, text).await {
+                Ok(()) => tokio::fs::rename(&tmp, &file).await,
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                error!("Unable to persist bans to {}: {}", file.display(), e);
+            }
+        }));
+    }
+}
+
+impl Default for Bans {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+            v4: IpLookupTable::new(),
+            v6: IpLookupTable::new(),
+            file: None,
+            writing: None,
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn ban_and_expire() {
+        let mut bans = Bans::default();
+
+        bans.ban("10.0.0.0/8".parse().unwrap(), None);
+        bans.ban(
+            "192.168.1.1".parse().unwrap(),
+            Some(Duration::from_secs(60)),
+        );
+        bans.insert("172.16.0.0/12".parse().unwrap(), Some(unix_now() - 1));
+        bans.insert("10.1.0.0/16".parse().unwrap(), Some(unix_now() - 1));
+        bans.ban("2001:db8::/32".parse().unwrap(), None);
+
+        assert!(bans.contains("10.1.2.3".parse().unwrap()));
+        assert!(bans.contains("2001:db8::1".parse().unwrap()));
+        assert!(!bans.contains("2001:db9::1".parse().unwrap()));
+        assert!(bans.contains("192.168.1.1".parse().unwrap()));
+        assert!(!bans.contains("192.168.1.2".parse().unwrap()));
+        assert!(!bans.contains("172.16.0.1".parse().unwrap()));
+
+        assert_eq!(bans.expire(), 2);
+        assert!(bans.unban(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!bans.unban(&"10.0.0.0/8".parse().unwrap()));
+        assert!(!bans.contains("10.1.2.3".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn persist_across_loads() {
+        let file = std::env::temp_dir().join(format!("lrthrome-bans-{}", std::process::id()));
+
+        let _ = std::fs::remove_file(&file);
+
+        let mut bans = Bans::load(&file).unwrap();
+
+        bans.ban("10.0.0.0/8".parse().unwrap(), None);
+        bans.ban(
+            "192.168.1.1".parse().unwrap(),
+            Some(Duration::from_secs(60)),
+        );
+        bans.flush().await;
+
+        let bans = Bans::load(&file).unwrap();
+
+        assert!(bans.contains("10.1.2.3".parse().unwrap()));
+        assert!(bans.contains("192.168.1.1".parse().unwrap()));
+        assert!(bans.to_text().starts_with("10.0.0.0/8 never\n"));
+
+        std::fs::remove_file(&file).unwrap();
+    }
+}
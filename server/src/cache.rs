@@ -14,56 +14,1848 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::net::Ipv4Addr;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use cidr::Cidr;
+use bytes::{Buf, BufMut, BytesMut};
+use cidr::{Cidr, IpCidr, Ipv4Cidr, Ipv6Cidr};
+use futures::StreamExt;
 use treebitmap::IpLookupTable;
 
-use crate::error::LrthromeResult;
-use crate::sources::Sources;
+use crate::config::Mode;
+use crate::error::{LrthromeError, LrthromeResult};
+use crate::export::Export;
+use crate::metrics::Metrics;
+use crate::sources::{SourceSet, Sources};
+
+/// Leading bytes of a persisted tree, followed by its format version.
+const CACHE_FILE_MAGIC: &[u8; 4] = b"LRTC";
+
+const CACHE_FILE_VERSION: u8 = 1;
+
+/// Number of the next temporary file a tree is written to, unique within the process.
+static CACHE_FILE_TMP: AtomicU64 = AtomicU64::new(0);
 
 /// Wrapper around prefix tree structure.
 ///
 /// Includes convenient methods for tempering and existence check.
-pub struct Cache(IpLookupTable<Ipv4Addr, bool>);
+///
+/// Immutable once built, tempering builds a new instance to be swapped in.
+pub struct Cache {
+    /// Prefixes of deny mode sources, with the sources that listed them.
+    deny: Deny,
+
+    /// Prefixes of allow mode sources, exempted from `deny`.
+    allow: IpLookupTable<Ipv4Addr, SourceSet>,
+
+    /// Prefixes of `allow` ordered by network address & length,
+    /// so those within a block are found by a range rather than a walk of the tree.
+    allow_ordered: BTreeMap<(u32, u32), SourceSet>,
+
+    /// Prefixes of shadow mode sources, never returned to peers.
+    shadow: IpLookupTable<Ipv4Addr, SourceSet>,
+
+    /// IPv6 prefixes of deny mode sources, with the sources that listed them.
+    ///
+    /// Always merged, regardless of `per_source_trees`.
+    deny_v6: IpLookupTable<Ipv6Addr, SourceSet>,
+
+    /// IPv6 prefixes of allow mode sources, exempted from `deny_v6`.
+    allow_v6: IpLookupTable<Ipv6Addr, SourceSet>,
+
+    /// Unix timestamp in seconds of the last successful temper, 0 if never.
+    ///
+    /// Atomic as a retained tree is marked fresh while shared.
+    tempered_at: AtomicU64,
+
+    /// Priority of each source in registration order, resolving an allowed & a denied prefix
+    /// of the same length. Empty for a loaded tree until tempered, every source being equal.
+    priorities: Vec<i32>,
+}
+
+/// Denied prefixes, either merged into a single tree or kept in a tree per source.
+enum Deny {
+    /// Prefixes of every source in one tree, attributed to the sources that listed them.
+    Merged(Box<IpLookupTable<Ipv4Addr, SourceSet>>),
+
+    /// A tree per source in registration order, each queried at lookup and combined.
+    ///
+    /// Sources beyond the first 64 share a tree, as they share an empty source set.
+    PerSource(Vec<(SourceSet, IpLookupTable<Ipv4Addr, ()>)>),
+}
+
+impl Deny {
+    fn insert(&mut self, prefix: Ipv4Addr, len: u32, source: SourceSet) {
+        match self {
+            Deny::Merged(tree) => {
+                // A prefix listed by several sources is attributed to each.
+                let listed = tree.exact_match(prefix, len).copied().unwrap_or(0);
+
+                tree.insert(prefix, len, listed | source);
+            }
+            Deny::PerSource(trees) => {
+                let i = match trees.iter().position(|(set, _)| *set == source) {
+                    Some(i) => i,
+                    None => {
+                        trees.push((source, IpLookupTable::new()));
+
+                        trees.len() - 1
+                    }
+                };
+
+                trees[i].1.insert(prefix, len, ());
+            }
+        }
+    }
+
+    /// Longest prefix matching the address.
+    ///
+    /// Per source, the most specific match across the trees wins,
+    /// ties going to the source registered first, as the merged tree would have it.
+    fn longest_match(&self, addr: Ipv4Addr) -> Option<(Ipv4Addr, u32)> {
+        match self {
+            Deny::Merged(tree) => tree
+                .longest_match(addr)
+                .map(|(prefix, len, _)| (prefix, len)),
+            Deny::PerSource(trees) => longest(trees.iter(), addr),
+        }
+    }
+
+    /// Longest prefix matching the address, listed by any of the sources.
+    fn filtered_match(&self, addr: Ipv4Addr, sources: SourceSet) -> Option<(Ipv4Addr, u32)> {
+        match self {
+            Deny::Merged(tree) => {
+                let network = u32::from(addr);
+
+                (0..=32).rev().find_map(|len| {
+                    let prefix = Ipv4Addr::from(network & netmask(len));
+
+                    tree.exact_match(prefix, len)
+                        .filter(|listed| **listed & sources != 0)
+                        .map(|_| (prefix, len))
+                })
+            }
+            Deny::PerSource(trees) => {
+                longest(trees.iter().filter(|(set, _)| set & sources != 0), addr)
+            }
+        }
+    }
+
+    /// Sources that listed exactly the prefix, none if no source did.
+    fn exact_match(&self, prefix: Ipv4Addr, len: u32) -> Option<SourceSet> {
+        match self {
+            Deny::Merged(tree) => tree.exact_match(prefix, len).copied(),
+            Deny::PerSource(trees) => trees
+                .iter()
+                .filter(|(_, tree)| tree.exact_match(prefix, len).is_some())
+                .map(|(set, _)| *set)
+                .reduce(|a, b| a | b),
+        }
+    }
+
+    /// Every prefix with the sources that listed it.
+    fn entries(&self) -> Box<dyn Iterator<Item = (Ipv4Addr, u32, SourceSet)> + '_> {
+        match self {
+            Deny::Merged(tree) => {
+                Box::new(tree.iter().map(|(prefix, len, set)| (prefix, len, *set)))
+            }
+            Deny::PerSource(trees) => Box::new(trees.iter().flat_map(|(set, tree)| {
+                tree.iter().map(move |(prefix, len, _)| (prefix, len, *set))
+            })),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Deny::Merged(tree) => tree.len(),
+            Deny::PerSource(trees) => trees.iter().map(|(_, tree)| tree.len()).sum(),
+        }
+    }
+
+    fn mem_usage(&self) -> (usize, usize) {
+        match self {
+            Deny::Merged(tree) => tree.mem_usage(),
+            Deny::PerSource(trees) => trees
+                .iter()
+                .map(|(_, tree)| tree.mem_usage())
+                .fold((0, 0), |acc, usage| (acc.0 + usage.0, acc.1 + usage.1)),
+        }
+    }
+}
 
 impl Cache {
     pub fn new() -> Self {
-        Self(IpLookupTable::new())
+        Self {
+            deny: Deny::Merged(Box::new(IpLookupTable::new())),
+            allow: IpLookupTable::new(),
+            allow_ordered: BTreeMap::new(),
+            shadow: IpLookupTable::new(),
+            deny_v6: IpLookupTable::new(),
+            allow_v6: IpLookupTable::new(),
+            tempered_at: AtomicU64::new(0),
+            priorities: Vec::new(),
+        }
+    }
+
+    /// Empty tree keeping denied prefixes in a tree per source, rather than merging them.
+    pub fn per_source() -> Self {
+        Self {
+            deny: Deny::PerSource(Vec::new()),
+            ..Self::new()
+        }
     }
 
+    /// Longest denied prefix matching the address.
+    ///
+    /// An allowed prefix more specific than the denied one exempts the address,
+    /// so a narrow allow carves a hole out of a broader deny.
+    /// One as specific exempts it unless the denying sources are of a higher priority.
     pub fn longest_match(&self, addr: Ipv4Addr) -> Option<(Ipv4Addr, u32)> {
-        self.0.longest_match(addr).map(|i| (i.0, i.1))
+        let (prefix, len) = self.deny.longest_match(addr)?;
+
+        self.exempt(addr, prefix, len, || {
+            self.listed_by(IpAddr::V4(prefix), len)
+        })
+    }
+
+    /// Longest shadow prefix matching the address, exempted by allowed prefixes as if denied.
+    ///
+    /// None if there are no shadow prefixes.
+    pub fn shadow_match(&self, addr: Ipv4Addr) -> Option<Option<(Ipv4Addr, u32)>> {
+        if self.shadow.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.shadow
+                .longest_match(addr)
+                .and_then(|(prefix, len, listed)| self.exempt(addr, prefix, len, || *listed)),
+        )
+    }
+
+    /// Every denied prefix matching the address, longest first.
+    ///
+    /// Prefixes exempted by an allowed prefix are left out,
+    /// as `longest_match` would for the longest.
+    pub fn matches(&self, addr: Ipv4Addr) -> Vec<(Ipv4Addr, u32)> {
+        let network = u32::from(addr);
+
+        (0..=32)
+            .rev()
+            .filter_map(|len| {
+                let prefix = Ipv4Addr::from(network & netmask(len));
+
+                self.deny
+                    .exact_match(prefix, len)
+                    .map(|listed| (prefix, len, listed))
+            })
+            .filter_map(|(prefix, len, listed)| self.exempt(addr, prefix, len, || listed))
+            .collect()
+    }
+
+    /// Longest denied prefix containing the entire block, equal or shorter than it.
+    ///
+    /// Unlike `longest_match` on the block's network address, a narrower prefix
+    /// covering only part of the block does not count. An allowed prefix overlapping
+    /// the block, at least as specific as the denied one, leaves a hole in it.
+    pub fn covering(&self, prefix: Ipv4Addr, mask_len: u32) -> Option<(Ipv4Addr, u32)> {
+        let mask_len = mask_len.min(32);
+        let network = u32::from(prefix) & netmask(mask_len);
+
+        let (prefix, len, listed) = (0..=mask_len).rev().find_map(|len| {
+            let prefix = Ipv4Addr::from(network & netmask(len));
+
+            self.deny
+                .exact_match(prefix, len)
+                .map(|listed| (prefix, len, listed))
+        })?;
+
+        let overrides = |allow_len, allowed| self.overrides(allow_len, allowed, len, || listed);
+
+        // Allowed prefixes containing the block, then those within it.
+        let holed = (len..mask_len).any(|allow_len| {
+            self.allow
+                .exact_match(Ipv4Addr::from(network & netmask(allow_len)), allow_len)
+                .is_some_and(|allowed| overrides(allow_len, *allowed))
+        }) || self
+            .allow_ordered
+            .range((network, mask_len)..=(network | !netmask(mask_len), 32))
+            .any(|(&(_, allow_len), &allowed)| overrides(allow_len, allowed));
+
+        if holed {
+            return None;
+        }
+
+        Some((prefix, len))
     }
 
+    /// Longest denied prefix matching the address, listed by any of the sources.
+    ///
+    /// A broader prefix is found should the longest be listed by other sources only.
+    /// Allowed prefixes exempt the address regardless of the sources listing them,
+    /// unless `ignore_allow`.
+    pub fn filtered_match(
+        &self,
+        addr: Ipv4Addr,
+        sources: SourceSet,
+        ignore_allow: bool,
+    ) -> Option<(Ipv4Addr, u32)> {
+        let (prefix, len) = self.deny.filtered_match(addr, sources)?;
+
+        if ignore_allow {
+            return Some((prefix, len));
+        }
+
+        self.exempt(addr, prefix, len, || {
+            self.listed_by(IpAddr::V4(prefix), len)
+        })
+    }
+
+    /// The denied prefix, unless an allowed prefix matching the address overrides it.
+    ///
+    /// The sources that listed the denied prefix are only looked up should it come to priority.
+    fn exempt<F: FnOnce() -> SourceSet>(
+        &self,
+        addr: Ipv4Addr,
+        prefix: Ipv4Addr,
+        len: u32,
+        listed: F,
+    ) -> Option<(Ipv4Addr, u32)> {
+        match self.allow.longest_match(addr) {
+            Some((_, allow_len, allowed)) if self.overrides(allow_len, *allowed, len, listed) => {
+                None
+            }
+            _ => Some((prefix, len)),
+        }
+    }
+
+    fn overrides<F: FnOnce() -> SourceSet>(
+        &self,
+        allow_len: u32,
+        allowed: SourceSet,
+        deny_len: u32,
+        listed: F,
+    ) -> bool {
+        overrides(&self.priorities, allow_len, allowed, deny_len, listed)
+    }
+
+    /// Longest denied IPv6 prefix matching the address.
+    ///
+    /// Restricted to prefixes listed by any of the sources if given,
+    /// and exempted by allowed IPv6 prefixes as IPv4 ones are, unless `ignore_allow`.
+    pub fn longest_match_v6(
+        &self,
+        addr: Ipv6Addr,
+        sources: Option<SourceSet>,
+        ignore_allow: bool,
+    ) -> Option<(Ipv6Addr, u32)> {
+        let (prefix, len) = match sources {
+            Some(sources) => {
+                let network = u128::from(addr);
+
+                (0..=128).rev().find_map(|len| {
+                    let prefix = Ipv6Addr::from(network & netmask_v6(len));
+
+                    self.deny_v6
+                        .exact_match(prefix, len)
+                        .filter(|listed| **listed & sources != 0)
+                        .map(|_| (prefix, len))
+                })?
+            }
+            None => self
+                .deny_v6
+                .longest_match(addr)
+                .map(|(prefix, len, _)| (prefix, len))?,
+        };
+
+        if ignore_allow {
+            return Some((prefix, len));
+        }
+
+        let listed = || self.listed_by(IpAddr::V6(prefix), len);
+
+        match self.allow_v6.longest_match(addr) {
+            Some((_, allow_len, allowed)) if self.overrides(allow_len, *allowed, len, listed) => {
+                None
+            }
+            _ => Some((prefix, len)),
+        }
+    }
+
+    /// Sources that listed exactly the denied prefix, of either family.
+    ///
+    /// Empty if no source did, such as once the tree is swapped for one without it.
+    pub fn listed_by(&self, prefix: IpAddr, len: u32) -> SourceSet {
+        match prefix {
+            IpAddr::V4(prefix) => self.deny.exact_match(prefix, len),
+            IpAddr::V6(prefix) => self.deny_v6.exact_match(prefix, len).copied(),
+        }
+        .unwrap_or(0)
+    }
+
+    /// Longest denied prefix matching the address, disregarding allowed prefixes.
+    pub fn longest_deny_match(&self, addr: Ipv4Addr) -> Option<(Ipv4Addr, u32)> {
+        self.deny.longest_match(addr)
+    }
+
+    /// Number of prefixes across both modes & families.
+    ///
+    /// Denied prefixes kept per source count once per source listing them.
     pub fn len(&self) -> usize {
-        self.0.len()
+        self.deny.len() + self.allow.len() + self.deny_v6.len() + self.allow_v6.len()
+    }
+
+    /// Whether a temper ever succeeded.
+    pub fn tempered(&self) -> bool {
+        self.tempered_at.load(Ordering::Relaxed) != 0
+    }
+
+    /// Seconds since the last successful temper, `u32::MAX` if it never succeeded.
+    pub fn age(&self) -> u32 {
+        age_since(self.tempered_at())
+    }
+
+    /// Unix timestamp in seconds of the last successful temper, 0 if never.
+    pub fn tempered_at(&self) -> u64 {
+        self.tempered_at.load(Ordering::Relaxed)
+    }
+
+    /// Mark the tree as fresh as of now.
+    pub fn mark_tempered(&self) {
+        self.tempered_at.store(unix_now(), Ordering::Relaxed);
+    }
+
+    /// Snapshot of the effective denied IPv4 prefixes, for export.
+    pub fn export(&self) -> Export {
+        Export::new(
+            self.deny.entries(),
+            self.allow.iter().map(|(addr, len, set)| (addr, len, *set)),
+            self.priorities.clone(),
+        )
+    }
+
+    fn insert(&mut self, cidr: &Ipv4Cidr, mode: Mode, source: SourceSet) {
+        self.insert_prefix(
+            cidr.first_address(),
+            cidr.network_length() as u32,
+            mode,
+            source,
+        );
+    }
+
+    fn insert_prefix(&mut self, prefix: Ipv4Addr, len: u32, mode: Mode, source: SourceSet) {
+        let tree = match mode {
+            Mode::Deny => return self.deny.insert(prefix, len, source),
+            Mode::Allow => &mut self.allow,
+            Mode::Shadow => &mut self.shadow,
+        };
+
+        // A prefix listed by several sources is attributed to each.
+        let listed = tree.exact_match(prefix, len).copied().unwrap_or(0) | source;
+
+        tree.insert(prefix, len, listed);
+
+        if mode == Mode::Allow {
+            self.allow_ordered.insert((u32::from(prefix), len), listed);
+        }
+    }
+
+    /// Insert an IPv6 prefix.
+    ///
+    /// Shadow sources are only compared against IPv4 lookups, their IPv6 prefixes are skipped.
+    fn insert_v6(&mut self, cidr: &Ipv6Cidr, mode: Mode, source: SourceSet) {
+        self.insert_prefix_v6(
+            cidr.first_address(),
+            cidr.network_length() as u32,
+            mode,
+            source,
+        );
+    }
+
+    fn insert_prefix_v6(&mut self, prefix: Ipv6Addr, len: u32, mode: Mode, source: SourceSet) {
+        let tree = match mode {
+            Mode::Deny => &mut self.deny_v6,
+            Mode::Allow => &mut self.allow_v6,
+            Mode::Shadow => return,
+        };
+
+        let listed = tree.exact_match(prefix, len).copied().unwrap_or(0);
+
+        tree.insert(prefix, len, listed | source);
     }
 
-    pub async fn temper(&mut self, sources: &Sources) -> LrthromeResult<()> {
+    /// Copy the prefixes the source listed in `current` over, returning their number.
+    ///
+    /// Used for a source not due for a refresh, rather than fetching it again,
+    /// and for a source that failed, rather than dropping its entries.
+    fn carry_over(&mut self, current: &Cache, mode: Mode, source: SourceSet) -> u64 {
+        let listed = |set: SourceSet| set & source != 0;
+
+        let v4: Vec<_> = match mode {
+            Mode::Deny => current
+                .deny
+                .entries()
+                .filter(|(_, _, set)| listed(*set))
+                .map(|(prefix, len, _)| (prefix, len))
+                .collect(),
+            Mode::Allow | Mode::Shadow => {
+                let tree = match mode {
+                    Mode::Allow => &current.allow,
+                    _ => &current.shadow,
+                };
+
+                tree.iter()
+                    .filter(|(_, _, set)| listed(**set))
+                    .map(|(prefix, len, _)| (prefix, len))
+                    .collect()
+            }
+        };
+
+        let v6: Vec<_> = match mode {
+            Mode::Deny => &current.deny_v6,
+            Mode::Allow => &current.allow_v6,
+            Mode::Shadow => return v4.len() as u64,
+        }
+        .iter()
+        .filter(|(_, _, set)| listed(**set))
+        .map(|(prefix, len, _)| (prefix, len))
+        .collect();
+
+        let carried = (v4.len() + v6.len()) as u64;
+
+        for (prefix, len) in v4 {
+            self.insert_prefix(prefix, len, mode, source);
+        }
+
+        for (prefix, len) in v6 {
+            self.insert_prefix_v6(prefix, len, mode, source);
+        }
+
+        carried
+    }
+
+    /// Write every prefix out, along with its mode & sources, replacing the file once fully written.
+    ///
+    /// A header of the format version, temper timestamp & number of prefixes precedes them.
+    pub fn save(&self, path: &Path) -> LrthromeResult<()> {
+        let mut records = BytesMut::new();
+        let mut count = 0u64;
+
+        let mut record = |mode: Mode, prefix: IpAddr, len: u32, sources: SourceSet| {
+            records.put_u8(mode as u8);
+
+            match prefix {
+                IpAddr::V4(prefix) => {
+                    records.put_u8(4);
+                    records.put_slice(&prefix.octets());
+                }
+                IpAddr::V6(prefix) => {
+                    records.put_u8(6);
+                    records.put_slice(&prefix.octets());
+                }
+            }
+
+            records.put_u8(len as u8);
+            records.put_u64_le(sources);
+
+            count += 1;
+        };
+
+        for (prefix, len, sources) in self.deny.entries() {
+            record(Mode::Deny, IpAddr::V4(prefix), len, sources);
+        }
+
+        for (mode, tree) in [(Mode::Allow, &self.allow), (Mode::Shadow, &self.shadow)] {
+            for (prefix, len, sources) in tree.iter() {
+                record(mode, IpAddr::V4(prefix), len, *sources);
+            }
+        }
+
+        for (mode, tree) in [(Mode::Deny, &self.deny_v6), (Mode::Allow, &self.allow_v6)] {
+            for (prefix, len, sources) in tree.iter() {
+                record(mode, IpAddr::V6(prefix), len, *sources);
+            }
+        }
+
+        let mut buf = BytesMut::with_capacity(records.len() + 21);
+
+        buf.put_slice(CACHE_FILE_MAGIC);
+        buf.put_u8(CACHE_FILE_VERSION);
+        buf.put_u64_le(self.tempered_at());
+        buf.put_u64_le(count);
+        buf.put_slice(&records);
+
+        let tmp = path.with_extension(format!(
+            "tmp.{}.{}",
+            std::process::id(),
+            CACHE_FILE_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let written = std::fs::write(&tmp, &buf).and_then(|_| std::fs::rename(&tmp, path));
+
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+
+        Ok(written?)
+    }
+
+    /// Read a tree written out by `save`, as fresh as it was when written.
+    ///
+    /// Prefixes are attributed to the sources as registered then,
+    /// until the next temper rebuilds the tree from the sources as registered now.
+    pub fn load(path: &Path, per_source: bool) -> LrthromeResult<Self> {
+        let data = std::fs::read(path)?;
+        let mut buf = &data[..];
+
+        let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt cache file");
+
+        if buf.len() < 21 || &buf[..4] != CACHE_FILE_MAGIC || buf[4] != CACHE_FILE_VERSION {
+            return Err(corrupt().into());
+        }
+
+        buf.advance(5);
+
+        let tempered_at = buf.get_u64_le();
+        let count = buf.get_u64_le();
+
+        let mut cache = match per_source {
+            true => Self::per_source(),
+            false => Self::new(),
+        };
+
+        for _ in 0..count {
+            if buf.remaining() < 2 {
+                return Err(corrupt().into());
+            }
+
+            let mode = match buf.get_u8() {
+                0 => Mode::Deny,
+                1 => Mode::Allow,
+                2 => Mode::Shadow,
+                _ => return Err(corrupt().into()),
+            };
+
+            let (addr_len, max_len) = match buf.get_u8() {
+                4 => (4, 32),
+                6 => (16, 128),
+                _ => return Err(corrupt().into()),
+            };
+
+            if buf.remaining() < addr_len + 9 {
+                return Err(corrupt().into());
+            }
+
+            let mut octets = [0; 16];
+
+            buf.copy_to_slice(&mut octets[..addr_len]);
+
+            let len = buf.get_u8() as u32;
+            let sources = buf.get_u64_le();
+
+            if len > max_len {
+                return Err(corrupt().into());
+            }
+
+            if addr_len == 4 {
+                let prefix = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+
+                match mode {
+                    Mode::Deny => cache.deny.insert(prefix, len, sources),
+                    Mode::Allow => cache.insert_prefix(prefix, len, Mode::Allow, sources),
+                    Mode::Shadow => {
+                        cache.shadow.insert(prefix, len, sources);
+                    }
+                }
+            } else {
+                let prefix = Ipv6Addr::from(octets);
+
+                match mode {
+                    Mode::Deny => {
+                        cache.deny_v6.insert(prefix, len, sources);
+                    }
+                    Mode::Allow => {
+                        cache.allow_v6.insert(prefix, len, sources);
+                    }
+                    Mode::Shadow => return Err(corrupt().into()),
+                }
+            }
+        }
+
+        if buf.has_remaining() {
+            return Err(corrupt().into());
+        }
+
+        cache.tempered_at.store(tempered_at, Ordering::Relaxed);
+
+        Ok(cache)
+    }
+
+    /// Build a new tree from the sources.
+    ///
+    /// Only sources due for a refresh as of `cache_ttl` & their own intervals, and with an update, are fetched,
+    /// the prefixes of others being carried over from `current`. Every source is fetched without it.
+    ///
+    /// A source is replaced whole or not at all, its entries carried over from `current` should it fail.
+    ///
+    /// None if no due source has an update, in which case the current tree should be retained.
+    /// Fails if every fetched source failed, as the tree would otherwise be wiped by a transient outage.
+    ///
+    /// Timing, entries & outcome of each source are recorded, and summarized in a log line.
+    pub async fn temper(
+        sources: &Sources,
+        metrics: &Metrics,
+        current: Option<&Cache>,
+        cache_ttl: Option<Duration>,
+    ) -> LrthromeResult<Option<Self>> {
+        let now = Instant::now();
+
+        let due = (0..sources.sources().len())
+            .map(|i| current.is_none() || sources.due(i, cache_ttl, now))
+            .collect::<Vec<_>>();
+
+        let mut updated = false;
+
+        // Due sources without an update are carried over as well, unless their entries are not theirs.
+        let mut fetch = due.clone();
+
+        // Every due source is checked, as a check may record state for the iteration that follows.
+        for (i, source) in sources
+            .sources()
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| due[*i])
+        {
+            let update = source.has_update().await;
+
+            updated |= update;
+            fetch[i] = update || current.is_none() || !sources.retained(i);
+        }
+
+        if !updated {
+            if due.contains(&true) {
+                info!("No source has an update, retaining the current tree");
+            } else {
+                debug!("No source is due for a refresh, retaining the current tree");
+            }
+
+            // Checked without an update, as good as refreshed until their next interval.
+            for i in (0..due.len()).filter(|i| due[*i]) {
+                sources.refreshed(i, now);
+            }
+
+            return Ok(None);
+        }
+
         // Create a new instance in order to purge prefixes that may not exist anymore
-        self.0 = IpLookupTable::new();
+        let mut cache = match sources.tree_per_source() {
+            true => Self::per_source(),
+            false => Self::new(),
+        };
+
+        cache.priorities = sources.sources().iter().map(|s| s.priority()).collect();
+
+        let mut summaries = Vec::with_capacity(sources.sources().len());
+
+        for (i, source) in sources.sources().iter().enumerate() {
+            let started = Instant::now();
+            let bit = Sources::source_bit(i);
+
+            let mut summary = SourceSummary {
+                name: source.name(),
+                outcome: Outcome::Fetched,
+                fetch: Duration::default(),
+                parse: Duration::default(),
+                entries: 0,
+                aggregated: None,
+            };
+
+            if let (false, Some(current)) = (fetch[i], current) {
+                summary.outcome = Outcome::Carried;
+                summary.entries = cache.carry_over(current, source.mode(), bit);
+                summaries.push(summary);
+
+                continue;
+            }
+
+            let normalize = source.normalize();
+            let mode = source.mode();
+
+            // Buffered until the source is exhausted, so a failing source leaves none of its new entries.
+            let mut v4 = Vec::new();
+            let mut v6 = Vec::new();
+            let mut dropped = 0;
+
+            let stream = source.iterate_cidr().await;
+
+            summary.fetch = started.elapsed();
+
+            match stream {
+                Ok(mut stream) => {
+                    while let Some(cidr) = stream.next().await {
+                        let cidr = match cidr {
+                            Ok(cidr) => cidr,
+                            Err(e) => {
+                                error!(
+                                    "Source failed mid-stream, its entries retained (source = {}): {}",
+                                    summary.name, e
+                                );
+
+                                summary.outcome = Outcome::Failed;
+
+                                break;
+                            }
+                        };
+
+                        if !sources.above_floor(&cidr) {
+                            error!(
+                                "Refused {} as it is broader than the configured prefix floor (source = {})",
+                                cidr, summary.name
+                            );
+
+                            continue;
+                        }
+
+                        match cidr {
+                            IpCidr::V4(cidr) if !normalize.admits(&cidr) => {
+                                dropped += 1;
+
+                                continue;
+                            }
+                            IpCidr::V4(cidr) => v4.push(cidr),
+                            IpCidr::V6(cidr) => v6.push(cidr),
+                        }
+
+                        summary.entries += 1;
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Source failed, its entries retained (source = {}): {}",
+                        summary.name, e
+                    );
+
+                    summary.outcome = Outcome::Failed;
+                }
+            }
+
+            if summary.outcome == Outcome::Failed {
+                // The entries of a source not yet refreshed into the current tree are skipped instead.
+                summary.entries = match (sources.retained(i), current) {
+                    (true, Some(current)) => cache.carry_over(current, mode, bit),
+                    _ => 0,
+                };
+
+                summary.parse = started.elapsed() - summary.fetch;
+                summary.record(metrics);
+                summaries.push(summary);
+
+                normalize.take_invalid();
 
-        for source in sources.sources() {
-            if !source.has_update().await {
                 continue;
             }
 
-            let iter = source.iterate_cidr().await?;
+            if sources.aggregate() {
+                // Merged no broader than the source admits, as each entry was.
+                v4 = aggregate(v4, sources.floor().max(normalize.min_mask()));
+
+                summary.aggregated = Some(v4.len() as u64);
+            }
+
+            for cidr in &v4 {
+                cache.insert(cidr, mode, bit);
+            }
+
+            for cidr in &v6 {
+                cache.insert_v6(cidr, mode, bit);
+            }
+
+            summary.parse = started.elapsed() - summary.fetch;
+            summary.record(metrics);
+
+            let invalid = normalize.take_invalid();
+
+            if invalid.count > 0 {
+                warn!(
+                    "Skipped {} lines that failed to parse (source = {}): {}",
+                    invalid.count,
+                    summary.name,
+                    invalid
+                        .samples
+                        .iter()
+                        .map(|(line, content)| format!("line {}: {:?}", line, content))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
 
-            for cidr in iter {
-                self.0
-                    .insert(cidr.first_address(), cidr.network_length() as u32, true);
+            if dropped > 0 {
+                warn!(
+                    "Dropped {} entries outside of mask bounds (source = {}) (min = {}) (max = {})",
+                    dropped,
+                    summary.name,
+                    normalize.min_mask(),
+                    normalize.max_mask(),
+                );
             }
+
+            debug!("Fetched {} cidrs from {}", summary.entries, summary.name);
+
+            summaries.push(summary);
         }
 
-        let mem_usage = self.0.mem_usage();
+        // Sources carried over are no sign of the sources being reachable.
+        if !summaries.iter().any(|s| s.outcome == Outcome::Fetched) {
+            info!(
+                "Temper summary: {}",
+                summaries
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+
+            return Err(LrthromeError::SourcesFailed);
+        }
+
+        // Failed sources are left due, retried upon the next tick,
+        // while those checked without an update are as good as refreshed until their next interval.
+        for (i, summary) in summaries.iter().enumerate() {
+            if due[i] && summary.outcome != Outcome::Failed {
+                sources.refreshed(i, now);
+            }
+        }
+
+        // Every source failing or empty leaves a tree answering nothing, while looking healthy.
+        let degraded = summaries.iter().all(|s| s.entries == 0);
+
+        if degraded {
+            warn!(
+                "Temper yielded no entries from {} sources, every lookup will be not found",
+                summaries.len()
+            );
+        }
+
+        metrics.degraded.set(degraded as u64);
+
+        info!(
+            "Temper summary: {}",
+            summaries
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+
+        let usage = [
+            cache.deny.mem_usage(),
+            cache.allow.mem_usage(),
+            cache.shadow.mem_usage(),
+            cache.deny_v6.mem_usage(),
+            cache.allow_v6.mem_usage(),
+        ]
+        .iter()
+        .fold((0, 0), |acc, usage| (acc.0 + usage.0, acc.1 + usage.1));
 
         info!(
             "Lookup table size: (node: {}) (results: {})",
-            mem_usage.0, mem_usage.1
+            usage.0, usage.1
+        );
+
+        cache.mark_tempered();
+
+        Ok(Some(cache))
+    }
+}
+
+/// Most specific match across the trees, ties going to the first.
+fn longest<'a, I>(trees: I, addr: Ipv4Addr) -> Option<(Ipv4Addr, u32)>
+where
+    I: Iterator<Item = &'a (SourceSet, IpLookupTable<Ipv4Addr, ()>)>,
+{
+    trees.filter_map(|(_, tree)| tree.longest_match(addr)).fold(
+        None,
+        |longest, (prefix, len, _)| match longest {
+            Some((_, longest_len)) if longest_len >= len => longest,
+            _ => Some((prefix, len)),
+        },
+    )
+}
+
+/// Collapse duplicate & contained prefixes, and merge sibling prefixes into their parent.
+///
+/// The broader prefix is reported for an address matched, rather than the one listed.
+/// Siblings are not merged into a parent broader than `floor`, as it would be refused.
+fn aggregate(cidrs: Vec<Ipv4Cidr>, floor: u8) -> Vec<Ipv4Cidr> {
+    let mut nets = cidrs
+        .iter()
+        .map(|cidr| {
+            (
+                u32::from(cidr.first_address()),
+                cidr.network_length() as u32,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Broader prefixes sort ahead of those they contain.
+    nets.sort_unstable();
+    nets.dedup();
+
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(nets.len());
+
+    for (prefix, len) in nets {
+        if let Some(&(last, last_len)) = merged.last() {
+            if prefix & netmask(last_len) == last {
+                continue;
+            }
+        }
+
+        merged.push((prefix, len));
+
+        // A merged parent may in turn complete the preceding sibling.
+        while let [.., (a, a_len), (b, b_len)] = merged[..] {
+            if a_len != b_len
+                || a_len <= floor as u32
+                || a & netmask(a_len - 1) != b & netmask(a_len - 1)
+            {
+                break;
+            }
+
+            merged.truncate(merged.len() - 2);
+            merged.push((a, a_len - 1));
+        }
+    }
+
+    merged
+        .into_iter()
+        .filter_map(|(prefix, len)| Ipv4Cidr::new(prefix.into(), len as u8).ok())
+        .collect()
+}
+
+/// Whether an allowed prefix overrides a denied one, being more specific,
+/// or as specific & listed by sources of at least the same priority.
+///
+/// Sources are prioritized by `priorities` in registration order, as lookups & exports alike resolve them.
+pub fn overrides<F: FnOnce() -> SourceSet>(
+    priorities: &[i32],
+    allow_len: u32,
+    allowed: SourceSet,
+    deny_len: u32,
+    listed: F,
+) -> bool {
+    allow_len > deny_len
+        || allow_len == deny_len && priority(priorities, allowed) >= priority(priorities, listed())
+}
+
+/// Highest priority among the sources, 0 if unknown.
+fn priority(priorities: &[i32], set: SourceSet) -> i32 {
+    priorities
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| Sources::source_bit(*i) & set != 0)
+        .map(|(_, priority)| *priority)
+        .max()
+        .unwrap_or(0)
+}
+
+fn netmask(len: u32) -> u32 {
+    u32::MAX.checked_shl(32 - len).unwrap_or(0)
+}
+
+fn netmask_v6(len: u32) -> u128 {
+    u128::MAX.checked_shl(128 - len).unwrap_or(0)
+}
+
+/// Outcome of a single source within a temper.
+struct SourceSummary<'a> {
+    name: &'a str,
+
+    outcome: Outcome,
+
+    /// Until the source began yielding entries.
+    fetch: Duration,
+
+    /// Streaming & parsing the entries.
+    parse: Duration,
+
+    /// Entries yielded by the source.
+    entries: u64,
+
+    /// Entries inserted into the tree once aggregated, none if not aggregated.
+    aggregated: Option<u64>,
+}
+
+#[derive(PartialEq)]
+enum Outcome {
+    /// Fetched in full, its entries replaced.
+    Fetched,
+
+    /// Not due for a refresh or without an update, its entries carried over from the current tree.
+    Carried,
+
+    /// Failed to be fetched in full, its entries carried over from the current tree, if any.
+    Failed,
+}
+
+impl SourceSummary<'_> {
+    fn record(&self, metrics: &Metrics) {
+        if self.outcome == Outcome::Fetched {
+            metrics.source_tempers.get(self.name).inc();
+        } else {
+            metrics.source_temper_failures.get(self.name).inc();
+        }
+
+        metrics
+            .source_fetch_millis
+            .get(self.name)
+            .set(self.fetch.as_millis() as u64);
+        metrics
+            .source_parse_millis
+            .get(self.name)
+            .set(self.parse.as_millis() as u64);
+        metrics.source_entries.get(self.name).set(self.entries);
+    }
+}
+
+impl fmt::Display for SourceSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{} (outcome = {}) (fetch = {}ms) (parse = {}ms) (entries = {})",
+            self.name,
+            match self.outcome {
+                Outcome::Fetched => "ok",
+                Outcome::Carried => "carried",
+                Outcome::Failed => "failed",
+            },
+            self.fetch.as_millis(),
+            self.parse.as_millis(),
+            self.entries,
+        )?;
+
+        if let Some(aggregated) = self.aggregated {
+            write!(f, " (aggregated = {})", aggregated)?;
+        }
+
+        write!(f, "]")
+    }
+}
+
+/// Seconds since the unix timestamp, `u32::MAX` if 0.
+pub fn age_since(tempered_at: u64) -> u32 {
+    match tempered_at {
+        0 => u32::MAX,
+        t => unix_now().saturating_sub(t).min(u32::MAX as u64) as u32,
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::str::FromStr;
+
+    #[test]
+    fn allow_carves_deny() {
+        let mut cache = Cache::new();
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Allow, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap(), Mode::Deny, 1);
+        cache.insert(
+            &Ipv4Cidr::from_str("192.168.0.0/16").unwrap(),
+            Mode::Deny,
+            1,
+        );
+        cache.insert(
+            &Ipv4Cidr::from_str("192.168.0.0/16").unwrap(),
+            Mode::Allow,
+            1,
+        );
+
+        let lookup = |ip: &str| cache.longest_match(ip.parse().unwrap());
+
+        assert_eq!(lookup("10.2.0.1"), Some(("10.0.0.0".parse().unwrap(), 8)));
+        assert_eq!(lookup("10.1.3.1"), None);
+        assert_eq!(lookup("10.1.2.1"), Some(("10.1.2.0".parse().unwrap(), 24)));
+        assert_eq!(lookup("192.168.1.1"), None);
+        assert_eq!(lookup("172.16.0.1"), None);
+
+        assert_eq!(
+            cache.longest_deny_match("10.1.3.1".parse().unwrap()),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+    }
+
+    #[test]
+    fn priority_resolves_ties() {
+        let mut cache = Cache::new();
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/24").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/24").unwrap(), Mode::Allow, 2);
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/25").unwrap(), Mode::Allow, 2);
+
+        let lookup = |cache: &Cache, ip: &str| cache.longest_match(ip.parse().unwrap());
+
+        // Allowed prefixes win ties between sources of the same priority.
+        assert_eq!(lookup(&cache, "10.0.0.200"), None);
+        assert_eq!(cache.covering("10.0.0.0".parse().unwrap(), 24), None);
+
+        cache.priorities = vec![10, 0];
+
+        assert_eq!(
+            lookup(&cache, "10.0.0.200"),
+            Some(("10.0.0.0".parse().unwrap(), 24))
+        );
+        assert!(cache.covering("10.0.0.128".parse().unwrap(), 25).is_some());
+        // A more specific allow still carves a hole, regardless of priority.
+        assert_eq!(lookup(&cache, "10.0.0.1"), None);
+    }
+
+    #[test]
+    fn covering_blocks() {
+        let mut cache = Cache::new();
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap(), Mode::Allow, 1);
+        cache.insert(&Ipv4Cidr::from_str("172.16.0.0/24").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("172.16.1.0/24").unwrap(), Mode::Deny, 1);
+
+        let covering = |ip: &str, len| cache.covering(ip.parse().unwrap(), len);
+
+        assert_eq!(
+            covering("10.2.0.0", 16),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+        // The allowed /24 leaves a hole in the block.
+        assert_eq!(covering("10.1.0.0", 16), None);
+        assert_eq!(covering("10.0.0.0", 8), None);
+        // Within the allowed /24, or beside it.
+        assert_eq!(covering("10.1.2.128", 25), None);
+        assert_eq!(
+            covering("10.1.3.0", 24),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+        // Straddling two entries, despite each half being covered.
+        assert_eq!(covering("172.16.0.0", 23), None);
+        assert_eq!(
+            covering("172.16.1.0", 24),
+            Some(("172.16.1.0".parse().unwrap(), 24))
+        );
+        assert_eq!(covering("192.168.0.0", 16), None);
+    }
+
+    #[test]
+    fn every_match() {
+        let mut cache = Cache::new();
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 2);
+        cache.insert(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.2.0/25").unwrap(), Mode::Allow, 1);
+
+        let matches = |ip: &str| cache.matches(ip.parse().unwrap());
+
+        assert_eq!(
+            matches("10.1.2.200"),
+            vec![
+                ("10.1.2.0".parse().unwrap(), 24),
+                ("10.1.0.0".parse().unwrap(), 16),
+                ("10.0.0.0".parse().unwrap(), 8),
+            ]
+        );
+        // Within the allowed /25, which exempts every broader prefix.
+        assert!(matches("10.1.2.1").is_empty());
+        assert_eq!(matches("10.2.0.1"), vec![("10.0.0.0".parse().unwrap(), 8)]);
+        assert!(matches("192.168.0.1").is_empty());
+    }
+
+    #[test]
+    fn filtered_by_source() {
+        let mut cache = Cache::new();
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 2);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 4);
+        cache.insert(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap(), Mode::Allow, 8);
+
+        let filtered =
+            |ip: &str, sources| cache.filtered_match(ip.parse().unwrap(), sources, false);
+
+        assert_eq!(
+            filtered("10.1.0.1", 1),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+        assert_eq!(
+            filtered("10.1.0.1", 4),
+            Some(("10.1.0.0".parse().unwrap(), 16))
+        );
+        assert_eq!(filtered("10.1.0.1", 16), None);
+        assert_eq!(filtered("10.1.2.1", 1 | 2), None);
+        assert_eq!(
+            cache.filtered_match("10.1.2.1".parse().unwrap(), 2, true),
+            Some(("10.1.0.0".parse().unwrap(), 16))
+        );
+
+        // A prefix listed by several sources is attributed to each.
+        assert_eq!(cache.listed_by("10.1.0.0".parse().unwrap(), 16), 2 | 4);
+        assert_eq!(cache.listed_by("10.1.2.0".parse().unwrap(), 24), 0);
+    }
+
+    #[test]
+    fn per_source_agrees_with_merged() {
+        let mut merged = Cache::new();
+        let mut per_source = Cache::per_source();
+
+        for cache in [&mut merged, &mut per_source] {
+            cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Deny, 1);
+            cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 2);
+            cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 4);
+            cache.insert(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap(), Mode::Allow, 8);
+            cache.insert(&Ipv4Cidr::from_str("10.3.0.0/16").unwrap(), Mode::Deny, 4);
+        }
+
+        for ip in [
+            "10.0.0.1",
+            "10.1.0.1",
+            "10.1.2.1",
+            "10.3.0.1",
+            "192.168.0.1",
+        ] {
+            let ip = ip.parse().unwrap();
+
+            assert_eq!(merged.longest_match(ip), per_source.longest_match(ip));
+            assert_eq!(
+                merged.longest_deny_match(ip),
+                per_source.longest_deny_match(ip)
+            );
+
+            for sources in [1, 2, 4, 1 | 4, 16] {
+                assert_eq!(
+                    merged.filtered_match(ip, sources, false),
+                    per_source.filtered_match(ip, sources, false)
+                );
+            }
+        }
+
+        assert_eq!(
+            merged.covering("10.3.0.0".parse().unwrap(), 24),
+            per_source.covering("10.3.0.0".parse().unwrap(), 24)
+        );
+        assert_eq!(
+            merged.export().map(|c| c.to_string()).collect::<Vec<_>>(),
+            per_source
+                .export()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+        );
+
+        // The /16 listed by two sources is counted for each.
+        assert_eq!(merged.len(), 4);
+        assert_eq!(per_source.len(), 5);
+    }
+
+    /// Lookup time of a merged tree against a tree per source.
+    ///
+    /// Run with `cargo test --release bench_per_source -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_per_source() {
+        const SOURCES: u64 = 8;
+        const ENTRIES: u32 = 20_000;
+        const LOOKUPS: u32 = 1_000_000;
+
+        // Deterministic spread of addresses, without a random number generator.
+        let scatter = |i: u32| i.wrapping_mul(2_654_435_761);
+
+        let mut merged = Cache::new();
+        let mut per_source = Cache::per_source();
+
+        for source in 0..SOURCES {
+            for i in 0..ENTRIES {
+                let len = 16 + (i % 17) as u8;
+                let addr = Ipv4Addr::from(scatter(i * SOURCES as u32 + source as u32));
+                let cidr =
+                    Ipv4Cidr::new(Ipv4Addr::from(u32::from(addr) & netmask(len as u32)), len)
+                        .unwrap();
+
+                merged.insert(&cidr, Mode::Deny, 1 << source);
+                per_source.insert(&cidr, Mode::Deny, 1 << source);
+            }
+        }
+
+        for (name, cache) in [("merged", &merged), ("per source", &per_source)] {
+            let started = Instant::now();
+            let mut found = 0;
+
+            for i in 0..LOOKUPS {
+                found += cache
+                    .longest_match(Ipv4Addr::from(scatter(i ^ 0x5555)))
+                    .is_some() as u32;
+            }
+
+            println!(
+                "{} ({} sources): {:?} per lookup, {} found",
+                name,
+                SOURCES,
+                started.elapsed() / LOOKUPS,
+                found
+            );
+        }
+    }
+
+    #[test]
+    fn ipv6_lookups() {
+        let mut cache = Cache::new();
+
+        let v6 = |c: &str| Ipv6Cidr::from_str(c).unwrap();
+
+        cache.insert_v6(&v6("2001:db8::/32"), Mode::Deny, 1);
+        cache.insert_v6(&v6("2001:db8:1::/48"), Mode::Deny, 2);
+        cache.insert_v6(&v6("2001:db8:2::/48"), Mode::Allow, 1);
+
+        let lookup = |ip: &str, sources, ignore_allow| {
+            cache.longest_match_v6(ip.parse().unwrap(), sources, ignore_allow)
+        };
+
+        assert_eq!(
+            lookup("2001:db8::1", None, false),
+            Some(("2001:db8::".parse().unwrap(), 32))
         );
+        assert_eq!(
+            lookup("2001:db8:1::1", None, false),
+            Some(("2001:db8:1::".parse().unwrap(), 48))
+        );
+        assert_eq!(
+            lookup("2001:db8:1::1", Some(1), false),
+            Some(("2001:db8::".parse().unwrap(), 32))
+        );
+        assert_eq!(lookup("2001:db8:2::1", None, false), None);
+        assert_eq!(
+            lookup("2001:db8:2::1", None, true),
+            Some(("2001:db8::".parse().unwrap(), 32))
+        );
+        assert_eq!(lookup("2001:db9::1", None, false), None);
+        assert_eq!(cache.listed_by("2001:db8:1::".parse().unwrap(), 48), 2);
+
+        // Families are apart, IPv4 lookups are unaffected.
+        assert_eq!(cache.longest_match("32.1.13.184".parse().unwrap()), None);
+        assert_eq!(cache.len(), 3);
+    }
+
+    #[test]
+    fn persist_round_trip() {
+        let file = std::env::temp_dir().join(format!("lrthrome-cache-{}", std::process::id()));
+
+        let mut cache = Cache::new();
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Deny, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 2);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Deny, 4);
+        cache.insert(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap(), Mode::Allow, 8);
+        cache.insert(
+            &Ipv4Cidr::from_str("172.16.0.0/12").unwrap(),
+            Mode::Shadow,
+            16,
+        );
+        cache.insert_v6(&Ipv6Cidr::from_str("2001:db8::/32").unwrap(), Mode::Deny, 1);
+        cache.mark_tempered();
+
+        cache.save(&file).unwrap();
+
+        for per_source in [false, true] {
+            let loaded = Cache::load(&file, per_source).unwrap();
+
+            assert_eq!(loaded.len(), cache.len());
+            assert_eq!(loaded.tempered_at(), cache.tempered_at());
+            assert_eq!(
+                loaded.longest_match("10.1.0.1".parse().unwrap()),
+                Some(("10.1.0.0".parse().unwrap(), 16))
+            );
+            assert_eq!(loaded.longest_match("10.1.2.1".parse().unwrap()), None);
+            assert_eq!(loaded.listed_by("10.1.0.0".parse().unwrap(), 16), 2 | 4);
+            assert_eq!(
+                loaded.shadow_match("172.16.0.1".parse().unwrap()),
+                Some(Some(("172.16.0.0".parse().unwrap(), 12)))
+            );
+            assert!(loaded
+                .longest_match_v6("2001:db8::1".parse().unwrap(), None, false)
+                .is_some());
+        }
+
+        // A truncated file is refused, rather than serving part of the tree.
+        let data = std::fs::read(&file).unwrap();
+
+        std::fs::write(&file, &data[..data.len() - 1]).unwrap();
+
+        assert!(Cache::load(&file, false).is_err());
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn shadow_apart_from_live() {
+        let mut cache = Cache::new();
+
+        assert_eq!(cache.shadow_match("10.0.0.1".parse().unwrap()), None);
+
+        cache.insert(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap(), Mode::Shadow, 1);
+        cache.insert(&Ipv4Cidr::from_str("10.1.0.0/16").unwrap(), Mode::Allow, 1);
+
+        assert_eq!(cache.longest_match("10.0.0.1".parse().unwrap()), None);
+        assert_eq!(
+            cache.shadow_match("10.0.0.1".parse().unwrap()),
+            Some(Some(("10.0.0.0".parse().unwrap(), 8)))
+        );
+        assert_eq!(cache.shadow_match("10.1.0.1".parse().unwrap()), Some(None));
+    }
+
+    #[test]
+    fn aggregate_prefixes() {
+        let cidrs = |c: &[&str]| {
+            c.iter()
+                .map(|c| Ipv4Cidr::from_str(c).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            aggregate(
+                cidrs(&[
+                    "10.0.0.0/25",
+                    "10.0.0.128/25",
+                    "10.0.0.0/25",
+                    "10.0.1.0/24",
+                    "10.0.1.7/32",
+                    "192.168.0.0/24",
+                    "192.168.2.0/24",
+                ]),
+                1
+            ),
+            cidrs(&["10.0.0.0/23", "192.168.0.0/24", "192.168.2.0/24"])
+        );
+
+        // Merged all the way up as the siblings complete, short of the floor.
+        assert_eq!(
+            aggregate(cidrs(&["0.0.0.0/1", "128.0.0.0/2", "192.0.0.0/2"]), 0),
+            cidrs(&["0.0.0.0/0"])
+        );
+        assert_eq!(
+            aggregate(cidrs(&["0.0.0.0/1", "128.0.0.0/2", "192.0.0.0/2"]), 1),
+            cidrs(&["0.0.0.0/1", "128.0.0.0/1"])
+        );
+        assert!(aggregate(Vec::new(), 1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn aggregate_within_min_mask() {
+        use crate::config::Normalize as NormalizeConfig;
+        use crate::sources::{InMemory, Normalize};
+
+        let cidr = |c: &str| Ipv4Cidr::from_str(c).unwrap();
+
+        let mut sources = Sources::new();
+
+        sources.aggregate_cidrs(true);
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/24"), cidr("10.0.1.0/24")], Mode::Deny).normalized(
+                Normalize::new(NormalizeConfig {
+                    min_mask: 24,
+                    ..Default::default()
+                }),
+            ),
+        ));
+
+        let cache = Cache::temper(&sources, &Metrics::default(), None, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.longest_match("10.0.1.1".parse().unwrap()),
+            Some(("10.0.1.0".parse().unwrap(), 24))
+        );
+    }
+
+    #[tokio::test]
+    async fn temper_from_sources() {
+        use crate::sources::InMemory;
+
+        let cidr = |c: &str| Ipv4Cidr::from_str(c).unwrap();
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(
+            vec![cidr("0.0.0.0/0"), cidr("10.0.0.0/8")],
+            Mode::Deny,
+        )));
+        sources.register(Box::new(InMemory::new(
+            vec![cidr("10.1.0.0/16")],
+            Mode::Allow,
+        )));
+
+        let metrics = Metrics::default();
+
+        let cache = Cache::temper(&sources, &metrics, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The default route is refused by the prefix floor.
+        assert_eq!(cache.longest_match("192.168.0.1".parse().unwrap()), None);
+        assert_eq!(
+            cache.longest_match("10.2.0.1".parse().unwrap()),
+            Some(("10.0.0.0".parse().unwrap(), 8))
+        );
+        assert_eq!(cache.longest_match("10.1.0.1".parse().unwrap()), None);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.tempered());
+
+        // Both sources share a name, the allow source recorded last.
+        assert_eq!(metrics.source_tempers.get("memory").get(), 2);
+        assert_eq!(metrics.source_entries.get("memory").get(), 1);
+        assert_eq!(metrics.degraded.get(), 0);
+
+        // A tree without any entries is flagged, rather than looking healthy.
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(vec![], Mode::Deny)));
+
+        let empty = Cache::temper(&sources, &metrics, None, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(empty.len(), 0);
+        assert_eq!(metrics.degraded.get(), 1);
+
+        // Without an update, no tree is built for the current one to be retained.
+        let mut sources = Sources::new();
+        let unchanged = InMemory::new(vec![], Mode::Deny);
+
+        unchanged.set_has_update(false);
+        sources.register(Box::new(unchanged));
+
+        assert!(Cache::temper(&sources, &Metrics::default(), None, None)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn refresh_per_source() {
+        use crate::sources::InMemory;
+
+        let cidr = |c: &str| Ipv4Cidr::from_str(c).unwrap();
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/8")], Mode::Deny).named("fast"),
+        ));
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("172.16.0.0/12")], Mode::Deny).named("slow"),
+        ));
+
+        assert!(sources.refresh_every("fast", Duration::from_secs(60)));
+        assert!(!sources.refresh_every("unknown", Duration::from_secs(60)));
+
+        let cache_ttl = Some(Duration::from_secs(3600));
+
+        assert_eq!(
+            sources.refresh_interval(cache_ttl),
+            Some(Duration::from_secs(60))
+        );
+
+        let metrics = Metrics::default();
+
+        let current = Cache::temper(&sources, &metrics, None, cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Neither is due right after being refreshed.
+        assert!(Cache::temper(&sources, &metrics, Some(&current), cache_ttl)
+            .await
+            .unwrap()
+            .is_none());
+
+        sources.refreshed(0, Instant::now() - Duration::from_secs(60));
+
+        let cache = Cache::temper(&sources, &metrics, Some(&current), cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Only the fast source was fetched again, the slow one's entries carried over.
+        assert_eq!(metrics.source_tempers.get("fast").get(), 2);
+        assert_eq!(metrics.source_tempers.get("slow").get(), 1);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.longest_match("172.16.0.1".parse().unwrap()),
+            Some(("172.16.0.0".parse().unwrap(), 12))
+        );
+
+        // Without a cache TTL, a source without an interval of its own is never due again.
+        assert!(!sources.due(1, None, Instant::now() + Duration::from_secs(86400)));
+        assert!(sources.due(1, cache_ttl, Instant::now() + Duration::from_secs(3600)));
+
+        // Both due, the slow source without an update is carried over rather than fetched again.
+        let mut sources = Sources::new();
+        let unchanged = InMemory::new(vec![cidr("192.168.0.0/16")], Mode::Deny).named("slow");
+
+        unchanged.set_has_update(false);
+
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/8")], Mode::Deny).named("fast"),
+        ));
+        sources.register(Box::new(unchanged));
+
+        let refreshed = Instant::now() - Duration::from_secs(3600);
+
+        sources.refreshed(0, refreshed);
+        sources.refreshed(1, refreshed);
+
+        let metrics = Metrics::default();
+
+        let cache = Cache::temper(&sources, &metrics, Some(&current), cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(metrics.source_tempers.get("fast").get(), 1);
+        assert_eq!(metrics.source_tempers.get("slow").get(), 0);
+        assert!(cache.longest_match("172.16.0.1".parse().unwrap()).is_some());
+        assert!(cache
+            .longest_match("192.168.0.1".parse().unwrap())
+            .is_none());
+        assert!(!sources.due(1, cache_ttl, Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn failed_source_retained() {
+        use crate::sources::InMemory;
+
+        let cidr = |c: &str| Ipv4Cidr::from_str(c).unwrap();
+        let cache_ttl = Some(Duration::from_secs(3600));
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/8")], Mode::Deny).named("stable"),
+        ));
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("172.16.0.0/12")], Mode::Deny).named("flaky"),
+        ));
+
+        let current = Cache::temper(&sources, &Metrics::default(), None, cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Both due, the second failing outright.
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/8"), cidr("192.168.0.0/16")], Mode::Deny)
+                .named("stable"),
+        ));
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("100.64.0.0/10")], Mode::Deny)
+                .named("flaky")
+                .failing_after(0),
+        ));
+
+        let refreshed = Instant::now() - Duration::from_secs(3600);
+
+        sources.refreshed(0, refreshed);
+        sources.refreshed(1, refreshed);
+
+        let metrics = Metrics::default();
+
+        let cache = Cache::temper(&sources, &metrics, Some(&current), cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(cache.len(), 3);
+        assert!(cache
+            .longest_match("192.168.0.1".parse().unwrap())
+            .is_some());
+        assert!(cache.longest_match("172.16.0.1".parse().unwrap()).is_some());
+        assert!(cache.longest_match("100.64.0.1".parse().unwrap()).is_none());
+        assert_eq!(metrics.source_temper_failures.get("flaky").get(), 1);
+        assert_eq!(metrics.source_entries.get("flaky").get(), 1);
+
+        // The failed source is left due, retried upon the next tick.
+        assert!(!sources.due(0, cache_ttl, Instant::now()));
+        assert!(sources.due(1, cache_ttl, Instant::now()));
+
+        // A carried over source is no sign of the failed one being reachable.
+        assert!(matches!(
+            Cache::temper(&sources, &metrics, Some(&cache), cache_ttl).await,
+            Err(LrthromeError::SourcesFailed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn refresh_atomic_per_source() {
+        use crate::sources::InMemory;
+
+        let cidr = |c: &str| Ipv4Cidr::from_str(c).unwrap();
+        let cache_ttl = Some(Duration::from_secs(3600));
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/8")], Mode::Deny).named("stable"),
+        ));
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("172.16.0.0/12")], Mode::Deny).named("flaky"),
+        ));
+
+        let current = Cache::temper(&sources, &Metrics::default(), None, cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The second fails after yielding its first new entry.
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(
+            InMemory::new(vec![cidr("10.0.0.0/8")], Mode::Deny).named("stable"),
+        ));
+        sources.register(Box::new(
+            InMemory::new(
+                vec![cidr("100.64.0.0/10"), cidr("198.18.0.0/15")],
+                Mode::Deny,
+            )
+            .named("flaky")
+            .failing_after(1),
+        ));
+
+        sources.refreshed(1, Instant::now() - Duration::from_secs(3600));
+
+        let cache = Cache::temper(&sources, &Metrics::default(), Some(&current), cache_ttl)
+            .await
+            .unwrap()
+            .unwrap();
 
-        Ok(())
+        // None of its new entries made it in, its previous ones in their place.
+        assert_eq!(cache.len(), 2);
+        assert!(cache.longest_match("100.64.0.1".parse().unwrap()).is_none());
+        assert!(cache.longest_match("172.16.0.1".parse().unwrap()).is_some());
     }
 }
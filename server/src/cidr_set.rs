@@ -0,0 +1,109 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use cidr::{Cidr, IpCidr};
+use treebitmap::IpLookupTable;
+
+use crate::error::LrthromeResult;
+
+/// Operator-defined set of CIDRs, such as exemptions, checked against peer addresses.
+///
+/// Compiled into a tree once upon config load,
+/// so a check on the accept or request path is a lookup rather than a scan.
+pub struct CidrSet {
+    v4: IpLookupTable<Ipv4Addr, ()>,
+
+    v6: IpLookupTable<Ipv6Addr, ()>,
+}
+
+impl CidrSet {
+    /// Parse entries of either family in CIDR notation, a bare address being a host.
+    pub fn parse<S: AsRef<str>>(entries: &[S]) -> LrthromeResult<Self> {
+        let mut set = Self::default();
+
+        for entry in entries {
+            match IpCidr::from_str(entry.as_ref().trim())? {
+                IpCidr::V4(cidr) => {
+                    set.v4
+                        .insert(cidr.first_address(), cidr.network_length() as u32, ());
+                }
+                IpCidr::V6(cidr) => {
+                    set.v6
+                        .insert(cidr.first_address(), cidr.network_length() as u32, ());
+                }
+            }
+        }
+
+        Ok(set)
+    }
+
+    /// Whether the address falls within any of the CIDRs of its family.
+    ///
+    /// Peer addresses are expected normalized beforehand, IPv4-mapped addresses as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(v4) => self.v4.longest_match(v4).is_some(),
+            IpAddr::V6(v6) => self.v6.longest_match(v6).is_some(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.v4.len() == 0 && self.v6.len() == 0
+    }
+}
+
+impl Default for CidrSet {
+    fn default() -> Self {
+        Self {
+            v4: IpLookupTable::new(),
+            v6: IpLookupTable::new(),
+        }
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn contains_within() {
+        let set = CidrSet::parse(&["10.0.0.0/8", "192.168.1.1"]).unwrap();
+
+        assert!(set.contains("10.20.30.40".parse().unwrap()));
+        assert!(set.contains("192.168.1.1".parse().unwrap()));
+        assert!(!set.contains("192.168.1.2".parse().unwrap()));
+        assert!(!set.contains("::1".parse().unwrap()));
+        assert!(CidrSet::default().is_empty());
+
+        assert!(CidrSet::parse(&["10.0.0.1/8"]).is_err());
+    }
+
+    #[test]
+    fn contains_ipv6() {
+        let set = CidrSet::parse(&["2001:db8::/32", "::1"]).unwrap();
+
+        assert!(!set.is_empty());
+        assert!(set.contains("2001:db8::1".parse().unwrap()));
+        assert!(set.contains("::1".parse().unwrap()));
+        assert!(!set.contains("2001:db9::1".parse().unwrap()));
+        assert!(!set.contains("10.0.0.1".parse().unwrap()));
+
+        assert!(CidrSet::parse(&["2001:db8::1/32"]).is_err());
+    }
+}
@@ -0,0 +1,481 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::convert::TryFrom;
+use std::io;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::error::{LrthromeError, LrthromeResult};
+use crate::protocol::{
+    crc32, Header, ProtocolVersion, Variant, CHECKSUM_FLAG, CHECKSUM_LEN, MAX_CSTRING_LEN,
+};
+
+/// Longest frame accepted by default, header included.
+pub const MAX_FRAME_LEN: usize = 4096;
+
+/// Frame received from a peer, with its header validated.
+pub struct Frame {
+    pub header: Header,
+
+    /// Bytes following the header, delimited by the variant's layout.
+    pub payload: Bytes,
+}
+
+/// Delimits peer frames from the stream by their variant's layout,
+/// and writes out response bytes as is.
+///
+/// A protocol error is yielded as an item rather than an error,
+/// so that it may be responded to before the connection is closed.
+/// The buffer is discarded upon one, as the stream can no longer be delimited.
+pub struct LrthromeCodec {
+    /// Longest string accepted, excluding its NUL terminator.
+    ///
+    /// A string scanned past this without being terminated is refused as malformed,
+    /// well before the frame length limit is reached.
+    max_cstring_len: usize,
+
+    /// Longest frame accepted, header included.
+    ///
+    /// Bytes buffered beyond this without completing a frame are refused as malformed,
+    /// as is a completed frame longer than this, such as one of many meta pairs.
+    max_frame_len: usize,
+
+    /// Whether frames flagged with a trailing checksum are accepted & verified.
+    ///
+    /// The flag is otherwise left on the variant byte, refused as an invalid variant.
+    checksums: bool,
+}
+
+impl LrthromeCodec {
+    pub fn new(max_cstring_len: usize, max_frame_len: usize, checksums: bool) -> Self {
+        Self {
+            max_cstring_len,
+            max_frame_len,
+            checksums,
+        }
+    }
+}
+
+impl Default for LrthromeCodec {
+    fn default() -> Self {
+        Self::new(MAX_CSTRING_LEN, MAX_FRAME_LEN, false)
+    }
+}
+
+impl Decoder for LrthromeCodec {
+    type Item = LrthromeResult<Frame>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = match frame_len(src, self.max_cstring_len, self.checksums) {
+            Ok(Some(len)) if len <= self.max_frame_len => len,
+            Ok(Some(_)) => {
+                src.clear();
+
+                return Ok(Some(Err(LrthromeError::MalformedPayload)));
+            }
+            Ok(None) if src.len() > self.max_frame_len => {
+                src.clear();
+
+                return Ok(Some(Err(LrthromeError::MalformedPayload)));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                src.clear();
+
+                return Ok(Some(Err(e)));
+            }
+        };
+
+        let mut frame = src.split_to(len);
+
+        let header = match Header::parse(&frame) {
+            Ok((_, header)) => header,
+            Err(_) => return Ok(Some(Err(LrthromeError::MalformedPayload))),
+        };
+
+        if header.checksum {
+            let checksum = frame.split_off(len - CHECKSUM_LEN);
+
+            if crc32(&frame).to_le_bytes() != checksum[..] {
+                return Ok(Some(Err(LrthromeError::ChecksumMismatch)));
+            }
+        }
+
+        frame.advance(HEADER_LEN);
+
+        Ok(Some(Ok(Frame {
+            header,
+            payload: frame.freeze(),
+        })))
+    }
+
+    // A frame cut short by the peer closing its end is malformed.
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            None if !src.is_empty() => {
+                src.clear();
+
+                Ok(Some(Err(LrthromeError::MalformedPayload)))
+            }
+            frame => Ok(frame),
+        }
+    }
+}
+
+impl Encoder<Bytes> for LrthromeCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+
+        Ok(())
+    }
+}
+
+const HEADER_LEN: usize = 2;
+
+/// Length of the frame at the start of the buffer, none if yet to be received in full.
+///
+/// A checksum flagged on the frame is included, if `checksums` are accepted.
+fn frame_len(buf: &[u8], max_cstring_len: usize, checksums: bool) -> LrthromeResult<Option<usize>> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    ProtocolVersion::try_from(buf[0])?;
+
+    let (variant, checksum_len) = if checksums && buf[1] & CHECKSUM_FLAG != 0 {
+        (buf[1] & !CHECKSUM_FLAG, CHECKSUM_LEN)
+    } else {
+        (buf[1], 0)
+    };
+
+    let payload = &buf[HEADER_LEN..];
+
+    let payload_len = match Variant::try_from(variant)? {
+        Variant::Identify | Variant::RequestHost => cstrings_len(payload, 1, max_cstring_len)?,
+        // IP address & meta count, followed by the meta pairs.
+        Variant::Request => match payload.get(4) {
+            Some(&meta_count) => {
+                cstrings_len(&payload[5..], meta_count as usize * 2, max_cstring_len)?
+                    .map(|len| 5 + len)
+            }
+            None => None,
+        },
+        Variant::RequestV6 => match payload.get(16) {
+            Some(&meta_count) => {
+                cstrings_len(&payload[17..], meta_count as usize * 2, max_cstring_len)?
+                    .map(|len| 17 + len)
+            }
+            None => None,
+        },
+        // Prefix & mask length.
+        Variant::RequestCovered => Some(5).filter(|len| payload.len() >= *len),
+        Variant::RequestAll => Some(4).filter(|len| payload.len() >= *len),
+        // Address count, followed by the addresses.
+        Variant::RequestBatch => match payload.get(..2) {
+            Some(count) => Some(2 + u16::from_le_bytes([count[0], count[1]]) as usize * 4)
+                .filter(|len| payload.len() >= *len),
+            None => None,
+        },
+        Variant::Ping | Variant::Health | Variant::RequestStats => Some(0),
+        // Only sent by the server, such as a client echoing back established.
+        variant => return Err(LrthromeError::ServerOnlyVariant(variant)),
+    };
+
+    Ok(payload_len
+        .map(|len| HEADER_LEN + len + checksum_len)
+        .filter(|len| buf.len() >= *len))
+}
+
+/// Length of `n` consecutive NUL terminated strings, none if yet to be terminated.
+///
+/// Malformed if a string is longer than `max_len`, terminated or not.
+fn cstrings_len(buf: &[u8], n: usize, max_len: usize) -> LrthromeResult<Option<usize>> {
+    let mut len = 0;
+
+    for _ in 0..n {
+        let rest = &buf[len..];
+
+        match rest.iter().take(max_len + 1).position(|b| *b == 0) {
+            Some(pos) => len += pos + 1,
+            None if rest.len() > max_len => return Err(LrthromeError::MalformedPayload),
+            None => return Ok(None),
+        }
+    }
+
+    Ok(Some(len))
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use crate::protocol::PROTOCOL_VERSION;
+
+    #[test]
+    #[rustfmt::skip]
+    fn decode_pipelined() {
+        let mut codec = LrthromeCodec::default();
+
+        let mut buf = BytesMut::from(&[
+            PROTOCOL_VERSION, Variant::Request as u8,
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0x01, // Meta count
+            0x61, 0x00, 0x62, 0x00, // a = b
+            PROTOCOL_VERSION, Variant::Identify as u8,
+            0x66, 0x69, // Partial token
+        ][..]);
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+
+        assert_eq!(frame.header.variant, Variant::Request);
+        assert_eq!(frame.payload.len(), 9);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&[0x00]);
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+
+        assert_eq!(frame.header.variant, Variant::Identify);
+        assert_eq!(&frame.payload[..], b"fi\0");
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn decode_ping() {
+        let mut codec = LrthromeCodec::default();
+
+        let mut buf = BytesMut::from(&[
+            PROTOCOL_VERSION, Variant::Ping as u8,
+            PROTOCOL_VERSION, Variant::Ping as u8,
+        ][..]);
+
+        for _ in 0..2 {
+            let frame = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+
+            assert_eq!(frame.header.variant, Variant::Ping);
+            assert!(frame.payload.is_empty());
+        }
+
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn decode_checksummed() {
+        let request = [
+            PROTOCOL_VERSION, Variant::Request as u8 | CHECKSUM_FLAG,
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0x00, // Meta count
+        ];
+
+        let mut frame = request.to_vec();
+        frame.extend_from_slice(&crc32(&request).to_le_bytes());
+
+        // Refused as an invalid variant unless checksums are accepted.
+        let mut buf = BytesMut::from(&frame[..]);
+
+        assert!(matches!(
+            LrthromeCodec::default().decode(&mut buf).unwrap(),
+            Some(Err(LrthromeError::InvalidMessageVariant(_)))
+        ));
+
+        let mut codec = LrthromeCodec::new(MAX_CSTRING_LEN, MAX_FRAME_LEN, true);
+
+        // Not yielded until the checksum is received in full.
+        let mut buf = BytesMut::from(&frame[..frame.len() - 1]);
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+
+        assert_eq!(decoded.header.variant, Variant::Request);
+        assert!(decoded.header.checksum);
+        assert_eq!(&decoded.payload[..], &request[2..]);
+
+        // Corrupted in transit.
+        frame[3] ^= 0x01;
+
+        let mut buf = BytesMut::from(&frame[..]);
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Err(e @ LrthromeError::ChecksumMismatch)) => assert_eq!(e.code(), 10),
+            _ => panic!("Corrupted frame was not refused"),
+        }
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn decode_byte_at_a_time() {
+        let mut codec = LrthromeCodec::default();
+
+        let request = [
+            PROTOCOL_VERSION, Variant::Request as u8,
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0x01, // Meta count
+            0x61, 0x00, 0x62, 0x00, // a = b
+        ];
+
+        let mut buf = BytesMut::new();
+
+        // However the request is segmented, no frame is yielded until it is complete.
+        for b in &request[..request.len() - 1] {
+            buf.extend_from_slice(&[*b]);
+
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+
+        buf.extend_from_slice(&request[request.len() - 1..]);
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap().unwrap();
+
+        assert_eq!(frame.header.variant, Variant::Request);
+        assert_eq!(frame.payload.len(), 9);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_unterminated_string() {
+        let mut codec = LrthromeCodec::new(16, MAX_FRAME_LEN, false);
+
+        let mut buf = BytesMut::from(&[PROTOCOL_VERSION, Variant::Identify as u8][..]);
+
+        buf.extend_from_slice(&[0x61; 16]);
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+
+        // Refused as soon as the string outgrows the limit, rather than the frame length.
+        buf.extend_from_slice(&[0x61]);
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(LrthromeError::MalformedPayload))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn decode_oversized_frame() {
+        let mut codec = LrthromeCodec::new(16, 32, false);
+
+        let mut buf = BytesMut::from(&[
+            PROTOCOL_VERSION, Variant::Request as u8,
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0xFF, // Meta count
+        ][..]);
+
+        // Every string is within its limit, the frame as a whole is not.
+        for _ in 0..2 {
+            buf.extend_from_slice(b"0123456789\0");
+
+            assert!(codec.decode(&mut buf).unwrap().is_none());
+        }
+
+        buf.extend_from_slice(b"0123456789\0");
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(LrthromeError::MalformedPayload))
+        ));
+        assert!(buf.is_empty());
+
+        // A completed frame is refused likewise.
+        let mut buf = BytesMut::from(&[
+            PROTOCOL_VERSION, Variant::Request as u8,
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0x02, // Meta count
+        ][..]);
+
+        buf.extend_from_slice(b"0123456789\0abcdefghij\0ab\0cd\0");
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(LrthromeError::MalformedPayload))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn decode_server_only_variants() {
+        let mut codec = LrthromeCodec::default();
+
+        for variant in [
+            Variant::Established,
+            Variant::ResponseOkFound,
+            Variant::ResponseOkNotFound,
+            Variant::ResponseError,
+            Variant::ResponseHost,
+            Variant::ResponseOkFoundV6,
+            Variant::ResponseOkNotFoundV6,
+            Variant::ResponseCovered,
+            Variant::ResponseNotCovered,
+            Variant::ResponseBatch,
+            Variant::Pong,
+            Variant::ResponseHealth,
+            Variant::ResponseStats,
+            Variant::ResponseAll,
+            Variant::ResponseErrorV6,
+        ] {
+            // Trailing bytes are discarded along with the refused frame.
+            let mut buf =
+                BytesMut::from(&[PROTOCOL_VERSION, variant.clone() as u8, 0x01, 0x02][..]);
+
+            match codec.decode(&mut buf).unwrap() {
+                Some(Err(e @ LrthromeError::ServerOnlyVariant(_))) => {
+                    assert_eq!(e.code(), 3);
+                    assert_eq!(
+                        e.to_string(),
+                        format!("Message variant {} is only sent by the server", variant)
+                    );
+                }
+                _ => panic!("{} was not refused", variant),
+            }
+
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn decode_protocol_errors() {
+        let mut codec = LrthromeCodec::default();
+
+        let mut buf = BytesMut::from(&[PROTOCOL_VERSION, Variant::ResponseOkFound as u8][..]);
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(LrthromeError::ServerOnlyVariant(Variant::ResponseOkFound)))
+        ));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&[PROTOCOL_VERSION, Variant::RequestHost as u8, 0x61][..]);
+
+        assert!(matches!(
+            codec.decode_eof(&mut buf).unwrap(),
+            Some(Err(LrthromeError::MalformedPayload))
+        ));
+    }
+}
@@ -14,8 +14,19 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::num::NonZeroU32;
+use std::path::Path;
+use std::str::FromStr;
+
 use serde::Deserialize;
 
+use crate::codec::MAX_FRAME_LEN;
+use crate::error::{LrthromeError, LrthromeResult};
+use crate::protocol::{MAX_CSTRING_LEN, MAX_META_COUNT};
+use crate::sources::DEFAULT_FETCH_TIMEOUT;
+
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(rename(deserialize = "General"))]
@@ -23,38 +34,562 @@ pub struct Config {
 
     #[serde(rename(deserialize = "Sources"))]
     pub sources: Sources,
+
+    /// Locale variants of human facing messages, keyed by language tag.
+    #[serde(rename(deserialize = "Locales"), default)]
+    pub locales: HashMap<String, Locale>,
+
+    /// Server-side hostname resolution.
+    /// Hostname requests are refused when absent.
+    #[serde(rename(deserialize = "Resolver"))]
+    pub resolver: Option<Resolver>,
+
+    /// Identify token authentication.
+    /// Identify frames are ignored when absent.
+    #[serde(rename(deserialize = "Auth"))]
+    pub auth: Option<Auth>,
+
+    /// Operator command listener.
+    /// Admin commands are not accepted when absent.
+    #[serde(rename(deserialize = "Admin"))]
+    pub admin: Option<Admin>,
+
+    /// Metrics listener for scrapers.
+    /// No listener is started when absent.
+    #[serde(rename(deserialize = "Metrics"))]
+    pub metrics: Option<Metrics>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> LrthromeResult<Self> {
+        let path = path.as_ref();
+
+        let body = std::fs::read(path).map_err(|e| {
+            LrthromeError::InvalidConfig(format!("unable to read {}: {}", path.display(), e))
+        })?;
+
+        let mut config: Self = toml::from_slice(&body)
+            .map_err(|e| LrthromeError::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+
+        Ok(config)
+    }
+
+    /// Override values of the file with those of environment variables,
+    /// such as `LRTHROME_RATE_LIMIT`, for deploys without a config file of their own.
+    fn apply_overrides<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> LrthromeResult<()> {
+        let general = &mut self.general;
+
+        // Comma separated, as a list has no other representation in a variable.
+        if let Some(bind_address) = var("LRTHROME_BIND_ADDRESS") {
+            general.bind_address = bind_address
+                .split(',')
+                .map(|address| address.trim().to_string())
+                .collect();
+        }
+
+        if let Some(banner) = var("LRTHROME_BANNER") {
+            general.banner = banner;
+        }
+
+        override_parsed(&var, "LRTHROME_RATE_LIMIT", &mut general.rate_limit)?;
+        override_parsed(&var, "LRTHROME_CACHE_TTL", &mut general.cache_ttl)?;
+        override_parsed(&var, "LRTHROME_PEER_TTL", &mut general.peer_ttl)?;
+
+        Ok(())
+    }
+
+    /// Check invariants the types alone do not hold,
+    /// so a misconfiguration is refused upfront rather than failing once serving.
+    pub fn validate(&self) -> LrthromeResult<()> {
+        let general = &self.general;
+
+        let invalid = |message: &str| Err(LrthromeError::InvalidConfig(message.to_string()));
+
+        if general.bind_address.is_empty() {
+            return invalid("bind_address must list at least one address");
+        }
+
+        if general.bind_address.iter().any(|a| a.trim().is_empty()) {
+            return invalid("bind_address must not be empty");
+        }
+
+        if general.rate_limit == 0 {
+            return invalid("rate_limit must be at least 1");
+        }
+
+        if general.max_in_flight == 0 {
+            return invalid("max_in_flight must be at least 1, or clients are never read from");
+        }
+
+        if general.max_queued_responses < general.max_in_flight as usize {
+            return invalid(
+                "max_queued_responses must be at least max_in_flight, or pipelining clients are disconnected",
+            );
+        }
+
+        if general.cache_jitter > 100 {
+            return invalid("cache_jitter must be a percentage of at most 100");
+        }
+
+        if general.min_tree_percent > 100 {
+            return invalid("min_tree_percent must be a percentage of at most 100");
+        }
+
+        if self
+            .admin
+            .as_ref()
+            .is_some_and(|a| a.bind_address.trim().is_empty())
+        {
+            return invalid("[Admin] bind_address must not be empty");
+        }
+
+        if self
+            .metrics
+            .as_ref()
+            .is_some_and(|m| m.bind_address.trim().is_empty())
+        {
+            return invalid("[Metrics] bind_address must not be empty");
+        }
+
+        if self.sources.refresh_secs.values().any(|secs| *secs == 0) {
+            return invalid("[Sources] refresh_secs must be at least 1 for every source");
+        }
+
+        let normalizes = self
+            .sources
+            .remotes
+            .iter()
+            .filter_map(|r| match r {
+                Remote::Table { url, normalize, .. } => Some((url.as_str(), normalize)),
+                Remote::Url(_) => None,
+            })
+            .chain(std::iter::once((
+                "GeoLite",
+                &self.sources.geolite.normalize,
+            )))
+            .chain(
+                self.sources
+                    .git
+                    .iter()
+                    .map(|g| (g.url.as_str(), &g.normalize)),
+            );
+
+        // Otherwise every entry of the source is silently dropped.
+        for (source, normalize) in normalizes {
+            if normalize.max_mask > 32 {
+                return invalid(&format!("max_mask of {} must be at most 32", source));
+            }
+
+            if normalize.min_mask > normalize.max_mask {
+                return invalid(&format!(
+                    "min_mask of {} must be at most its max_mask of {}",
+                    source, normalize.max_mask
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Override the value with the environment variable's, if set.
+fn override_parsed<F, T>(var: &F, name: &str, value: &mut T) -> LrthromeResult<()>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(raw) = var(name) {
+        *value = raw.trim().parse().map_err(|e| {
+            LrthromeError::InvalidConfig(format!("{} of {:?} is invalid: {}", name, raw, e))
+        })?;
+    }
+
+    Ok(())
 }
 
 #[derive(Deserialize)]
 pub struct General {
-    pub bind_address: String,
+    /// Addresses the TCP server binds to, either a single address or a list of them.
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind_address: Vec<String>,
+
+    /// Times binding is retried should the address be unavailable, before giving up.
+    #[serde(default)]
+    pub bind_retries: u32,
+
+    /// Seconds waited before the first bind retry, doubling after every attempt.
+    #[serde(default = "default_bind_retry_secs")]
+    pub bind_retry_secs: u32,
+
+    /// Most clients connected at once, 0 if unlimited.
+    #[serde(default)]
+    pub max_connections: u32,
+
+    /// Most clients connected at once from a single IP address, 0 if unlimited.
+    #[serde(default)]
+    pub max_connections_per_ip: u32,
+
+    /// Seconds peers are given to disconnect upon shutdown, before they are dropped.
+    #[serde(default = "default_shutdown_grace_secs")]
+    pub shutdown_grace_secs: u32,
 
     /// Cache time-to-live.
     /// Interval in seconds the cache will be purged and fetched again.
     pub cache_ttl: u32,
 
+    /// Cache refresh jitter.
+    /// Percentage of `cache_ttl` the refresh interval is randomly offset by, in either direction.
+    #[serde(default)]
+    pub cache_jitter: u8,
+
+    /// File the tree is written to after every temper & upon shutdown,
+    /// and served from upon startup until the first temper completes.
+    pub cache_persist_path: Option<String>,
+
+    /// Percentage of the current tree's entries a tempered tree must have to replace it.
+    /// Set to 0 to always replace it.
+    #[serde(default = "default_min_tree_percent")]
+    pub min_tree_percent: u8,
+
     /// Peer time-to-live.
     /// Interval that a peer's connection can stay alive without additional requests.
     pub peer_ttl: u32,
 
-    /// Maximum rate over the span of 5 seconds.
+    /// Maximum rate over the span of `rate_limit_window_secs`.
     /// Multiple connections on a single IP address are aggregated together.
     pub rate_limit: u32,
 
+    /// Seconds rate limits apply over, advertised to clients in established.
+    #[serde(default = "default_rate_limit_window_secs")]
+    pub rate_limit_window_secs: NonZeroU32,
+
+    /// Number of in-flight requests & queued responses a client may have.
+    /// The client is no longer read from until its responses are written out.
+    #[serde(default = "default_max_in_flight")]
+    pub max_in_flight: u32,
+
+    /// Number of responses queued to a client before it is disconnected for not reading them.
+    #[serde(default = "default_max_queued_responses")]
+    pub max_queued_responses: usize,
+
+    /// Longest string accepted within client frames, such as identify tokens & meta.
+    #[serde(default = "default_max_cstring_len")]
+    pub max_cstring_len: usize,
+
+    /// Longest frame accepted from clients, header included.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+
+    /// Most meta pairs accepted within a request.
+    #[serde(default = "default_max_meta_count")]
+    pub max_meta_count: u8,
+
+    /// Most addresses accepted within a single batch request.
+    #[serde(default = "default_max_batch")]
+    pub max_batch: usize,
+
     /// Banner message sent to clients upon established.
     pub banner: String,
+
+    /// Omit the banner field from established entirely, rather than sending it empty.
+    #[serde(default)]
+    pub omit_banner: bool,
+
+    /// Defer established until the client's first frame,
+    /// so its banner can be localized to the language requested in meta.
+    #[serde(default)]
+    pub defer_established: bool,
+
+    /// Accept frames flagged with a trailing CRC-32 from clients,
+    /// and checksum responses to clients that send them.
+    #[serde(default)]
+    pub frame_checksums: bool,
+
+    /// Number of requests a new connection may make before rate limits are enforced.
+    #[serde(default)]
+    pub rate_limit_grace: u32,
+
+    /// Whether a client exceeding its rate limit is disconnected, or only has the request rejected.
+    #[serde(default)]
+    pub ratelimit_action: RatelimitAction,
+
+    /// Addresses & CIDRs of clients never rate limited, such as monitoring hosts.
+    #[serde(default)]
+    pub ratelimit_exempt: Vec<String>,
+
+    /// Rate limit violations of an address within `ban_window_secs` it is banned upon,
+    /// 0 if never.
+    #[serde(default)]
+    pub ban_threshold: u32,
+
+    /// Seconds violations are counted over towards `ban_threshold`.
+    #[serde(default = "default_ban_window_secs")]
+    pub ban_window_secs: u64,
+
+    /// Seconds an address is banned for upon reaching `ban_threshold`.
+    #[serde(default = "default_ban_duration_secs")]
+    pub ban_duration_secs: u64,
+
+    /// Seconds closing a peer socket may block to flush unsent bytes (`SO_LINGER`).
+    /// Operating system default if absent.
+    pub linger_secs: Option<u32>,
+
+    /// Bytes per second responses are written out to a single client, unlimited if absent.
+    pub egress_rate: Option<NonZeroU32>,
+
+    /// Bytes a client's responses may burst to, defaults to `egress_rate`.
+    pub egress_burst: Option<u32>,
+
+    /// Threads of a runtime dedicated to tempering, apart from the one serving clients.
+    /// Tempers share the serving threads if absent.
+    pub temper_threads: Option<usize>,
+}
+
+/// Locale variant of human facing messages.
+#[derive(Deserialize)]
+pub struct Locale {
+    /// Banner sent upon established, base banner if absent.
+    pub banner: Option<String>,
+
+    /// Error messages keyed by error code, base message if absent.
+    #[serde(default)]
+    pub errors: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+pub struct Auth {
+    /// Path to the file of identify tokens, kept apart from this config.
+    /// Reloaded on SIGHUP.
+    pub token_file: String,
+}
+
+#[derive(Deserialize)]
+pub struct Admin {
+    /// Address the admin listener binds to.
+    /// Commands are unauthenticated, this should only be reachable by operators.
+    pub bind_address: String,
+
+    /// Seconds an address banned with `ban-ip` is refused for.
+    #[serde(default = "default_ban_secs")]
+    pub ban_secs: u64,
+
+    /// File bans are persisted to, surviving restarts. Bans are kept in memory only if absent.
+    pub ban_file: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Metrics {
+    /// Address metrics are served over HTTP on, at `/metrics`.
+    pub bind_address: String,
+}
+
+#[derive(Deserialize)]
+pub struct Resolver {
+    /// Maximum number of resolutions in flight across all peers.
+    #[serde(default = "default_resolver_max_concurrent")]
+    pub max_concurrent: usize,
+
+    /// Seconds allowed for a single resolution before giving up.
+    #[serde(default = "default_resolver_timeout_secs")]
+    pub timeout_secs: u32,
+
+    /// Seconds a resolved hostname is served from cache.
+    #[serde(default = "default_resolver_cache_ttl")]
+    pub cache_ttl: u32,
 }
 
 #[derive(Deserialize)]
 pub struct Sources {
-    pub remotes: Vec<String>,
+    /// Broadest mask length any source may insert into the tree.
+    ///
+    /// Guards against a single bad entry such as 0.0.0.0/0 matching every address.
+    /// Setting this to 0 opts into accepting a default route.
+    #[serde(default = "default_prefix_floor")]
+    pub prefix_floor: u8,
+
+    /// Consecutive failures after which a source's circuit breaker opens, 0 to disable.
+    #[serde(default)]
+    pub breaker_threshold: u32,
+
+    /// Seconds an open circuit breaker waits before allowing a probe fetch.
+    #[serde(default = "default_breaker_cooldown_secs")]
+    pub breaker_cooldown_secs: u64,
+
+    /// Keep denied prefixes in a tree per source, queried & combined at lookup.
+    #[serde(default)]
+    pub per_source_trees: bool,
+
+    /// Collapse duplicate & contained prefixes of each source, and merge adjacent ones.
+    #[serde(default)]
+    pub aggregate_cidrs: bool,
+
+    /// Times a failed fetch of a remote endpoint is retried, before failing over to its mirrors.
+    #[serde(default)]
+    pub fetch_retries: u32,
+
+    /// Milliseconds before the first retry of a fetch, doubling after every attempt.
+    #[serde(default = "default_fetch_retry_base_ms")]
+    pub fetch_retry_base_ms: u64,
+
+    /// Seconds a remote fetch may wait to connect, for response headers, or between body chunks.
+    #[serde(default = "default_fetch_timeout_secs")]
+    pub fetch_timeout_secs: u64,
+
+    /// Start even if no source is configured to yield anything, every lookup being not found.
+    #[serde(default)]
+    pub allow_empty_sources: bool,
+
+    /// Seconds between refreshes of a source by its name, refreshed every `cache_ttl` otherwise.
+    #[serde(default)]
+    pub refresh_secs: HashMap<String, u32>,
+
+    pub remotes: Vec<Remote>,
 
     #[serde(rename = "GeoLite")]
     pub geolite: GeoLite,
+
+    #[serde(rename = "Bogon", default)]
+    pub bogon: Bogon,
+
+    #[serde(rename = "Git", default)]
+    pub git: Vec<Git>,
+}
+
+/// Whether a source's entries are blocked, exempted from other sources' blocks,
+/// or only compared against lookups without affecting them.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    Deny,
+
+    Allow,
+
+    /// Matches are metered against the live result, but never returned to peers,
+    /// for evaluating a candidate source against real traffic.
+    Shadow,
+}
+
+/// Action taken upon a client exceeding its rate limit.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RatelimitAction {
+    /// Respond with the error and close the connection.
+    #[default]
+    Disconnect,
+
+    /// Respond with the error to the request, keeping the connection open.
+    Reject,
+}
+
+/// Lists tracked in a Git repository.
+#[derive(Deserialize)]
+pub struct Git {
+    pub url: String,
+
+    /// Branch tracked, the remote's default branch if absent.
+    pub branch: Option<String>,
+
+    /// Directory the repository is cloned into.
+    pub checkout_dir: String,
+
+    /// Files read as CIDR lists, relative to the repository root.
+    pub files: Vec<String>,
+
+    #[serde(default, alias = "kind")]
+    pub mode: Mode,
+
+    /// Priority over sources of another mode listing the same prefix, higher winning.
+    #[serde(default)]
+    pub priority: i32,
+
+    #[serde(flatten)]
+    pub normalize: Normalize,
+}
+
+/// Built-in private, shared & reserved ranges.
+#[derive(Deserialize, Default)]
+pub struct Bogon {
+    #[serde(default)]
+    pub enabled: bool,
+
+    #[serde(default, alias = "kind")]
+    pub mode: Mode,
+
+    /// Priority over sources of another mode listing the same prefix, higher winning.
+    #[serde(default)]
+    pub priority: i32,
+}
+
+/// Remote endpoint entry.
+///
+/// Either a bare URL, or a table with the URL and per-source options.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Remote {
+    Url(String),
+
+    Table {
+        url: String,
+
+        /// Mirrors of the same list, tried in order should `url` fail.
+        #[serde(default)]
+        mirrors: Vec<String>,
+
+        /// Encoding label lines not valid UTF-8 are decoded with, such as `latin1`.
+        encoding: Option<String>,
+
+        /// Headers sent with every request to the URL & its mirrors, such as an API key.
+        #[serde(default)]
+        headers: HashMap<String, String>,
+
+        #[serde(default, alias = "kind")]
+        mode: Mode,
+
+        /// Priority over sources of another mode listing the same prefix, higher winning.
+        #[serde(default)]
+        priority: i32,
+
+        #[serde(flatten)]
+        normalize: Normalize,
+    },
+}
+
+/// Per-source normalization options.
+#[derive(Deserialize)]
+pub struct Normalize {
+    /// Broadest mask length accepted, entries shorter are dropped.
+    #[serde(default)]
+    pub min_mask: u8,
+
+    /// Narrowest mask length accepted, entries longer are dropped.
+    #[serde(default = "default_max_mask")]
+    pub max_mask: u8,
+
+    /// Truncate entries with host bits set to their network, rather than dropping them.
+    #[serde(default)]
+    pub normalize_host_bits: bool,
 }
 
 #[derive(Deserialize)]
 pub struct GeoLite {
+    #[serde(flatten)]
+    pub normalize: Normalize,
+
+    /// Format every database is in.
+    #[serde(default)]
+    pub format: GeoLiteFormat,
+
+    #[serde(default, alias = "kind")]
+    pub mode: Mode,
+
+    /// Priority over sources of another mode listing the same prefix, higher winning.
+    #[serde(default)]
+    pub priority: i32,
+
     #[serde(rename = "ASN")]
     pub asn: GeoLiteAsn,
 
@@ -65,6 +600,18 @@ pub struct GeoLite {
     pub country: GeoLiteCountry,
 }
 
+/// Format of MaxMind's GeoLite databases.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GeoLiteFormat {
+    /// CSV exports of IPv4 blocks.
+    #[default]
+    Csv,
+
+    /// Binary databases, updated in place.
+    Mmdb,
+}
+
 #[derive(Deserialize)]
 pub struct GeoLiteAsn {
     pub database_path: String,
@@ -85,3 +632,286 @@ pub struct GeoLiteCountry {
 
     pub countries: Vec<u32>,
 }
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Self {
+            min_mask: 0,
+            max_mask: default_max_mask(),
+            normalize_host_bits: false,
+        }
+    }
+}
+
+fn default_max_mask() -> u8 {
+    32
+}
+
+fn default_bind_retry_secs() -> u32 {
+    1
+}
+
+fn default_rate_limit_window_secs() -> NonZeroU32 {
+    NonZeroU32::new(5).unwrap()
+}
+
+fn default_min_tree_percent() -> u8 {
+    50
+}
+
+fn default_shutdown_grace_secs() -> u32 {
+    5
+}
+
+fn default_max_in_flight() -> u32 {
+    64
+}
+
+fn default_max_queued_responses() -> usize {
+    256
+}
+
+fn default_max_batch() -> usize {
+    256
+}
+
+fn default_max_cstring_len() -> usize {
+    MAX_CSTRING_LEN
+}
+
+fn default_max_frame_bytes() -> usize {
+    MAX_FRAME_LEN
+}
+
+fn default_max_meta_count() -> u8 {
+    MAX_META_COUNT
+}
+
+fn default_ban_secs() -> u64 {
+    3600
+}
+
+fn default_ban_window_secs() -> u64 {
+    60
+}
+
+fn default_ban_duration_secs() -> u64 {
+    600
+}
+
+fn default_fetch_timeout_secs() -> u64 {
+    DEFAULT_FETCH_TIMEOUT.as_secs()
+}
+
+fn default_fetch_retry_base_ms() -> u64 {
+    500
+}
+
+fn default_breaker_cooldown_secs() -> u64 {
+    3600
+}
+
+fn default_prefix_floor() -> u8 {
+    1
+}
+
+fn default_resolver_max_concurrent() -> usize {
+    8
+}
+
+fn default_resolver_timeout_secs() -> u32 {
+    3
+}
+
+fn default_resolver_cache_ttl() -> u32 {
+    300
+}
+
+/// Accept either a single string or a list of them.
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn parse_example_config() {
+        let config: Config = toml::from_slice(include_bytes!("../config.toml.example")).unwrap();
+
+        assert_eq!(config.general.bind_address, vec!["0.0.0.0:25597"]);
+        assert_eq!(config.sources.geolite.normalize.max_mask, 32);
+        assert_eq!(config.sources.geolite.mode, Mode::Deny);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn override_from_env() {
+        let mut config: Config =
+            toml::from_slice(include_bytes!("../config.toml.example")).unwrap();
+
+        config
+            .apply_overrides(|name| match name {
+                "LRTHROME_BIND_ADDRESS" => Some("0.0.0.0:25597, [::]:25597".to_string()),
+                "LRTHROME_RATE_LIMIT" => Some("250".to_string()),
+                _ => None,
+            })
+            .unwrap();
+
+        assert_eq!(
+            config.general.bind_address,
+            vec!["0.0.0.0:25597", "[::]:25597"]
+        );
+        assert_eq!(config.general.rate_limit, 250);
+        assert_eq!(config.general.cache_ttl, 86400);
+
+        let error = config
+            .apply_overrides(|name| match name {
+                "LRTHROME_CACHE_TTL" => Some("1d".to_string()),
+                _ => None,
+            })
+            .unwrap_err();
+
+        assert!(error
+            .to_string()
+            .contains("LRTHROME_CACHE_TTL of \"1d\" is invalid"));
+    }
+
+    #[test]
+    fn validate_invariants() {
+        let config = |from: &str, to: &str| -> Config {
+            let example = include_str!("../config.toml.example");
+
+            assert!(example.contains(from));
+
+            toml::from_str(&example.replacen(from, to, 1)).unwrap()
+        };
+
+        let error = |config: Config| config.validate().unwrap_err().to_string();
+
+        assert_eq!(
+            error(config("rate_limit = 100", "rate_limit = 0")),
+            "Invalid config, rate_limit must be at least 1"
+        );
+        assert_eq!(
+            error(config(
+                "bind_address = \"0.0.0.0:25597\"",
+                "bind_address = \"\""
+            )),
+            "Invalid config, bind_address must not be empty"
+        );
+        assert_eq!(
+            error(config(
+                "bind_address = \"0.0.0.0:25597\"",
+                "bind_address = []"
+            )),
+            "Invalid config, bind_address must list at least one address"
+        );
+
+        let dual_stack = config(
+            "bind_address = \"0.0.0.0:25597\"",
+            "bind_address = [\"0.0.0.0:25597\", \"[::]:25597\"]",
+        );
+
+        assert_eq!(dual_stack.general.bind_address.len(), 2);
+        assert!(dual_stack.validate().is_ok());
+        assert!(error(config("cache_jitter = 10", "cache_jitter = 150")).contains("cache_jitter"));
+        assert!(error(config(
+            "max_queued_responses = 256",
+            "max_queued_responses = 16"
+        ))
+        .contains("max_queued_responses"));
+
+        // Peers never timing out is intended.
+        assert!(config("peer_ttl = 15", "peer_ttl = 0").validate().is_ok());
+
+        assert_eq!(
+            error(config(
+                "format = \"csv\"",
+                "format = \"csv\"\nmax_mask = 33"
+            )),
+            "Invalid config, max_mask of GeoLite must be at most 32"
+        );
+        assert_eq!(
+            error(config(
+                "format = \"csv\"",
+                "format = \"csv\"\nmin_mask = 24\nmax_mask = 16"
+            )),
+            "Invalid config, min_mask of GeoLite must be at most its max_mask of 16"
+        );
+    }
+
+    #[test]
+    fn parse_remote_table() {
+        let sources: Sources = toml::from_str(
+            r#"
+            remotes = [
+                "https://example.com/a.netset",
+                { url = "https://example.com/b.netset", min_mask = 8, normalize_host_bits = true },
+                { url = "https://a.example.com/c.netset", mirrors = ["https://b.example.com/c.netset"], encoding = "latin1" },
+                { url = "https://example.com/d.netset", headers = { Authorization = "Bearer key" } },
+                { url = "https://example.com/e.netset", kind = "allow", priority = 10 },
+            ]
+
+            [GeoLite.ASN]
+            database_path = ""
+            asns = []
+
+            [GeoLite.City]
+            database_path = ""
+            cities = []
+
+            [GeoLite.Country]
+            database_path = ""
+            countries = []
+            "#,
+        )
+        .unwrap();
+
+        match &sources.remotes[1] {
+            Remote::Table { url, normalize, .. } => {
+                assert_eq!(url, "https://example.com/b.netset");
+                assert_eq!(normalize.min_mask, 8);
+                assert_eq!(normalize.max_mask, 32);
+                assert!(normalize.normalize_host_bits);
+            }
+            _ => panic!("expected remote table"),
+        }
+
+        match &sources.remotes[2] {
+            Remote::Table {
+                mirrors, encoding, ..
+            } => {
+                assert_eq!(mirrors, &["https://b.example.com/c.netset"]);
+                assert_eq!(encoding.as_deref(), Some("latin1"));
+            }
+            _ => panic!("expected remote table"),
+        }
+
+        match &sources.remotes[3] {
+            Remote::Table { headers, .. } => {
+                assert_eq!(headers["Authorization"], "Bearer key");
+            }
+            _ => panic!("expected remote table"),
+        }
+
+        match &sources.remotes[4] {
+            Remote::Table { mode, priority, .. } => {
+                assert_eq!(*mode, Mode::Allow);
+                assert_eq!(*priority, 10);
+            }
+            _ => panic!("expected remote table"),
+        }
+    }
+}
@@ -14,7 +14,13 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::net::IpAddr;
+use std::time::Duration;
+
 use thiserror::Error;
+
+use crate::protocol::Variant;
+
 #[derive(Debug, Error)]
 pub enum LrthromeError {
     #[error("IO error {0}")]
@@ -26,18 +32,90 @@ pub enum LrthromeError {
     #[error("CSV error {0}")]
     CsvError(#[from] csv::Error),
 
+    #[error("MaxMind DB error {0}")]
+    MaxMindDbError(#[from] maxminddb::MaxMindDBError),
+
     #[error("Malformed payload")]
     MalformedPayload,
 
+    /// With the time until the next request would be allowed.
     #[error("Exceeded ratelimit")]
-    Ratelimited,
+    Ratelimited(Duration),
 
-    #[error("Mismatching protocol version, expected {expected}, received {received}")]
-    VersionMismatch { expected: u8, received: u8 },
+    #[error("Unsupported protocol version {received}, supported are {minimum} to {preferred}")]
+    VersionMismatch {
+        minimum: u8,
+        preferred: u8,
+        received: u8,
+    },
 
     #[error("Invalid message variant {0}")]
     InvalidMessageVariant(u8),
 
+    #[error("Message variant {0} is only sent by the server")]
+    ServerOnlyVariant(Variant),
+
+    #[error("Hostname lookups are disabled")]
+    HostLookupDisabled,
+
+    #[error("Invalid identification token")]
+    Unauthorized,
+
+    #[error("Disconnected by an operator: {0}")]
+    Kicked(String),
+
+    #[error("Server is shutting down")]
+    ShuttingDown,
+
+    #[error("Batch of {count} addresses exceeds the maximum of {max}")]
+    BatchTooLarge { count: usize, max: usize },
+
+    #[error("Too many connections, limited to {0}")]
+    TooManyConnections(u32),
+
+    #[error("Frame checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Unknown source {0}")]
+    UnknownSource(String),
+
+    #[error("No usable sources are configured, set allow_empty_sources to start regardless")]
+    NoSources,
+
+    #[error("Source {0} is unavailable")]
+    SourceUnavailable(String),
+
+    #[error("Timed out after {0:?}")]
+    TimedOut(std::time::Duration),
+
+    #[error("Every source failed, the current tree is retained")]
+    SourcesFailed,
+
+    #[error("Tempered tree of {entries} entries is below {percent}% of the current {current}, the current tree is retained")]
+    TreeShrunk {
+        entries: usize,
+        current: usize,
+        percent: u8,
+    },
+
+    #[error("Unknown encoding {0}")]
+    UnknownEncoding(String),
+
+    #[error("Invalid header {0}")]
+    InvalidHeader(String),
+
+    #[error("Source {url} is not valid UTF-8 at line {line}, configure its encoding")]
+    UndecodableSource { url: String, line: usize },
+
+    #[error("OpenSSL error {0}")]
+    OpensslError(#[from] openssl::error::ErrorStack),
+
+    #[error("TOML error {0}")]
+    TomlError(#[from] toml::de::Error),
+
+    #[error("Invalid config, {0}")]
+    InvalidConfig(String),
+
     #[error("Invalid net address {0}")]
     InvalidAddress(#[from] std::net::AddrParseError),
 
@@ -47,6 +125,9 @@ pub enum LrthromeError {
     #[error("Invalid CIDR {0}")]
     InvalidCidr(#[from] cidr::NetworkParseError),
 
+    #[error("Temper task failed {0}")]
+    TemperTaskError(#[from] tokio::task::JoinError),
+
     #[error("Stream shutdown watch channel error {0}")]
     ShutdownWatchError(#[from] tokio::sync::watch::error::SendError<bool>),
 }
@@ -55,15 +136,58 @@ impl LrthromeError {
     pub fn code(&self) -> u8 {
         match *self {
             LrthromeError::MalformedPayload => 0,
-            LrthromeError::Ratelimited => 1,
-            LrthromeError::VersionMismatch {
-                expected: _,
-                received: _,
-            } => 2,
-            LrthromeError::InvalidMessageVariant(_) => 3,
+            LrthromeError::Ratelimited(_) => 1,
+            LrthromeError::VersionMismatch { .. } => 2,
+            LrthromeError::InvalidMessageVariant(_) | LrthromeError::ServerOnlyVariant(_) => 3,
+            LrthromeError::HostLookupDisabled => 4,
+            LrthromeError::Unauthorized => 5,
+            LrthromeError::Kicked(_) => 6,
+            LrthromeError::ShuttingDown => 7,
+            LrthromeError::BatchTooLarge { .. } => 8,
+            LrthromeError::TooManyConnections(_) => 9,
+            LrthromeError::ChecksumMismatch => 10,
+            LrthromeError::UnknownSource(_) => 11,
             _ => 255,
         }
     }
 }
 
+/// Error arising from a peer frame,
+/// attributed to the requested address if it arose from a specific request.
+#[derive(Debug)]
+pub struct FrameError {
+    pub error: LrthromeError,
+
+    pub ip_address: Option<IpAddr>,
+}
+
+impl LrthromeError {
+    /// Milliseconds until the peer may retry, 0 if unspecified.
+    pub fn retry_after_ms(&self) -> u32 {
+        match self {
+            LrthromeError::Ratelimited(retry_after) => {
+                retry_after.as_millis().clamp(1, u32::MAX as u128) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// Attribute the error to the requested address, of either family.
+    pub fn for_request(self, ip_address: Option<IpAddr>) -> FrameError {
+        FrameError {
+            error: self,
+            ip_address,
+        }
+    }
+}
+
+impl From<LrthromeError> for FrameError {
+    fn from(error: LrthromeError) -> Self {
+        Self {
+            error,
+            ip_address: None,
+        }
+    }
+}
+
 pub type LrthromeResult<T> = std::result::Result<T, LrthromeError>;
@@ -0,0 +1,234 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+
+use cidr::{Cidr, Ipv4Cidr};
+
+use crate::cache::overrides;
+use crate::sources::SourceSet;
+
+/// Snapshot of the tree, yielding the effective denied prefixes in ascending order.
+///
+/// Allow subtraction is applied & adjacent denied prefixes are merged,
+/// so the output matches what lookups would actually deny rather than the raw sources.
+///
+/// Prefixes are computed lazily as iterated, only the snapshot itself resides in memory.
+pub struct Export {
+    /// Denied prefixes with the sources that listed them.
+    deny: HashMap<(u32, u8), SourceSet>,
+
+    /// Allowed prefixes with the sources that listed them.
+    allow: HashMap<(u32, u8), SourceSet>,
+
+    /// Priority of each source in registration order, resolving prefixes of the same length.
+    priorities: Vec<i32>,
+
+    /// Every entry of both modes, sorted by network & length.
+    entries: Vec<(u32, u8)>,
+
+    /// Prefixes pending a visit, lowest on top.
+    stack: Vec<(u32, u8)>,
+}
+
+impl Export {
+    pub fn new<D, A>(deny: D, allow: A, priorities: Vec<i32>) -> Self
+    where
+        D: Iterator<Item = (Ipv4Addr, u32, SourceSet)>,
+        A: Iterator<Item = (Ipv4Addr, u32, SourceSet)>,
+    {
+        let deny = listed(deny);
+        let allow = listed(allow);
+
+        let mut entries = deny.keys().chain(allow.keys()).copied().collect::<Vec<_>>();
+
+        entries.sort_unstable();
+        entries.dedup();
+
+        Self {
+            deny,
+            allow,
+            priorities,
+            entries,
+            stack: vec![(0, 0)],
+        }
+    }
+
+    /// Whether any entry lies strictly within the prefix.
+    fn has_inner(&self, net: u32, len: u8) -> bool {
+        if len == 32 {
+            return false;
+        }
+
+        // Entries at the same network sort by length, so the first entry past
+        // (net, len) is either the same network narrower, or a later network.
+        let i = self.entries.partition_point(|e| *e <= (net, len));
+
+        self.entries
+            .get(i)
+            .is_some_and(|&(addr, _)| addr <= net | !mask(len))
+    }
+
+    /// Whether an address within a prefix without inner entries is denied.
+    ///
+    /// Mirrors the lookup, the longest allow exempts it should it override the longest deny.
+    fn denied(&self, net: u32, len: u8) -> bool {
+        let longest = |map: &HashMap<(u32, u8), SourceSet>| {
+            (0..=len)
+                .rev()
+                .find_map(|l| map.get(&(net & mask(l), l)).map(|set| (l as u32, *set)))
+        };
+
+        match (longest(&self.deny), longest(&self.allow)) {
+            (Some((d, listed)), Some((a, allowed))) => {
+                !overrides(&self.priorities, a, allowed, d, || listed)
+            }
+            (Some(_), None) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the prefix is denied in its entirety.
+    fn fully_denied(&self, net: u32, len: u8) -> bool {
+        if !self.has_inner(net, len) {
+            return self.denied(net, len);
+        }
+
+        let (low, high) = halves(net, len);
+
+        self.fully_denied(low.0, low.1) && self.fully_denied(high.0, high.1)
+    }
+}
+
+impl Iterator for Export {
+    type Item = Ipv4Cidr;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((net, len)) = self.stack.pop() {
+            if self.fully_denied(net, len) {
+                return Some(Ipv4Cidr::new(Ipv4Addr::from(net), len).unwrap());
+            }
+
+            if self.has_inner(net, len) {
+                let (low, high) = halves(net, len);
+
+                self.stack.push(high);
+                self.stack.push(low);
+            }
+        }
+
+        None
+    }
+}
+
+/// Prefixes with the sources that listed them,
+/// a prefix kept per source being listed once per source.
+fn listed<I: Iterator<Item = (Ipv4Addr, u32, SourceSet)>>(
+    entries: I,
+) -> HashMap<(u32, u8), SourceSet> {
+    let mut map = HashMap::new();
+
+    for (addr, len, set) in entries {
+        *map.entry((u32::from(addr), len as u8)).or_insert(0) |= set;
+    }
+
+    map
+}
+
+fn mask(len: u8) -> u32 {
+    if len == 0 {
+        0
+    } else {
+        !0 << (32 - len)
+    }
+}
+
+fn halves(net: u32, len: u8) -> ((u32, u8), (u32, u8)) {
+    ((net, len + 1), (net | 1 << (31 - len), len + 1))
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(dead_code)]
+    fn export(deny: &[&str], allow: &[&str]) -> Vec<String> {
+        let parse = |s: &&str| {
+            let (addr, len) = s.split_once('/').unwrap();
+
+            (addr.parse().unwrap(), len.parse().unwrap(), 1)
+        };
+
+        Export::new(deny.iter().map(parse), allow.iter().map(parse), Vec::new())
+            .map(|c| c.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn merge_covered() {
+        assert_eq!(
+            export(&["10.0.0.0/8", "10.1.0.0/16", "10.0.0.0/9"], &[]),
+            ["10.0.0.0/8"]
+        );
+
+        assert_eq!(
+            export(&["10.0.0.0/9", "10.128.0.0/9", "192.168.0.0/16"], &[]),
+            ["10.0.0.0/8", "192.168.0.0/16"]
+        );
+    }
+
+    #[test]
+    fn subtract_allow() {
+        assert_eq!(
+            export(
+                &["10.0.0.0/8", "10.1.2.0/24", "192.168.0.0/16"],
+                &["10.1.0.0/16", "192.168.0.0/16", "172.16.0.0/12"]
+            ),
+            [
+                "10.0.0.0/16",
+                "10.1.2.0/24",
+                "10.2.0.0/15",
+                "10.4.0.0/14",
+                "10.8.0.0/13",
+                "10.16.0.0/12",
+                "10.32.0.0/11",
+                "10.64.0.0/10",
+                "10.128.0.0/9",
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_priority() {
+        let entry = |set| ("10.0.0.0".parse().unwrap(), 8, set);
+
+        let export = |priorities: Vec<i32>| {
+            Export::new(
+                std::iter::once(entry(1)),
+                std::iter::once(entry(2)),
+                priorities,
+            )
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+        };
+
+        // As specific, the deny of a higher priority wins, the allow winning ties.
+        assert_eq!(export(vec![1, 0]), ["10.0.0.0/8"]);
+        assert!(export(vec![0, 1]).is_empty());
+        assert!(export(vec![0, 0]).is_empty());
+    }
+}
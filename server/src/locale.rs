@@ -0,0 +1,105 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use crate::config::Locale;
+use crate::error::LrthromeError;
+
+/// Meta key peers request a language with.
+pub const LANG_META_KEY: &str = "lang";
+
+/// Configured locale variants of human facing messages.
+///
+/// Keyed by lowercase language tag, such as `de` or `pt-br`.
+#[derive(Default)]
+pub struct Locales(HashMap<String, Locale>);
+
+impl Locales {
+    pub fn new(locales: HashMap<String, Locale>) -> Self {
+        Self(
+            locales
+                .into_iter()
+                .map(|(lang, locale)| (lang.to_lowercase(), locale))
+                .collect(),
+        )
+    }
+
+    /// Banner for the language, falling back to the base banner.
+    pub fn banner<'a>(&'a self, lang: Option<&str>, base: &'a str) -> &'a str {
+        self.find(lang)
+            .and_then(|l| l.banner.as_deref())
+            .unwrap_or(base)
+    }
+
+    /// Error message for the language, falling back to the error's own message.
+    pub fn error(&self, lang: Option<&str>, error: &LrthromeError) -> String {
+        self.find(lang)
+            .and_then(|l| l.errors.get(&error.code().to_string()))
+            .cloned()
+            .unwrap_or_else(|| error.to_string())
+    }
+
+    /// Find the locale by exact tag, then by its primary subtag (`de-AT` to `de`).
+    fn find(&self, lang: Option<&str>) -> Option<&Locale> {
+        let lang = lang?.to_lowercase();
+
+        self.0.get(&lang).or_else(|| {
+            lang.split(['-', '_'])
+                .next()
+                .and_then(|primary| self.0.get(primary))
+        })
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn find_by_primary_subtag() {
+        use std::time::Duration;
+
+        let mut de = Locale {
+            banner: Some("Hallo".to_string()),
+            errors: HashMap::new(),
+        };
+
+        de.errors
+            .insert("1".to_string(), "Ratenlimit überschritten".to_string());
+
+        let mut locales = HashMap::new();
+        locales.insert("DE".to_string(), de);
+
+        let locales = Locales::new(locales);
+
+        assert_eq!(locales.banner(Some("de-AT"), "Hello"), "Hallo");
+        assert_eq!(locales.banner(Some("fr"), "Hello"), "Hello");
+        assert_eq!(locales.banner(None, "Hello"), "Hello");
+
+        assert_eq!(
+            locales.error(
+                Some("de"),
+                &LrthromeError::Ratelimited(Duration::from_secs(1))
+            ),
+            "Ratenlimit überschritten"
+        );
+        assert_eq!(
+            locales.error(Some("de"), &LrthromeError::MalformedPayload),
+            "Malformed payload"
+        );
+    }
+}
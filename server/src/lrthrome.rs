@@ -14,64 +14,129 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::hash_map::RandomState;
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{self, Runtime};
 use tokio::select;
-use tokio::sync::{mpsc, watch, RwLock};
-use tokio::time::{sleep, Duration};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{self, sleep, sleep_until, Duration};
 use tokio_stream::StreamExt;
-use tokio_util::codec::{BytesCodec, Decoder, Framed};
+use tokio_util::codec::{Decoder, Framed};
 
-use bytes::{Bytes, BytesMut};
+use arc_swap::ArcSwap;
 
-use ratelimit_meter::{KeyedRateLimiter, GCRA};
+use bytes::Bytes;
 
+use cidr::{Cidr, IpCidr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+use ratelimit_meter::{DirectRateLimiter, KeyedRateLimiter, NonConformance, GCRA};
+
+use futures::future;
 use futures::sink::SinkExt;
 
-use crate::error::LrthromeResult;
+use crate::admin::{self, Command, Kick, KickTarget, Tunable};
+use crate::auth::{Auth, SigningKey};
+use crate::ban::Bans;
+use crate::cidr_set::CidrSet;
+use crate::codec::{Frame, LrthromeCodec, MAX_FRAME_LEN};
+use crate::config::{Config, RatelimitAction};
+use crate::error::{FrameError, LrthromeResult};
+use crate::locale::{Locales, LANG_META_KEY};
+use crate::metrics::{self, Metrics};
+use crate::peer::{self, Egress, PeerId, Window};
 use crate::protocol::{
-    Established, Header, Request, ResponseError, ResponseOkFound, ResponseOkNotFound, Variant,
+    checksummed, complete, flag_checksum, Established, Header, Identify, Pong, Request, RequestAll,
+    RequestBatch, RequestCovered, RequestHost, RequestV6, ResponseAll, ResponseBatch,
+    ResponseCovered, ResponseError, ResponseHealth, ResponseHost, ResponseNotCovered,
+    ResponseOkFound, ResponseOkFoundV6, ResponseOkNotFound, ResponseOkNotFoundV6, ResponseStats,
+    Variant, CAPABILITY_CHECKSUM, CAPABILITY_IPV6, CIDR_META_KEY, IGNORE_ALLOW_META_KEY,
+    MATCHED_SOURCES_META_KEY, MAX_CSTRING_LEN, MAX_META_COUNT, SOURCES_META_KEY,
+    SUPPORTED_VERSIONS,
+};
+use crate::resolver::Resolver;
+use crate::sources::{SourceSet, Sources};
+use crate::{
+    cache::{age_since, Cache},
+    error::LrthromeError,
 };
-use crate::sources::Sources;
-use crate::{cache::Cache, error::LrthromeError};
 
 pub struct Lrthrome {
-    /// TCP listener bind for the lrthrome server.
-    listener: TcpListener,
+    /// TCP listeners bound for the lrthrome server, all feeding the same peers & cache.
+    listeners: Vec<TcpListener>,
 
     /// Shared data between peers and the server.
     ///
-    /// Only cache field maintain RwLock, as it's the only field mutable
+    /// Only cache field is swapped, as it's the only field mutable
     shared: Arc<Shared>,
 
-    /// Mapping of peer socket address to peer structure.
+    /// Mapping of peer identity to peer structure.
     ///
     /// The key is cleared as soon as peer disconnects.
     ///
     /// There could be multiple peers per IP address.
-    peers: HashMap<SocketAddr, PeerRegistry>,
+    peers: HashMap<PeerId, PeerRegistry>,
 
     /// Main event loop receiver.
     ///
     /// Operates on cache feedback & peer updates
-    rx: mpsc::UnboundedReceiver<Message>,
+    rx: mpsc::Receiver<Message>,
 
     /// Structure containing compile-time registered sources,
     /// with data populated at run-time from the config file.
     ///
     /// Temper will utilize the sources to refresh its cache.
-    sources: Sources,
+    ///
+    /// Shared with the temper runtime, if any.
+    sources: Arc<Sources>,
+
+    /// Runtime dedicated to tempering, so fetching, parsing & building the tree
+    /// does not compete with serving peers for the same threads.
+    ///
+    /// Tempers run on the serving runtime if absent.
+    temper_runtime: Option<TemperRuntime>,
 
     /// Cache time-to-live.
     ///
     /// The amount of time between temperance.
     cache_ttl: u32,
 
+    /// Cache refresh jitter, as a percentage of `cache_ttl`.
+    ///
+    /// Desynchronizes instances started together from hitting sources at once.
+    cache_jitter: u8,
+
+    /// File the tree is persisted to, and loaded from upon start.
+    ///
+    /// Only held in memory if absent.
+    cache_file: Option<PathBuf>,
+
+    /// Latest write of the tree, awaited by the next so writes land in order.
+    persisting: Option<JoinHandle<()>>,
+
+    /// Percentage of the current tree's entries a tempered tree must have to be swapped in.
+    ///
+    /// Guards against a source returning a partial or empty list, 0 swaps regardless.
+    min_tree_percent: u8,
+
+    /// Swap in the next tempered tree regardless of `min_tree_percent`, as forced by an operator.
+    force_admit: bool,
+
     /// Peer time-to-live.
     ///
     /// The amount of time a peer is allowed to keep their connection open
@@ -80,7 +145,7 @@ pub struct Lrthrome {
 
     /// Ratelimiter for individual IP address.
     ///
-    /// Note that the key is `IpAddr` rather than SocketAddr.
+    /// Note that the key is the peer's `PeerId::ratelimit_key` rather than its identity.
     /// As the ratelimit applies globally to a single address,
     /// shared between the IP address's connections.
     ratelimiter: KeyedRateLimiter<IpAddr, GCRA>,
@@ -90,8 +155,132 @@ pub struct Lrthrome {
     /// Peer that exceeds this will be force disconnected.
     rate_limit: NonZeroU32,
 
+    /// Span rate limits apply over, for addresses & identities alike.
+    rate_limit_window: Duration,
+
     /// Banner message sent to clients upon established.
     banner: String,
+
+    /// Omit the banner field from `Established`, changing its layout.
+    omit_banner: bool,
+
+    /// Server-side hostname resolver.
+    ///
+    /// Hostname requests are refused if absent.
+    resolver: Option<Arc<Resolver>>,
+
+    /// Most peers connected at once, 0 if unlimited.
+    max_connections: u32,
+
+    /// Most peers connected at once from a single IP address, 0 if unlimited.
+    max_connections_per_ip: u32,
+
+    /// `SO_LINGER` duration applied to peer sockets.
+    ///
+    /// Bounds how long closing a socket blocks to flush unsent bytes.
+    /// Operating system default if absent.
+    linger: Option<Duration>,
+
+    /// Bytes per second & burst responses are written out to each peer at.
+    ///
+    /// Unlimited if absent.
+    egress: Option<(u32, u32)>,
+
+    /// Number of in-flight frames & responses a peer may have before it is no longer read from.
+    max_in_flight: usize,
+
+    /// Number of responses queued to a peer before it is disconnected for not reading them,
+    /// never fewer than `max_in_flight`.
+    max_queued_responses: usize,
+
+    /// Longest string accepted within peer frames.
+    max_cstring_len: usize,
+
+    /// Longest frame accepted from peers, header included.
+    max_frame_len: usize,
+
+    /// Most meta pairs accepted within a request.
+    max_meta_count: u8,
+
+    /// Most addresses accepted within a batch request.
+    max_batch: usize,
+
+    /// Locale variants of the banner & error messages.
+    locales: Locales,
+
+    /// Defer `Established` until the peer's first frame,
+    /// so the banner can be localized to the language it requests.
+    defer_established: bool,
+
+    /// Whether peers may opt in to checksummed frames, advertised in `Established`.
+    frame_checksums: bool,
+
+    /// Number of requests a new peer may make before rate limits are enforced.
+    rate_limit_grace: u32,
+
+    /// Whether a peer exceeding its rate limit is disconnected, or only has the request rejected.
+    ratelimit_action: RatelimitAction,
+
+    /// Addresses of peers never rate limited, checked once upon connection.
+    ratelimit_exempt: CidrSet,
+
+    /// Identify tokens.
+    ///
+    /// Identify frames are ignored if absent.
+    auth: Option<Auth>,
+
+    /// Rate limit meter per identity, in place of the IP address based meter.
+    identity_limiters: HashMap<String, DirectRateLimiter<GCRA>>,
+
+    /// Operator command listener.
+    ///
+    /// Taken upon start, admin commands are not accepted if absent.
+    admin: Option<TcpListener>,
+
+    /// Metrics listener for scrapers.
+    ///
+    /// Taken upon start, metrics are only rendered through admin commands if absent.
+    metrics_listener: Option<TcpListener>,
+
+    /// Admin command sender, cloned to the admin listener.
+    tx_admin: mpsc::UnboundedSender<Command>,
+
+    /// Admin command receiver.
+    rx_admin: mpsc::UnboundedReceiver<Command>,
+
+    /// Networks banned by an operator, refused at accept.
+    bans: Bans,
+
+    /// Duration addresses banned with `ban-ip` are banned for.
+    ban_duration: Duration,
+
+    /// Rate limit violations within `ban_window` an address is banned upon, 0 if never.
+    ban_threshold: u32,
+
+    /// Span violations are counted over.
+    ban_window: Duration,
+
+    /// Duration addresses reaching `ban_threshold` are banned for.
+    violation_ban_duration: Duration,
+
+    /// Rate limit violations per address, with the instant the first within the window occurred.
+    violations: HashMap<IpAddr, (Instant, u32)>,
+
+    /// Duration peers are given to disconnect upon shutdown.
+    shutdown_grace: Duration,
+
+    /// Config re-read upon SIGHUP, never if absent.
+    config_path: Option<PathBuf>,
+
+    /// Addresses the listeners were bound to as configured,
+    /// a change of which upon reload requires a restart.
+    bind_address: Vec<String>,
+
+    /// Background cache & peer timers, aborted upon being re-armed.
+    timers: Vec<JoinHandle<()>>,
+
+    /// Instant the server was created, reported as uptime to health probes.
+    started: Instant,
 }
 
 /// Enum of message variants & data,
@@ -103,26 +292,84 @@ enum Message {
     /// Upon repeating timer of `peer_ttl`.
     PeerTick,
 
-    PeerFrame(SocketAddr, BytesMut),
+    /// Upon a peer frame, or a protocol error in place of one.
+    PeerFrame(PeerId, LrthromeResult<Frame>),
 
     /// Upon peer disconnect or force disconnect.
-    PeerDisconnected(SocketAddr),
+    PeerDisconnected(PeerId),
+
+    /// Upon a temper spawned off the event loop completing.
+    Tempered(Box<Tempered>),
+}
+
+/// Outcome of a temper, along with the sources it tempered.
+struct Tempered {
+    result: LrthromeResult<Option<Cache>>,
+
+    /// Compared against the current sources, a reload during the temper making it stale.
+    sources: Arc<Sources>,
+
+    started: Instant,
 }
 
 /// Data structures that's shared between peers and the server.
 ///
-/// Only cache retains RwLock for mutability
+/// Only cache is swapped for mutability
 struct Shared {
     /// IPv4 Radix cache tree.
     ///
-    /// Will be write-locked when tempered
-    cache: RwLock<Cache>,
+    /// Readers take a snapshot without locking, so neither each other nor a swap contend.
+    /// Tempering builds a new tree aside, then swaps it in,
+    /// so lookups are never blocked by a temper in progress.
+    cache: ArcSwap<Cache>,
+
+    /// Incremented upon every completed temper, invalidating peers' last match.
+    generation: AtomicU64,
 
     /// Main event loop sender.
     ///
     /// This will be cloned to peers.
     /// Used by peers to send message back to main thread.
-    tx: mpsc::UnboundedSender<Message>,
+    ///
+    /// Bounded by `EVENT_QUEUE_LEN`, peers wait to be read from while the main loop is behind.
+    tx: mpsc::Sender<Message>,
+
+    /// Notified once a timer triggered temper completes.
+    ///
+    /// The cache timer waits on this before scheduling its next tick.
+    tempered: Notify,
+
+    /// Whether a temper is currently in progress.
+    ///
+    /// Ticks arriving while set are skipped rather than queued.
+    tempering: AtomicBool,
+
+    /// Operational metrics, shared with sources.
+    metrics: Arc<Metrics>,
+}
+
+/// Runtime tempers are spawned onto.
+///
+/// Shut down in the background when dropped, as a runtime may not be dropped
+/// from within the serving runtime's async context.
+struct TemperRuntime(Option<Runtime>);
+
+impl TemperRuntime {
+    fn spawn<F>(&self, future: F) -> tokio::task::JoinHandle<F::Output>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.0.as_ref().unwrap().spawn(future)
+    }
+}
+
+impl Drop for TemperRuntime {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
 }
 
 struct PeerRegistry {
@@ -139,16 +386,77 @@ struct PeerRegistry {
     /// Peer sending channel.
     ///
     /// For main thread to pass information back to the `Peer`
-    tx_bytes: mpsc::UnboundedSender<Bytes>,
+    ///
+    /// Bounded by `max_queued_responses`, the peer being disconnected once full.
+    tx_bytes: mpsc::Sender<Bytes>,
+
+    /// Whether `Established` has been sent to the peer.
+    ///
+    /// Only unset while deferred until the peer's first frame.
+    established: bool,
+
+    /// Protocol version negotiated with the peer, that of its first frame.
+    ///
+    /// Any later frame of another version is refused.
+    version: Option<u8>,
+
+    /// Whether responses are checksummed, once the peer sent a checksummed frame.
+    checksum: bool,
+
+    /// Whether every frame of the peer was a health probe, unknown until its first frame.
+    ///
+    /// Such peers, as load balancer checks, are not swept upon `peer_ttl`.
+    health_only: Option<bool>,
+
+    /// Language requested by the peer through request meta.
+    lang: Option<String>,
+
+    /// Name of the identity the peer authenticated as.
+    identity: Option<String>,
+
+    /// Key of the identity responses are signed with.
+    signing_key: Option<SigningKey>,
+
+    /// In-flight window shared with the `Peer`.
+    window: Arc<Window>,
+
+    /// Remaining requests that bypass the rate limit,
+    /// allowing a burst right after connecting.
+    grace: u32,
+
+    /// Whether the peer's address is exempt from rate limits.
+    exempt: bool,
+
+    /// Result of the peer's last lookup, reused for a peer polling the same address.
+    last_match: Option<LastMatch>,
+}
+
+/// Result of a lookup, valid while the tree's generation is unchanged.
+#[derive(Clone, Copy)]
+struct LastMatch {
+    generation: u64,
+
+    ip_address: Ipv4Addr,
+
+    ignore_allow: bool,
+
+    sources: Option<SourceSet>,
+
+    longest_match: Option<(Ipv4Addr, u32)>,
+
+    shadow_match: Option<Option<(Ipv4Addr, u32)>>,
+
+    /// Of the tree looked up, for the data age to be derived without it.
+    tempered_at: u64,
 }
 
 struct Peer {
-    /// Socket address identifier.
-    addr: SocketAddr,
+    /// Peer identifier.
+    id: PeerId,
 
     /// Wrap the TcpStream around bytes allows chunked based level operation
     /// rather than raw bytes.
-    frame: Framed<TcpStream, BytesCodec>,
+    frame: Framed<TcpStream, LrthromeCodec>,
 
     /// Peer shutdown receiver channel.
     ///
@@ -158,30 +466,89 @@ struct Peer {
     /// Peer receiving channel.
     ///
     /// This is used to receive bytes to write to `Peer`'s socket
-    rx_bytes: mpsc::UnboundedReceiver<Bytes>,
+    rx_bytes: mpsc::Receiver<Bytes>,
+
+    /// In-flight window, frames are not read while full.
+    window: Arc<Window>,
+
+    /// Throttle of bytes written out, unlimited if absent.
+    egress: Option<Egress>,
 }
 
 impl Lrthrome {
-    pub async fn new<A>(addr: A, sources: Sources, rate_limit: NonZeroU32) -> LrthromeResult<Self>
-    where
-        A: ToSocketAddrs,
-    {
-        let (tx, rx) = mpsc::unbounded_channel();
+    pub fn new(
+        listeners: Vec<TcpListener>,
+        sources: Sources,
+        rate_limit: NonZeroU32,
+        metrics: Arc<Metrics>,
+    ) -> LrthromeResult<Self> {
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_LEN);
+        let (tx_admin, rx_admin) = mpsc::unbounded_channel();
 
         Ok(Self {
-            listener: TcpListener::bind(addr).await?,
-            shared: Arc::new(Shared::new(tx)),
+            listeners,
+            shared: Arc::new(Shared::new(tx, metrics)),
             peers: HashMap::new(),
 
             // Default cache time-to-live to 24 hours.
             cache_ttl: 86400,
+            cache_jitter: 0,
+            cache_file: None,
+            persisting: None,
+
+            // Default to refusing trees less than half the current one.
+            min_tree_percent: 50,
+            force_admit: false,
 
             // Default peer time-to-live to 15 seconds.
             peer_ttl: 15,
             ratelimiter: KeyedRateLimiter::new(rate_limit, Duration::from_secs(5)),
+
+            // Default rate limit window to 5 seconds.
+            rate_limit_window: Duration::from_secs(5),
             banner: "".to_string(),
+            omit_banner: false,
+            resolver: None,
+            max_connections: 0,
+            max_connections_per_ip: 0,
+            linger: None,
+            egress: None,
+            max_in_flight: 64,
+            max_queued_responses: 256,
+            max_cstring_len: MAX_CSTRING_LEN,
+            max_frame_len: MAX_FRAME_LEN,
+            max_meta_count: MAX_META_COUNT,
+            max_batch: 256,
+            locales: Locales::default(),
+            defer_established: false,
+            frame_checksums: false,
+            rate_limit_grace: 0,
+            ratelimit_action: RatelimitAction::Disconnect,
+            ratelimit_exempt: CidrSet::default(),
+            auth: None,
+            identity_limiters: HashMap::new(),
+            admin: None,
+            metrics_listener: None,
+            tx_admin,
+            rx_admin,
+            bans: Bans::default(),
+
+            // Default ban duration to 1 hour.
+            ban_duration: Duration::from_secs(3600),
+            ban_threshold: 0,
+            ban_window: Duration::from_secs(60),
+            violation_ban_duration: Duration::from_secs(600),
+            violations: HashMap::new(),
+
+            // Default shutdown grace to 5 seconds.
+            shutdown_grace: Duration::from_secs(5),
+            config_path: None,
+            bind_address: Vec::new(),
+            timers: Vec::new(),
+            started: Instant::now(),
             rate_limit,
-            sources,
+            sources: Arc::new(sources),
+            temper_runtime: None,
             rx,
         })
     }
@@ -192,6 +559,24 @@ impl Lrthrome {
         self
     }
 
+    pub fn cache_jitter(&mut self, percent: u8) -> &mut Self {
+        self.cache_jitter = percent.min(100);
+
+        self
+    }
+
+    pub fn cache_persist_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.cache_file = Some(path.into());
+
+        self
+    }
+
+    pub fn min_tree_percent(&mut self, percent: u8) -> &mut Self {
+        self.min_tree_percent = percent.min(100);
+
+        self
+    }
+
     pub fn peer_ttl(&mut self, dur: u32) -> &mut Self {
         self.peer_ttl = dur;
 
@@ -204,288 +589,2987 @@ impl Lrthrome {
         self
     }
 
-    /// Start the main event loop.
-    ///
-    /// Handles the connections as well as `Lrthrome`.rx events.
-    pub async fn up(&mut self) -> LrthromeResult<()> {
-        self.start_timers();
-        self.temper_cache().await?;
-
-        info!("Started processing connections");
+    pub fn omit_banner(&mut self, omit: bool) -> &mut Self {
+        self.omit_banner = omit;
 
-        loop {
-            select! {
-                _ = tokio::signal::ctrl_c() => {
-                    // Exit to main
-                    return Ok(());
-                }
-                Ok((stream, addr)) = self.listener.accept() => {
-                    let (tx_shutdown, rx_shutdown) = watch::channel(false);
-                    let (tx_bytes, rx_bytes) = mpsc::unbounded_channel();
+        self
+    }
 
-                    debug!("Peer has connected (addr = {})", addr);
+    pub fn resolver(&mut self, resolver: Resolver) -> &mut Self {
+        self.resolver = Some(Arc::new(resolver));
 
-                    let mut peer = PeerRegistry::new(tx_shutdown, tx_bytes);
+        self
+    }
 
-                    let tree_size = {
-                        let c = self.shared.cache.read().await;
+    pub fn linger(&mut self, dur: Duration) -> &mut Self {
+        self.linger = Some(dur);
 
-                        c.len()
-                    };
+        self
+    }
 
-                    let payload = Established {
-                        rate_limit: self.rate_limit.into(),
-                        tree_size: tree_size as u32,
-                        cache_ttl: self.cache_ttl,
-                        peer_ttl: self.peer_ttl,
-                        banner: &self.banner,
-                    }.to_bytes();
+    pub fn egress(&mut self, rate: NonZeroU32, burst: u32) -> &mut Self {
+        self.egress = Some((rate.get(), burst));
 
-                    Self::peer_send(&addr, &mut peer, payload);
+        self
+    }
 
-                    self.peers.insert(addr, peer);
-                    self.process_peer(Peer::new(addr, stream, rx_shutdown, rx_bytes));
-                }
-                Some(message) = self.rx.recv() => {
-                    match message {
-                        Message::CacheTick => self.temper_cache().await?,
-                        Message::PeerTick => self.sweep_peers()?,
-                        Message::PeerFrame(addr, buf) => {
-                            debug!("Received peer frame (addr = {}) (length = {})", addr, buf.len());
+    /// Temper on a dedicated runtime of the number of threads.
+    pub fn temper_threads(&mut self, threads: usize) -> LrthromeResult<&mut Self> {
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(threads.max(1))
+            .thread_name("lrthrome-temper")
+            .enable_all()
+            .build()?;
 
-                            if let Err(e) = self.process_frame(addr, buf.as_ref()).await {
-                                if let Some(peer) = self.peers.get_mut(&addr) {
-                                    Self::peer_error(&addr, peer, e);
-                                    self.cleanup();
-                                }
-                            }
-                        },
-                        Message::PeerDisconnected(addr) => {
-                            debug!("Peer has disconnected (addr = {})", addr);
+        self.temper_runtime = Some(TemperRuntime(Some(runtime)));
 
-                            self.peers.remove(&addr);
-                        }
-                    }
-                }
-            }
-        }
+        Ok(self)
     }
 
-    #[inline]
-    async fn process_frame(&mut self, addr: SocketAddr, frame: &[u8]) -> LrthromeResult<()> {
-        let (frame, header) = Header::parse(frame).map_err(|_| LrthromeError::MalformedPayload)?;
+    pub fn max_in_flight(&mut self, max: usize) -> &mut Self {
+        self.max_in_flight = max.max(1);
 
-        debug!(
-            "Received peer frame (type = {}) (addr = {})",
-            header.variant.to_string(),
-            addr
-        );
+        self
+    }
 
-        match header.variant {
-            Variant::Identify => {
-                // Unused ATM
-                // let (_, identify) = Identify::parse(frame).map_err(|_| LrthromeError::MalformedPayload)?;
-            }
-            Variant::Request => {
-                let (_, request) =
-                    Request::parse(frame).map_err(|_| LrthromeError::MalformedPayload)?;
+    pub fn max_queued_responses(&mut self, max: usize) -> &mut Self {
+        self.max_queued_responses = max.max(1);
 
-                if let Some(peer) = self.peers.get_mut(&addr) {
-                    if self.ratelimiter.check(addr.ip()).is_err() {
-                        warn!("Peer exceeded ratelimit (addr = {})", addr);
+        self
+    }
 
-                        return Err(LrthromeError::Ratelimited);
-                    }
+    pub fn max_cstring_len(&mut self, max: usize) -> &mut Self {
+        self.max_cstring_len = max;
 
-                    peer.last_request = Instant::now();
+        self
+    }
 
-                    let longest_match = {
-                        let c = self.shared.cache.read().await;
+    pub fn max_frame_bytes(&mut self, max: usize) -> &mut Self {
+        self.max_frame_len = max;
 
-                        c.longest_match(request.ip_address)
+        self
+    }
 
-                        // Read guard dropped here
-                    };
+    pub fn max_meta_count(&mut self, max: u8) -> &mut Self {
+        self.max_meta_count = max;
 
-                    let resp = match longest_match {
-                        Some(m) => {
-                            info!(
-                                "{} found in range of {}/{} ({:?}) (addr = {})",
-                                request.ip_address, m.0, m.1, request.meta, addr,
-                            );
-
-                            ResponseOkFound {
-                                ip_address: request.ip_address,
-                                prefix: m.0,
-                                mask_len: m.1,
-                            }
-                        }
-                        .to_bytes(),
-                        None => ResponseOkNotFound {
-                            ip_address: request.ip_address,
-                        }
-                        .to_bytes(),
-                    };
+        self
+    }
 
-                    Self::peer_send(&addr, peer, resp);
-                }
-            }
-            _ => (),
-        }
+    pub fn max_batch(&mut self, max: usize) -> &mut Self {
+        self.max_batch = max;
 
-        Ok(())
+        self
     }
 
-    fn peer_error(addr: &SocketAddr, peer: &mut PeerRegistry, error: LrthromeError) {
-        let resp = ResponseError {
-            code: error.code(),
-            message: &error.to_string(),
-        }
-        .to_bytes();
+    pub fn locales(&mut self, locales: Locales) -> &mut Self {
+        self.locales = locales;
 
-        Self::peer_send(&addr, peer, resp);
-        Self::shutdown_peer(peer, &addr);
+        self
     }
 
-    fn peer_send(addr: &SocketAddr, peer: &mut PeerRegistry, payload: Bytes) {
-        if let Err(e) = peer.tx_bytes.send(payload) {
-            error!("Unable to send payload to peer (addr = {}): {}", addr, e);
-        }
+    pub fn defer_established(&mut self, defer: bool) -> &mut Self {
+        self.defer_established = defer;
+
+        self
     }
 
-    fn shutdown_peer(peer: &mut PeerRegistry, addr: &SocketAddr) {
-        if let Err(e) = peer.tx_shutdown.send(true) {
-            error!("Unable to shutdown peer (addr = {}): {}", addr, e);
-        }
+    pub fn frame_checksums(&mut self, enabled: bool) -> &mut Self {
+        self.frame_checksums = enabled;
+
+        self
     }
 
-    fn cleanup(&mut self) {
-        self.ratelimiter.cleanup(Duration::from_secs(60));
+    /// Span rate limits apply over, resetting every meter.
+    pub fn rate_limit_window(&mut self, dur: Duration) -> &mut Self {
+        self.rate_limit_window = dur;
+        self.ratelimiter = KeyedRateLimiter::new(self.rate_limit, dur);
+        self.rebuild_identity_limiters();
+
+        self
     }
 
-    async fn temper_cache(&mut self) -> LrthromeResult<()> {
-        let mut c = self.shared.cache.write().await;
+    pub fn max_connections(&mut self, max: u32) -> &mut Self {
+        self.max_connections = max;
 
-        c.temper(&self.sources).await?;
+        self
+    }
 
-        Ok(())
+    pub fn max_connections_per_ip(&mut self, max: u32) -> &mut Self {
+        self.max_connections_per_ip = max;
+
+        self
     }
 
-    fn sweep_peers(&mut self) -> LrthromeResult<()> {
-        for c in self.peers.values() {
-            if c.last_request.elapsed() > Duration::from_secs(self.peer_ttl as u64) {
-                c.tx_shutdown.send(true)?;
-            }
-        }
+    pub fn shutdown_grace(&mut self, dur: Duration) -> &mut Self {
+        self.shutdown_grace = dur;
 
-        Ok(())
+        self
     }
 
-    fn process_peer(&mut self, peer: Peer) {
-        let shared = self.shared.clone();
+    pub fn config_path<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.config_path = Some(path.into());
 
-        let mut peer = peer;
-        tokio::spawn(async move {
-            loop {
-                select! {
-                    _ = peer.rx_shutdown.changed() => {
-                        break;
-                    }
-                    Some(bytes) = peer.rx_bytes.recv() => {
-                        if let Err(e) = peer.frame.send(bytes).await {
-                            error!("Unable to send bytes to {}: {}", peer.addr, e);
-                        }
-                    }
-                    frame = peer.frame.next() => {
-                        match frame {
-                            Some(message) => {
-                                match message {
-                                    Ok(buf) => {
-                                        let _ = shared.tx.send(Message::PeerFrame(peer.addr, buf));
-                                    },
-                                    Err(_) => {
-                                        break;
-                                    }
-                                }
-                            },
-                            None => {
-                                break;
-                            }
-                        }
-                    }
-                }
-            }
+        self
+    }
 
-            // Peer has no more frames, declare disconnect.
-            let _ = shared.tx.send(Message::PeerDisconnected(peer.addr));
+    pub fn bind_address(&mut self, addresses: Vec<String>) -> &mut Self {
+        self.bind_address = addresses;
 
-            // Exiting this future will drop peer, dropping the connection
-        });
+        self
     }
 
-    /// Starts background timers.
-    ///
-    /// Peer & Cache TTL timers will initialize here.
-    fn start_timers(&mut self) {
-        let shared = self.shared.clone();
-        let cache_ttl = Duration::from_secs(self.cache_ttl as u64);
+    pub fn rate_limit_grace(&mut self, requests: u32) -> &mut Self {
+        self.rate_limit_grace = requests;
 
-        tokio::spawn(async move {
-            loop {
-                sleep(cache_ttl).await;
+        self
+    }
 
-                if let Err(e) = shared.tx.send(Message::CacheTick) {
-                    error!("Unable to send cache tick: {0}", e);
-                }
-            }
-        });
+    pub fn ratelimit_action(&mut self, action: RatelimitAction) -> &mut Self {
+        self.ratelimit_action = action;
 
-        let shared = self.shared.clone();
-        let peer_ttl = Duration::from_secs(self.peer_ttl as u64);
+        self
+    }
 
-        tokio::spawn(async move {
-            loop {
-                sleep(peer_ttl).await;
+    pub fn ratelimit_exempt(&mut self, exempt: CidrSet) -> &mut Self {
+        self.ratelimit_exempt = exempt;
 
-                if let Err(e) = shared.tx.send(Message::PeerTick) {
-                    error!("Unable to send cache tick: {0}", e);
+        self
+    }
+
+    pub fn auth(&mut self, auth: Auth) -> &mut Self {
+        self.auth = Some(auth);
+        self.rebuild_identity_limiters();
+
+        self
+    }
+
+    pub fn admin(&mut self, listener: TcpListener) -> &mut Self {
+        self.admin = Some(listener);
+
+        self
+    }
+
+    pub fn metrics_listener(&mut self, listener: TcpListener) -> &mut Self {
+        self.metrics_listener = Some(listener);
+
+        self
+    }
+
+    pub fn bans(&mut self, bans: Bans) -> &mut Self {
+        self.bans = bans;
+
+        self
+    }
+
+    /// Duration addresses banned with `ban-ip` are refused for.
+    pub fn ban_duration(&mut self, dur: Duration) -> &mut Self {
+        self.ban_duration = dur;
+
+        self
+    }
+
+    /// Ban addresses for `dur` upon `threshold` rate limit violations within `window`.
+    pub fn ban_threshold(&mut self, threshold: u32, window: Duration, dur: Duration) -> &mut Self {
+        self.ban_threshold = threshold;
+        self.ban_window = window;
+        self.violation_ban_duration = dur;
+
+        self
+    }
+
+    /// Whether the tree was ever successfully tempered.
+    pub fn tempered(&self) -> bool {
+        self.shared.snapshot().tempered()
+    }
+
+    /// Start the main event loop.
+    ///
+    /// Handles the connections as well as `Lrthrome`.rx events.
+    pub async fn up(&mut self) -> LrthromeResult<()> {
+        self.start_timers();
+        self.load_cache();
+        self.temper_cache().await?;
+
+        if let Some(listener) = self.admin.take() {
+            tokio::spawn(admin::serve(listener, self.tx_admin.clone()));
+        }
+
+        if let Some(listener) = self.metrics_listener.take() {
+            tokio::spawn(metrics::serve(listener, self.shared.metrics.clone()));
+        }
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        let mut expire_bans = time::interval(BAN_EXPIRY_INTERVAL);
+
+        info!("Started processing connections");
+
+        loop {
+            select! {
+                _ = tokio::signal::ctrl_c() => break,
+                Some(_) = terminate.recv() => break,
+                Some(_) = hangup.recv() => {
+                    self.reload_config();
+                    self.reload_auth();
+
+                    // Out of band, the periodic timer keeps its schedule.
+                    info!("Manual refresh triggered by SIGHUP");
+
+                    self.spawn_temper();
+                }
+                _ = expire_bans.tick() => {
+                    self.bans.expire();
+
+                    // Swept here rather than with peers, which are never swept without a peer TTL.
+                    let window = self.ban_window;
+
+                    self.violations.retain(|_, (first, _)| first.elapsed() <= window);
+                }
+                Ok((stream, addr)) = accept(&self.listeners) => {
+                    let (tx_shutdown, rx_shutdown) = watch::channel(false);
+                    let (tx_bytes, rx_bytes) =
+                        mpsc::channel(self.max_queued_responses.max(self.max_in_flight));
+
+                    let id = PeerId::new(addr);
+
+                    // Closed upon drop, before the handshake.
+                    if self.bans.contains(id.connection_key()) {
+                        debug!("Refused banned peer (addr = {})", id);
+
+                        continue;
+                    }
+
+                    if let Some((limit, max)) = self.connection_limit(&id) {
+                        warn!("Refused peer over the {} connection limit of {} (addr = {})", limit, max, id);
+
+                        self.shared.metrics.connections_refused.get(limit).inc();
+                        self.refuse_peer(stream, LrthromeError::TooManyConnections(max));
+
+                        continue;
+                    }
+
+                    debug!("Peer has connected (addr = {})", id);
+
+                    if let Some(linger) = self.linger {
+                        if let Err(e) = stream.set_linger(Some(linger)) {
+                            warn!("Unable to set linger on peer socket (addr = {}): {}", id, e);
+                        }
+                    }
+
+                    let window = Arc::new(Window::new(self.max_in_flight));
+                    let mut peer = PeerRegistry::new(
+                        tx_shutdown,
+                        tx_bytes,
+                        window.clone(),
+                        self.rate_limit_grace,
+                    );
+
+                    peer.exempt = self.ratelimit_exempt.contains(id.ratelimit_key());
+
+                    if !self.defer_established {
+                        let payload = self.established(None).await;
+
+                        Self::peer_send(&id, &mut peer, payload);
+
+                        peer.established = true;
+                    }
+
+                    self.peers.insert(id, peer);
+                    self.shared.metrics.peers.set(self.peers.len() as u64);
+                    self.process_peer(Peer::new(
+                        id,
+                        stream,
+                        LrthromeCodec::new(
+                            self.max_cstring_len,
+                            self.max_frame_len,
+                            self.frame_checksums,
+                        ),
+                        rx_shutdown,
+                        rx_bytes,
+                        window,
+                        self.egress.map(|(rate, burst)| Egress::new(rate, burst)),
+                    ));
+                }
+                Some(message) = self.rx.recv() => {
+                    match message {
+                        Message::CacheTick => self.spawn_temper(),
+                        Message::Tempered(tempered) => {
+                            self.finish_temper(*tempered)?;
+                            self.shared.tempered.notify_one();
+                        }
+                        Message::PeerTick => self.sweep_peers()?,
+                        Message::PeerFrame(id, frame) => {
+                            let result = match frame {
+                                Ok(frame) => self.process_frame(id, frame).await,
+                                Err(e) => {
+                                    warn!("Peer sent an undecodable frame (addr = {}): {}", id, e);
+
+                                    Err(e.into())
+                                }
+                            };
+
+                            if let Err(e) = result {
+                                let ratelimited = matches!(e.error, LrthromeError::Ratelimited(_));
+
+                                if ratelimited {
+                                    self.shared.metrics.ratelimited.inc();
+                                    self.record_violation(&id);
+                                }
+
+                                if let Some(peer) = self.peers.get_mut(&id) {
+                                    if ratelimited && self.ratelimit_action == RatelimitAction::Reject {
+                                        Self::peer_reject(&id, peer, e.error, e.ip_address, &self.locales);
+                                    } else {
+                                        Self::peer_error(&id, peer, e.error, e.ip_address, &self.locales);
+                                        self.cleanup();
+                                    }
+                                }
+                            }
+
+                            if let Some(peer) = self.peers.get(&id) {
+                                peer.window.release();
+                            }
+                        },
+                        Message::PeerDisconnected(id) => {
+                            debug!("Peer has disconnected (addr = {})", id);
+
+                            self.peers.remove(&id);
+                            self.shared.metrics.peers.set(self.peers.len() as u64);
+                        }
+                    }
+                }
+                Some(command) = self.rx_admin.recv() => self.process_admin(command).await,
+            }
+        }
+
+        self.shutdown().await;
+
+        self.persist_cache();
+
+        if let Some(persisting) = self.persisting.take() {
+            let _ = persisting.await;
+        }
+
+        self.bans.flush().await;
+
+        // Exit to main
+        Ok(())
+    }
+
+    /// Tell every peer the server is shutting down,
+    /// and wait for them to disconnect for at most the shutdown grace.
+    ///
+    /// Connections are no longer accepted, as the listener is no longer polled.
+    async fn shutdown(&mut self) {
+        info!("Shutting down (peers = {})", self.peers.len());
+
+        for (id, peer) in self.peers.iter_mut() {
+            Self::peer_error(id, peer, LrthromeError::ShuttingDown, None, &self.locales);
+        }
+
+        let grace = sleep(self.shutdown_grace);
+
+        tokio::pin!(grace);
+
+        while !self.peers.is_empty() {
+            select! {
+                _ = &mut grace => {
+                    warn!(
+                        "Peers did not disconnect within the shutdown grace, dropping (peers = {})",
+                        self.peers.len()
+                    );
+
+                    return;
+                }
+                Some(message) = self.rx.recv() => {
+                    // Frames still in flight are no longer answered.
+                    if let Message::PeerDisconnected(id) = message {
+                        self.peers.remove(&id);
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    async fn process_frame(&mut self, id: PeerId, frame: Frame) -> Result<(), FrameError> {
+        let Frame { header, payload } = frame;
+        let frame = payload.as_ref();
+
+        debug!(
+            "Received peer frame (type = {}) (addr = {}) (length = {})",
+            header.variant.to_string(),
+            id,
+            frame.len()
+        );
+
+        if let Some(peer) = self.peers.get_mut(&id) {
+            let received = header.protocol_version.get();
+
+            match peer.version {
+                None => {
+                    debug!("Negotiated protocol version {} (addr = {})", received, id);
+
+                    peer.version = Some(received);
+                }
+                Some(version) if version != received => {
+                    return Err(LrthromeError::VersionMismatch {
+                        minimum: version,
+                        preferred: version,
+                        received,
+                    }
+                    .into());
+                }
+                Some(_) => {}
+            }
+
+            peer.checksum |= header.checksum;
+            peer.health_only =
+                Some(peer.health_only.unwrap_or(true) && header.variant == Variant::Health);
+        }
+
+        if self.peers.get(&id).is_some_and(|p| !p.established) {
+            // Only a request carries meta to localize with.
+            let lang = match header.variant {
+                Variant::Request => {
+                    Request::parse(frame, self.max_cstring_len, self.max_meta_count)
+                        .ok()
+                        .and_then(|(_, r)| r.meta.get(LANG_META_KEY).map(|l| l.to_string()))
+                }
+                Variant::RequestV6 => {
+                    RequestV6::parse(frame, self.max_cstring_len, self.max_meta_count)
+                        .ok()
+                        .and_then(|(_, r)| r.meta.get(LANG_META_KEY).map(|l| l.to_string()))
+                }
+                _ => None,
+            };
+
+            self.establish(id, lang).await;
+        }
+
+        let started = Instant::now();
+
+        let result = self.process_variant(id, &header, frame).await;
+
+        self.shared
+            .metrics
+            .frame_duration
+            .get(&header.variant.to_string())
+            .observe(started.elapsed());
+
+        result
+    }
+
+    async fn process_variant(
+        &mut self,
+        id: PeerId,
+        header: &Header,
+        frame: &[u8],
+    ) -> Result<(), FrameError> {
+        // Branch on `header.protocol_version` wherever message shapes differ between versions.
+        match &header.variant {
+            Variant::Identify => {
+                let identify = complete(Identify::parse(frame, self.max_cstring_len))?;
+
+                // Identification is optional, and is ignored unless configured.
+                if let Some(auth) = &self.auth {
+                    let identity = auth
+                        .identify(identify.identification)
+                        .ok_or(LrthromeError::Unauthorized)?;
+
+                    info!("Peer identified as {} (addr = {})", identity.name, id);
+
+                    if let Some(peer) = self.peers.get_mut(&id) {
+                        peer.identity = Some(identity.name.clone());
+                        peer.signing_key = identity.signing_key.clone();
+                    }
+                }
+            }
+            Variant::Request => {
+                let request = complete(Request::parse(
+                    frame,
+                    self.max_cstring_len,
+                    self.max_meta_count,
+                ))?;
+
+                self.process_request(id, IpAddr::V4(request.ip_address), &request.meta)?;
+            }
+            Variant::RequestV6 => {
+                let request = complete(RequestV6::parse(
+                    frame,
+                    self.max_cstring_len,
+                    self.max_meta_count,
+                ))?;
+
+                self.process_request(id, IpAddr::V6(request.ip_address), &request.meta)?;
+            }
+            Variant::RequestCovered => {
+                let request = complete(RequestCovered::parse(frame))?;
+
+                self.check_ratelimit(&id, Some(request.prefix.into()))?;
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    let mask_len = request.mask_len as u32;
+
+                    let (covering, data_age) = {
+                        let c = self.shared.snapshot();
+
+                        (c.covering(request.prefix, mask_len), c.age())
+                    };
+
+                    let resp = match covering {
+                        Some(m) => ResponseCovered {
+                            prefix: request.prefix,
+                            mask_len,
+                            covering_prefix: m.0,
+                            covering_mask_len: m.1,
+                            data_age,
+                        }
+                        .to_bytes(),
+                        None => ResponseNotCovered {
+                            prefix: request.prefix,
+                            mask_len,
+                            data_age,
+                        }
+                        .to_bytes(),
+                    };
+
+                    Self::peer_send(&id, peer, resp);
+                }
+            }
+            Variant::RequestAll => {
+                let request = complete(RequestAll::parse(frame))?;
+
+                self.check_ratelimit(&id, Some(request.ip_address.into()))?;
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    let (matches, data_age) = {
+                        let c = self.shared.snapshot();
+
+                        (c.matches(request.ip_address), c.age())
+                    };
+
+                    let outcome = match matches.first() {
+                        Some((_, len)) => {
+                            self.shared.metrics.matched_mask_len.observe(*len);
+
+                            "found"
+                        }
+                        None => "not_found",
+                    };
+
+                    self.shared.metrics.requests.get(outcome).inc();
+
+                    let resp = ResponseAll {
+                        ip_address: request.ip_address,
+                        data_age,
+                        matches,
+                    };
+
+                    Self::peer_send(&id, peer, resp.to_bytes());
+                }
+            }
+            Variant::RequestBatch => {
+                let request = complete(RequestBatch::parse(frame))?;
+
+                if request.ip_addresses.len() > self.max_batch {
+                    return Err(LrthromeError::BatchTooLarge {
+                        count: request.ip_addresses.len(),
+                        max: self.max_batch,
+                    }
+                    .into());
+                }
+
+                self.check_ratelimit(&id, None)?;
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    let (matches, data_age) = {
+                        let c = self.shared.snapshot();
+
+                        (
+                            request
+                                .ip_addresses
+                                .into_iter()
+                                .map(|ip| (ip, c.longest_match(ip)))
+                                .collect::<Vec<_>>(),
+                            c.age(),
+                        )
+                    };
+
+                    for (_, m) in &matches {
+                        let outcome = match m {
+                            Some((_, len)) => {
+                                self.shared.metrics.matched_mask_len.observe(*len);
+
+                                "found"
+                            }
+                            None => "not_found",
+                        };
+
+                        self.shared.metrics.requests.get(outcome).inc();
+                    }
+
+                    debug!(
+                        "Batch of {} addresses with {} found (addr = {})",
+                        matches.len(),
+                        matches.iter().filter(|m| m.1.is_some()).count(),
+                        id,
+                    );
+
+                    Self::peer_send(&id, peer, ResponseBatch { data_age, matches }.to_bytes());
+                }
+            }
+            Variant::Ping => {
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    peer.last_request = Instant::now();
+
+                    Self::peer_send(&id, peer, Pong.to_bytes());
+                }
+            }
+            Variant::Health => {
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    let (tree_size, data_age) = {
+                        let c = self.shared.snapshot();
+
+                        (c.len(), c.age())
+                    };
+
+                    let resp = ResponseHealth {
+                        tree_size: tree_size as u32,
+                        data_age,
+                        uptime: self.started.elapsed().as_secs() as u32,
+                    };
+
+                    Self::peer_send(&id, peer, resp.to_bytes());
+                }
+            }
+            Variant::RequestStats => {
+                let peers = self.peers.len() as u32;
+
+                if self.peers.get(&id).is_some_and(|p| p.identity.is_none()) {
+                    warn!("Unidentified peer requested stats (addr = {})", id);
+
+                    return Err(LrthromeError::Unauthorized.into());
+                }
+
+                self.check_ratelimit(&id, None)?;
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    let metrics = &self.shared.metrics;
+                    let found = metrics.requests.get("found").get();
+                    let not_found = metrics.requests.get("not_found").get();
+
+                    let (tree_size, last_tempered_unix) = {
+                        let c = self.shared.snapshot();
+
+                        (c.len(), c.tempered_at())
+                    };
+
+                    let resp = ResponseStats {
+                        peers,
+                        requests: found + not_found,
+                        found,
+                        not_found,
+                        tree_size: tree_size as u32,
+                        last_tempered_unix,
+                    };
+
+                    Self::peer_send(&id, peer, resp.to_bytes());
+                }
+            }
+            Variant::RequestHost => {
+                let request = complete(RequestHost::parse(frame, self.max_cstring_len))?;
+
+                let resolver = self
+                    .resolver
+                    .clone()
+                    .ok_or(LrthromeError::HostLookupDisabled)?;
+
+                self.check_ratelimit(&id, None)?;
+
+                if let Some(peer) = self.peers.get_mut(&id) {
+                    let hostname = request.hostname.to_string();
+                    let shared = self.shared.clone();
+                    let tx_bytes = peer.tx_bytes.clone();
+                    let window = peer.window.clone();
+                    let signing_key = peer.signing_key.clone();
+                    let checksum = peer.checksum;
+
+                    // Resolution is done off the main loop, as it may take up to the resolver timeout.
+                    tokio::spawn(async move {
+                        let (status, addrs) = resolver.resolve(&hostname).await;
+
+                        let matches = {
+                            let c = shared.snapshot();
+
+                            addrs
+                                .into_iter()
+                                .map(|ip| (ip, c.longest_match(ip)))
+                                .collect::<Vec<_>>()
+                        };
+
+                        info!(
+                            "{} resolved ({:?}) with {} of {} addresses found (addr = {})",
+                            hostname,
+                            status,
+                            matches.iter().filter(|m| m.1.is_some()).count(),
+                            matches.len(),
+                            id,
+                        );
+
+                        let resp = ResponseHost {
+                            hostname: &hostname,
+                            status,
+                            matches,
+                        }
+                        .to_bytes();
+
+                        let resp = match sign(&signing_key, checksum, resp) {
+                            Ok(resp) => resp,
+                            Err(e) => {
+                                error!("Unable to sign payload to peer (addr = {}): {}", id, e);
+
+                                return;
+                            }
+                        };
+
+                        window.acquire();
+
+                        // A peer not reading its responses is disconnected upon its next one.
+                        if let Err(e) = tx_bytes.try_send(resp) {
+                            window.release();
+
+                            error!("Unable to send payload to peer (addr = {}): {}", id, e);
+                        }
+                    });
+                }
+            }
+            // Refused by the codec already, should a frame make it here regardless.
+            variant => return Err(LrthromeError::ServerOnlyVariant(variant.clone()).into()),
+        }
+
+        Ok(())
+    }
+
+    /// Look up an address of either family and respond in kind.
+    ///
+    /// An IPv4-mapped IPv6 address is looked up by its IPv4 address among IPv4 prefixes,
+    /// while any other IPv6 address is looked up among IPv6 prefixes.
+    /// Only IPv4 lookups are compared against shadow sources & reuse the peer's last match.
+    fn process_request(
+        &mut self,
+        id: PeerId,
+        ip_address: IpAddr,
+        meta: &HashMap<&str, &str>,
+    ) -> Result<(), FrameError> {
+        let lookup_address = match ip_address {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+
+        // Set ahead of the rate limit, for its error to be in the peer's language.
+        if let (Some(peer), Some(lang)) = (self.peers.get_mut(&id), meta.get(LANG_META_KEY)) {
+            peer.lang = Some(lang.to_string());
+        }
+
+        self.check_ratelimit(&id, Some(ip_address))?;
+
+        if let Some(peer) = self.peers.get_mut(&id) {
+            let ignore_allow = meta.get(IGNORE_ALLOW_META_KEY) == Some(&"1");
+
+            if ignore_allow && !Self::may_ignore_allow(&self.auth, peer) {
+                warn!("Peer not permitted to ignore the allowlist (addr = {})", id);
+
+                return Err(LrthromeError::Unauthorized.for_request(Some(ip_address)));
+            }
+
+            let registered = &self.sources;
+            let sources = meta
+                .get(SOURCES_META_KEY)
+                .map(|names| registered.source_set(names.split(',')))
+                .transpose()
+                .map_err(|e| e.for_request(Some(ip_address)))?;
+
+            let (longest_match, data_age) = match (ip_address, lookup_address) {
+                (_, Some(ip)) => {
+                    let last = Self::lookup(&self.shared, peer, ip, ignore_allow, sources);
+
+                    if let Some(shadow_match) = last.shadow_match {
+                        Self::record_shadow(
+                            &self.shared.metrics,
+                            &id,
+                            ip,
+                            last.longest_match,
+                            shadow_match,
+                        );
+                    }
+
+                    if let Some(m) = last.longest_match {
+                        self.shared.metrics.matched_mask_len.observe(m.1);
+                    }
+
+                    (
+                        last.longest_match
+                            .map(|(prefix, len)| (IpAddr::V4(prefix), len)),
+                        age_since(last.tempered_at),
+                    )
+                }
+                (IpAddr::V6(ip), None) => {
+                    let c = self.shared.snapshot();
+
+                    (
+                        c.longest_match_v6(ip, sources, ignore_allow)
+                            .map(|(prefix, len)| (IpAddr::V6(prefix), len)),
+                        c.age(),
+                    )
+                }
+                (IpAddr::V4(_), None) => unreachable!("IPv4 addresses are always looked up"),
+            };
+
+            self.shared
+                .metrics
+                .requests
+                .get(match longest_match {
+                    Some(_) => "found",
+                    None => "not_found",
+                })
+                .inc();
+
+            if let Some(m) = longest_match {
+                info!(
+                    "{} found in range of {}/{} ({:?}) (addr = {})",
+                    ip_address, m.0, m.1, meta, id,
+                );
+            }
+
+            let with_cidr = meta.get(CIDR_META_KEY) == Some(&"1");
+
+            // Attributed against the current tree, rather than the one a reused match was of.
+            let matched_sources = match longest_match {
+                Some((prefix, len)) if meta.get(MATCHED_SOURCES_META_KEY) == Some(&"1") => {
+                    let listed = self.shared.snapshot().listed_by(prefix, len);
+
+                    Some(self.sources.names(listed).join(","))
+                }
+                _ => None,
+            };
+
+            let resp = match (ip_address, longest_match) {
+                (IpAddr::V4(ip_address), Some((IpAddr::V4(prefix), mask_len))) => ResponseOkFound {
+                    ip_address,
+                    prefix,
+                    mask_len,
+                    data_age,
+                    with_cidr,
+                    sources: matched_sources,
+                }
+                .to_bytes(),
+                (IpAddr::V4(ip_address), _) => ResponseOkNotFound {
+                    ip_address,
+                    data_age,
+                }
+                .to_bytes(),
+                // IPv4 prefixes matched by an IPv4-mapped address are answered mapped.
+                (IpAddr::V6(ip_address), Some((IpAddr::V4(prefix), mask_len))) => {
+                    ResponseOkFoundV6 {
+                        ip_address,
+                        prefix: prefix.to_ipv6_mapped(),
+                        mask_len: mask_len + 96,
+                        data_age,
+                        with_cidr,
+                        sources: matched_sources,
+                    }
+                    .to_bytes()
+                }
+                (IpAddr::V6(ip_address), Some((IpAddr::V6(prefix), mask_len))) => {
+                    ResponseOkFoundV6 {
+                        ip_address,
+                        prefix,
+                        mask_len,
+                        data_age,
+                        with_cidr,
+                        sources: matched_sources,
+                    }
+                    .to_bytes()
+                }
+                (IpAddr::V6(ip_address), None) => ResponseOkNotFoundV6 {
+                    ip_address,
+                    data_age,
+                }
+                .to_bytes(),
+            };
+
+            Self::peer_send(&id, peer, resp);
+        }
+
+        Ok(())
+    }
+
+    /// Look up the address, reusing the peer's last match if it was of the same address,
+    /// and the tree was not tempered since, without so much as taking a snapshot.
+    fn lookup(
+        shared: &Shared,
+        peer: &mut PeerRegistry,
+        ip_address: Ipv4Addr,
+        ignore_allow: bool,
+        sources: Option<SourceSet>,
+    ) -> LastMatch {
+        // Loaded ahead of the snapshot, a temper completing in between only costs a miss.
+        let generation = shared.generation.load(Ordering::Acquire);
+
+        match peer.last_match {
+            Some(last)
+                if last.generation == generation
+                    && last.ip_address == ip_address
+                    && last.ignore_allow == ignore_allow
+                    && last.sources == sources =>
+            {
+                last
+            }
+            _ => {
+                let c = shared.snapshot();
+
+                let last = LastMatch {
+                    generation,
+                    ip_address,
+                    ignore_allow,
+                    sources,
+                    longest_match: match sources {
+                        Some(sources) => c.filtered_match(ip_address, sources, ignore_allow),
+                        None if ignore_allow => c.longest_deny_match(ip_address),
+                        None => c.longest_match(ip_address),
+                    },
+                    // Shadow sources are compared against unfiltered lookups alone.
+                    shadow_match: match sources {
+                        Some(_) => None,
+                        None => c.shadow_match(ip_address),
+                    },
+                    tempered_at: c.tempered_at(),
+                };
+
+                peer.last_match = Some(last);
+
+                last
+            }
+        }
+    }
+
+    /// Meter whether the shadow sources agree with the live result.
+    ///
+    /// Disagreements are logged, a shadow-only match being a would-be false positive.
+    fn record_shadow(
+        metrics: &Metrics,
+        id: &PeerId,
+        ip_address: Ipv4Addr,
+        live: Option<(Ipv4Addr, u32)>,
+        shadow: Option<(Ipv4Addr, u32)>,
+    ) {
+        let outcome = match (live, shadow) {
+            (Some(_), Some(_)) => "both",
+            (None, None) => "neither",
+            (Some(_), None) => "live_only",
+            (None, Some(_)) => "shadow_only",
+        };
+
+        if let (None, Some(m)) = (live, shadow) {
+            info!(
+                "{} would be found in shadow range of {}/{} (addr = {})",
+                ip_address, m.0, m.1, id,
+            );
+        }
+
+        metrics.shadow_lookups.get(outcome).inc();
+    }
+
+    /// Count a request of the peer towards its rate limit, refused should it exceed the limit,
+    /// with the error attributed to the requested address, if any.
+    fn check_ratelimit(
+        &mut self,
+        id: &PeerId,
+        ip_address: Option<IpAddr>,
+    ) -> Result<(), FrameError> {
+        let peer = match self.peers.get_mut(id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
+
+        if let Some(retry_after) = Self::ratelimited(
+            &mut self.ratelimiter,
+            &mut self.identity_limiters,
+            self.rate_limit_window,
+            id,
+            peer,
+        ) {
+            warn!("Peer exceeded ratelimit (addr = {})", id);
+
+            return Err(LrthromeError::Ratelimited(retry_after).for_request(ip_address));
+        }
+
+        peer.last_request = Instant::now();
+
+        Ok(())
+    }
+
+    /// Time until the peer may request again if it exceeded its identity's rate limit,
+    /// or its address's if it has not identified.
+    ///
+    /// Requests of exempt peers, or within the peer's grace,
+    /// are neither limited nor counted towards the limit.
+    fn ratelimited(
+        ratelimiter: &mut KeyedRateLimiter<IpAddr, GCRA>,
+        identity_limiters: &mut HashMap<String, DirectRateLimiter<GCRA>>,
+        window: Duration,
+        id: &PeerId,
+        peer: &mut PeerRegistry,
+    ) -> Option<Duration> {
+        if peer.exempt {
+            return None;
+        }
+
+        if peer.grace > 0 {
+            peer.grace -= 1;
+
+            return None;
+        }
+
+        let decision = match peer
+            .identity
+            .as_ref()
+            .and_then(|name| identity_limiters.get_mut(name))
+        {
+            Some(limiter) => limiter.check(),
+            None => ratelimiter.check(id.ratelimit_key()),
+        };
+
+        // The earliest time reported is that of the theoretical arrival,
+        // a window later than a request would be allowed at.
+        decision.err().map(|not_until| {
+            not_until
+                .wait_time_from(Instant::now())
+                .saturating_sub(window)
+        })
+    }
+
+    /// Whether the peer identified as an identity permitted to ignore the allowlist.
+    fn may_ignore_allow(auth: &Option<Auth>, peer: &PeerRegistry) -> bool {
+        match (auth, &peer.identity) {
+            (Some(auth), Some(name)) => auth.find(name).is_some_and(|i| i.ignore_allow),
+            _ => false,
+        }
+    }
+
+    /// Re-read identify tokens.
+    ///
+    /// Peers of identities no longer present fall back to the address based limit.
+    fn reload_auth(&mut self) {
+        if let Some(auth) = &mut self.auth {
+            if let Err(e) = auth.reload() {
+                error!("Unable to reload identify tokens, retaining current: {}", e);
+
+                return;
+            }
+
+            self.rebuild_identity_limiters();
+        }
+    }
+
+    /// Re-read the config, applying the settings that can be applied live.
+    ///
+    /// These are the banner, cache & peer TTLs, rate limit, and sources,
+    /// the rebuilt sources being tempered by the caller.
+    /// The current settings are retained entirely should the config fail to load.
+    fn reload_config(&mut self) {
+        let path = match &self.config_path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let reloaded = Config::load(&path).and_then(|config| {
+            config.validate()?;
+
+            let allow_empty_sources = config.sources.allow_empty_sources;
+            let sources = Sources::from_config(config.sources, self.shared.metrics.clone())?;
+
+            match sources.usable() {
+                0 if !allow_empty_sources => Err(LrthromeError::NoSources),
+                _ => Ok((config.general, sources)),
+            }
+        });
+
+        let (general, sources) = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                error!("Unable to reload config, retaining current: {}", e);
+
+                return;
+            }
+        };
+
+        if general.bind_address != self.bind_address {
+            warn!(
+                "Ignored bind address change, a restart is required (current = {}) (configured = {})",
+                self.bind_address.join(", "),
+                general.bind_address.join(", ")
+            );
+        }
+
+        match NonZeroU32::new(general.rate_limit) {
+            Some(rate_limit) if rate_limit != self.rate_limit => {
+                self.rate_limit = rate_limit;
+                self.ratelimiter = KeyedRateLimiter::new(rate_limit, self.rate_limit_window);
+            }
+            Some(_) => {}
+            None => warn!("Ignored rate limit of 0, retaining {}", self.rate_limit),
+        }
+
+        let interval = self.sources.refresh_interval(self.cache_ttl_duration());
+
+        self.banner = general.banner;
+        self.cache_ttl = general.cache_ttl;
+
+        let rearm = general.peer_ttl != self.peer_ttl
+            || sources.refresh_interval(self.cache_ttl_duration()) != interval;
+
+        self.peer_ttl = general.peer_ttl;
+
+        info!(
+            "Reloaded config with {} sources (path = {})",
+            sources.sources().len(),
+            path.display()
+        );
+
+        self.sources = Arc::new(sources);
+
+        if rearm {
+            self.start_timers();
+        }
+    }
+
+    fn rebuild_identity_limiters(&mut self) {
+        self.identity_limiters = self
+            .auth
+            .iter()
+            .flat_map(|auth| auth.identities())
+            .map(|identity| {
+                (
+                    identity.name.clone(),
+                    DirectRateLimiter::new(identity.rate_limit, self.rate_limit_window),
+                )
+            })
+            .collect();
+    }
+
+    async fn process_admin(&mut self, command: Command) {
+        match command {
+            Command::Export(tx) => {
+                let export = {
+                    let c = self.shared.snapshot();
+
+                    c.export()
+                };
+
+                info!("Exporting tree to admin");
+
+                let _ = tx.send(export);
+            }
+            Command::Metrics(tx) => {
+                let _ = tx.send(self.shared.metrics.render());
+            }
+            Command::Set(tunable, tx) => {
+                info!("Admin adjusted {:?}", tunable);
+
+                let previous = match tunable {
+                    Tunable::RateLimit(rate_limit) => {
+                        let previous = self.rate_limit;
+
+                        self.rate_limit = rate_limit;
+                        self.ratelimiter =
+                            KeyedRateLimiter::new(rate_limit, self.rate_limit_window);
+
+                        previous.to_string()
+                    }
+                    Tunable::RateLimitGrace(requests) => {
+                        let previous = self.rate_limit_grace;
+
+                        self.rate_limit_grace(requests);
+
+                        previous.to_string()
+                    }
+                    Tunable::MaxInFlight(max) => {
+                        let previous = self.max_in_flight;
+
+                        self.max_in_flight(max as usize);
+
+                        previous.to_string()
+                    }
+                    Tunable::MaxConnections(max) => {
+                        let previous = self.max_connections;
+
+                        self.max_connections(max);
+
+                        previous.to_string()
+                    }
+                    Tunable::MaxConnectionsPerIp(max) => {
+                        let previous = self.max_connections_per_ip;
+
+                        self.max_connections_per_ip(max);
+
+                        previous.to_string()
+                    }
+                };
+
+                let _ = tx.send(previous);
+            }
+            Command::Kick(kick, tx) => {
+                let _ = tx.send(self.kick(kick));
+            }
+            Command::Ban(cidr, dur, tx) => {
+                info!("Admin banned {} (duration = {:?})", cidr, dur);
+
+                self.bans.ban(cidr.clone(), dur);
+
+                let _ = tx.send(self.kick(Kick {
+                    target: KickTarget::Network(cidr),
+                    reason: None,
+                    ban: false,
+                }));
+            }
+            Command::Unban(cidr, tx) => {
+                info!("Admin unbanned {}", cidr);
+
+                let _ = tx.send(self.bans.unban(&cidr));
+            }
+            Command::Bans(tx) => {
+                let _ = tx.send(self.bans.to_text());
+            }
+            Command::ForceTemper(tx) => {
+                info!("Admin forced a temper");
+
+                // Applies to the temper in progress instead, should there be one.
+                self.force_admit = true;
+                self.spawn_temper();
+
+                let _ = tx.send(());
+            }
+        }
+    }
+
+    /// Count a rate limit violation of the peer's address, banning it upon the threshold.
+    fn record_violation(&mut self, id: &PeerId) {
+        if self.ban_threshold == 0 {
+            return;
+        }
+
+        let ip = id.connection_key();
+        let now = Instant::now();
+
+        let (first, count) = self.violations.entry(ip).or_insert((now, 0));
+
+        if now.duration_since(*first) > self.ban_window {
+            *first = now;
+            *count = 0;
+        }
+
+        *count += 1;
+
+        if *count >= self.ban_threshold {
+            warn!(
+                "Banned {} after {} rate limit violations (duration = {}s)",
+                ip,
+                count,
+                self.violation_ban_duration.as_secs()
+            );
+
+            self.violations.remove(&ip);
+            self.bans
+                .ban(IpCidr::new_host(ip), Some(self.violation_ban_duration));
+        }
+    }
+
+    /// Disconnect the matching peers, banning their address if requested.
+    fn kick(&mut self, kick: Kick) -> usize {
+        if kick.ban {
+            if let KickTarget::Ip(ip) = kick.target {
+                info!(
+                    "Admin banned {} (duration = {}s)",
+                    ip,
+                    self.ban_duration.as_secs()
+                );
+
+                self.bans.ban(
+                    IpCidr::new_host(peer::normalize(ip)),
+                    Some(self.ban_duration),
+                );
+            }
+        }
+
+        let mut kicked = 0;
+
+        for (id, peer) in self.peers.iter_mut() {
+            let matches = match kick.target {
+                KickTarget::Peer(addr) => id.addr() == addr,
+                KickTarget::Ip(ip) => id.connection_key() == peer::normalize(ip),
+                KickTarget::Network(ref cidr) => cidr.contains(&id.connection_key()),
+            };
+
+            if !matches {
+                continue;
+            }
+
+            info!("Admin kicked peer (addr = {}) ({:?})", id, kick.reason);
+
+            match &kick.reason {
+                Some(reason) => Self::peer_error(
+                    id,
+                    peer,
+                    LrthromeError::Kicked(reason.clone()),
+                    None,
+                    &self.locales,
+                ),
+                None => Self::shutdown_peer(peer, id),
+            }
+
+            kicked += 1;
+        }
+
+        kicked
+    }
+
+    /// Build the `Established` payload, with the banner localized to the language.
+    async fn established(&self, lang: Option<&str>) -> Bytes {
+        let (tree_size, data_age, last_tempered_unix) = {
+            let c = self.shared.snapshot();
+
+            (c.len(), c.age(), c.tempered_at())
+        };
+
+        Established {
+            rate_limit: self.rate_limit.into(),
+            tree_size: tree_size as u32,
+            cache_ttl: self.cache_ttl,
+            peer_ttl: self.peer_ttl,
+            banner: if self.omit_banner {
+                None
+            } else {
+                Some(self.locales.banner(lang, &self.banner))
+            },
+            data_age,
+            capabilities: if self.frame_checksums {
+                CAPABILITY_IPV6 | CAPABILITY_CHECKSUM
+            } else {
+                CAPABILITY_IPV6
+            },
+            versions: SUPPORTED_VERSIONS,
+            rate_limit_window: self.rate_limit_window.as_secs() as u32,
+            last_tempered_unix,
+        }
+        .to_bytes()
+    }
+
+    /// Send the deferred `Established` to the peer.
+    async fn establish(&mut self, id: PeerId, lang: Option<String>) {
+        let payload = self.established(lang.as_deref()).await;
+
+        if let Some(peer) = self.peers.get_mut(&id) {
+            if lang.is_some() {
+                peer.lang = lang;
+            }
+
+            Self::peer_send(&id, peer, payload);
+
+            peer.established = true;
+        }
+    }
+
+    fn peer_error(
+        id: &PeerId,
+        peer: &mut PeerRegistry,
+        error: LrthromeError,
+        ip_address: Option<IpAddr>,
+        locales: &Locales,
+    ) {
+        Self::peer_reject(id, peer, error, ip_address, locales);
+        Self::shutdown_peer(peer, id);
+    }
+
+    /// Respond with the error, keeping the connection open.
+    fn peer_reject(
+        id: &PeerId,
+        peer: &mut PeerRegistry,
+        error: LrthromeError,
+        ip_address: Option<IpAddr>,
+        locales: &Locales,
+    ) {
+        let resp = ResponseError {
+            code: error.code(),
+            message: &locales.error(peer.lang.as_deref(), &error),
+            ip_address: ip_address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            retry_after_ms: error.retry_after_ms(),
+        }
+        .to_bytes();
+
+        Self::peer_send(id, peer, resp);
+    }
+
+    /// Connection limit the peer would exceed, with its maximum.
+    ///
+    /// Connections of an address are counted by scanning, once per accept.
+    fn connection_limit(&self, id: &PeerId) -> Option<(&'static str, u32)> {
+        if self.max_connections > 0 && self.peers.len() >= self.max_connections as usize {
+            return Some(("global", self.max_connections));
+        }
+
+        let ip = id.connection_key();
+
+        if self.max_connections_per_ip > 0
+            && self
+                .peers
+                .keys()
+                .filter(|peer| peer.connection_key() == ip)
+                .count()
+                >= self.max_connections_per_ip as usize
+        {
+            return Some(("per_ip", self.max_connections_per_ip));
+        }
+
+        None
+    }
+
+    /// Write out the error to a peer that was never registered, then close it.
+    fn refuse_peer(&self, mut stream: TcpStream, error: LrthromeError) {
+        let resp = ResponseError {
+            code: error.code(),
+            message: &self.locales.error(None, &error),
+            ip_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            retry_after_ms: error.retry_after_ms(),
+        }
+        .to_bytes();
+
+        tokio::spawn(async move {
+            let _ = stream.write_all(&resp).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+
+    fn peer_send(id: &PeerId, peer: &mut PeerRegistry, payload: Bytes) {
+        let payload = match sign(&peer.signing_key, peer.checksum, payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("Unable to sign payload to peer (addr = {}): {}", id, e);
+
+                return;
+            }
+        };
+
+        peer.window.acquire();
+
+        match peer.tx_bytes.try_send(payload) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Peer is not reading its responses, disconnecting (addr = {})",
+                    id
+                );
+
+                Self::shutdown_peer(peer, id);
+            }
+            Err(e) => error!("Unable to send payload to peer (addr = {}): {}", id, e),
+        }
+    }
+
+    fn shutdown_peer(peer: &mut PeerRegistry, id: &PeerId) {
+        if let Err(e) = peer.tx_shutdown.send(true) {
+            error!("Unable to shutdown peer (addr = {}): {}", id, e);
+        }
+    }
+
+    fn cleanup(&mut self) {
+        self.ratelimiter.cleanup(Duration::from_secs(60));
+    }
+
+    /// Temper and wait for it to complete, as upon startup before connections are accepted.
+    async fn temper_cache(&mut self) -> LrthromeResult<()> {
+        match self.start_temper() {
+            Some(tempered) => {
+                let tempered = tempered.await;
+
+                self.finish_temper(tempered)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Temper without waiting on it, the tree swapped in once `Message::Tempered` arrives,
+    /// so peer frames are handled throughout.
+    fn spawn_temper(&mut self) {
+        if let Some(tempered) = self.start_temper() {
+            let tx = self.shared.tx.clone();
+
+            tokio::spawn(async move {
+                let _ = tx.send(Message::Tempered(Box::new(tempered.await))).await;
+            });
+        }
+    }
+
+    /// Spawn a temper of the current sources onto the temper runtime, if any,
+    /// none should one already be in progress.
+    fn start_temper(&self) -> Option<impl std::future::Future<Output = Tempered>> {
+        if self.shared.tempering.swap(true, Ordering::AcqRel) {
+            self.shared.metrics.temper_skipped.inc();
+
+            warn!(
+                "Temper already in progress, skipped (total skipped = {})",
+                self.shared.metrics.temper_skipped.get()
+            );
+
+            return None;
+        }
+
+        let started = Instant::now();
+        let sources = self.sources.clone();
+
+        let temper = {
+            let sources = sources.clone();
+            let metrics = self.shared.metrics.clone();
+            let current = self.shared.snapshot();
+            let cache_ttl = self.cache_ttl_duration();
+
+            async move { Cache::temper(&sources, &metrics, Some(&current), cache_ttl).await }
+        };
+
+        let handle = match &self.temper_runtime {
+            Some(runtime) => runtime.spawn(temper),
+            None => tokio::spawn(temper),
+        };
+
+        Some(async move {
+            Tempered {
+                result: handle.await.unwrap_or_else(|e| Err(e.into())),
+                sources,
+                started,
+            }
+        })
+    }
+
+    /// Swap the tempered tree in, unless refused or tempered from sources since reloaded.
+    fn finish_temper(&mut self, tempered: Tempered) -> LrthromeResult<()> {
+        let Tempered {
+            result,
+            sources,
+            started,
+        } = tempered;
+
+        if !Arc::ptr_eq(&sources, &self.sources) {
+            info!("Sources were reloaded during the temper, tempering again");
+
+            self.shared.tempering.store(false, Ordering::Release);
+            self.spawn_temper();
+
+            return Ok(());
+        }
+
+        let force = std::mem::take(&mut self.force_admit);
+
+        let tempered = result.and_then(|cache| match cache {
+            Some(cache) => self.admit_tree(cache, force).map(Some),
+            None => Ok(None),
+        });
+
+        let result = match tempered {
+            Ok(Some(cache)) => {
+                self.shared.metrics.tree_entries.set(cache.len() as u64);
+                self.shared.swap(cache);
+
+                // Written out in the background, after the previous write.
+                self.persist_cache();
+
+                Ok(())
+            }
+            // The retained tree is as fresh as the sources.
+            Ok(None) => {
+                self.shared.snapshot().mark_tempered();
+
+                Ok(())
+            }
+            // Neither swapped nor marked tempered, the data age keeps growing until sources recover.
+            Err(e @ LrthromeError::SourcesFailed) | Err(e @ LrthromeError::TreeShrunk { .. }) => {
+                error!("Temper aborted: {}", e);
+
+                // Sources refreshed into a refused tree are fetched again, rather than carried over.
+                self.sources.expire();
+
+                self.shared.tempering.store(false, Ordering::Release);
+
+                return Ok(());
+            }
+            Err(e) => Err(e),
+        };
+
+        if result.is_ok() {
+            self.shared.generation.fetch_add(1, Ordering::AcqRel);
+            self.shared
+                .metrics
+                .temper_millis
+                .set(started.elapsed().as_millis() as u64);
+        }
+
+        self.shared.tempering.store(false, Ordering::Release);
+
+        result
+    }
+
+    /// Refuse a tempered tree that shrunk below `min_tree_percent` of the current one, unless forced.
+    fn admit_tree(&self, cache: Cache, force: bool) -> LrthromeResult<Cache> {
+        let current = self.shared.snapshot().len();
+
+        if cache.len() * 100 < current * self.min_tree_percent as usize {
+            if !force {
+                return Err(LrthromeError::TreeShrunk {
+                    entries: cache.len(),
+                    current,
+                    percent: self.min_tree_percent,
+                });
+            }
+
+            warn!(
+                "Forced swap of a tree shrunk below {}% of the current one (entries = {}) (current = {})",
+                self.min_tree_percent,
+                cache.len(),
+                current
+            );
+        }
+
+        Ok(cache)
+    }
+
+    /// Serve the persisted tree until the first temper completes, if any.
+    fn load_cache(&mut self) {
+        let path = match &self.cache_file {
+            Some(path) => path,
+            None => return,
+        };
+
+        match Cache::load(path, self.sources.tree_per_source()) {
+            Ok(cache) => {
+                info!(
+                    "Loaded persisted tree from {} (entries = {}) (age = {}s)",
+                    path.display(),
+                    cache.len(),
+                    cache.age()
+                );
+
+                self.shared.metrics.tree_entries.set(cache.len() as u64);
+                self.shared.swap(cache);
+            }
+            Err(LrthromeError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!("No persisted tree at {}, starting empty", path.display());
+            }
+            Err(e) => warn!(
+                "Unable to load persisted tree from {}, starting empty: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Write the current tree out off the main loop, if a file is configured.
+    ///
+    /// Waits on the previous write, so an older tree never replaces a newer one.
+    fn persist_cache(&mut self) {
+        let path = match &self.cache_file {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let cache = self.shared.snapshot();
+
+        // A tree never tempered would only overwrite a warmer one.
+        if !cache.tempered() {
+            return;
+        }
+
+        let previous = self.persisting.take();
+
+        self.persisting = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+
+            let saved = tokio::task::spawn_blocking({
+                let path = path.clone();
+
+                move || cache.save(&path)
+            })
+            .await
+            .map_err(LrthromeError::from)
+            .and_then(|saved| saved);
+
+            match saved {
+                Ok(()) => debug!("Persisted tree to {}", path.display()),
+                Err(e) => error!("Unable to persist tree to {}: {}", path.display(), e),
+            }
+        }));
+    }
+
+    fn sweep_peers(&mut self) -> LrthromeResult<()> {
+        // Peers never time out.
+        if self.peer_ttl == 0 {
+            return Ok(());
+        }
+
+        for c in self.peers.values().filter(|c| c.health_only != Some(true)) {
+            if c.last_request.elapsed() > Duration::from_secs(self.peer_ttl as u64) {
+                c.tx_shutdown.send(true)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_peer(&mut self, peer: Peer) {
+        let drain_timeout = self.linger.unwrap_or(self.shutdown_grace);
+
+        tokio::spawn(peer.run(self.shared.clone(), drain_timeout));
+    }
+
+    /// Starts background timers.
+    ///
+    /// Peer & Cache TTL timers will initialize here.
+    ///
+    /// A TTL of 0 disables its timer, rather than ticking in a busy-loop.
+    /// Timers already started are aborted, re-arming them with the current TTLs.
+    fn start_timers(&mut self) {
+        for timer in self.timers.drain(..) {
+            timer.abort();
+        }
+
+        match self.sources.refresh_interval(self.cache_ttl_duration()) {
+            Some(interval) => {
+                let timer = self.start_cache_timer(interval);
+
+                self.timers.push(timer);
+            }
+            None => info!("Cache TTL is 0, the cache will not be refreshed after startup"),
+        }
+
+        if self.peer_ttl == 0 {
+            info!("Peer TTL is 0, peers will not time out");
+        } else {
+            let timer = self.start_peer_timer();
+
+            self.timers.push(timer);
+        }
+    }
+
+    /// Cache TTL as a duration, none if the cache is never refreshed along it.
+    fn cache_ttl_duration(&self) -> Option<Duration> {
+        match self.cache_ttl {
+            0 => None,
+            ttl => Some(Duration::from_secs(ttl as u64)),
+        }
+    }
+
+    /// Tick every `cache_ttl`, or sooner should a source refresh more often.
+    fn start_cache_timer(&self, cache_ttl: Duration) -> JoinHandle<()> {
+        let shared = self.shared.clone();
+        let cache_jitter = self.cache_jitter;
+
+        tokio::spawn(async move {
+            // Ticks are scheduled against a running deadline rather than
+            // sleeping a fixed duration, so the schedule does not drift by temper duration.
+            let mut deadline = time::Instant::now();
+
+            loop {
+                deadline += jitter(cache_ttl, cache_jitter);
+
+                sleep_until(deadline).await;
+
+                if shared.tempering.load(Ordering::Acquire) {
+                    shared.metrics.temper_skipped.inc();
+
+                    warn!(
+                        "Temper already in progress, skipped a cache tick (total skipped = {})",
+                        shared.metrics.temper_skipped.get()
+                    );
+
+                    continue;
+                }
+
+                if let Err(e) = shared.tx.send(Message::CacheTick).await {
+                    error!("Unable to send cache tick: {0}", e);
+                }
+
+                shared.tempered.notified().await;
+
+                // Skip rather than queue ticks missed by a temper overrunning its interval.
+                while deadline + cache_ttl <= time::Instant::now() {
+                    deadline += cache_ttl;
+
+                    shared.metrics.temper_skipped.inc();
+
+                    warn!(
+                        "Temper overran cache_ttl, skipped a cache tick (total skipped = {})",
+                        shared.metrics.temper_skipped.get()
+                    );
+                }
+            }
+        })
+    }
+
+    fn start_peer_timer(&self) -> JoinHandle<()> {
+        let shared = self.shared.clone();
+        let peer_ttl = Duration::from_secs(self.peer_ttl as u64);
+
+        tokio::spawn(async move {
+            loop {
+                sleep(peer_ttl).await;
+
+                if let Err(e) = shared.tx.send(Message::PeerTick).await {
+                    error!("Unable to send cache tick: {0}", e);
+                }
+            }
+        })
+    }
+}
+
+/// Offset the duration randomly by up to `percent` of itself in either direction.
+fn jitter(dur: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return dur;
+    }
+
+    // RandomState is randomly keyed per instance, sufficient for desynchronizing timers.
+    let random = RandomState::new().build_hasher().finish();
+
+    // Uniform within [-1.0, 1.0]
+    let factor = (random % 2001) as f64 / 1000.0 - 1.0;
+
+    dur.mul_f64(1.0 + factor * percent as f64 / 100.0)
+}
+
+/// Events queued to the main loop, such as peer frames, before their senders wait.
+const EVENT_QUEUE_LEN: usize = 4096;
+
+/// Interval expired bans are forgotten at.
+const BAN_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest wait between bind attempts.
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Pending connections queued by a listener, as `TcpListener::bind` would.
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Bind a listener, retrying up to `retries` times should the address be unavailable,
+/// such as while the previous instance's sockets linger during a rolling restart.
+///
+/// The wait starts at `interval`, doubling after every attempt up to `MAX_BIND_BACKOFF`.
+/// Listeners are bound with `SO_REUSEADDR`, sockets in `TIME_WAIT` do not hold the address.
+pub async fn bind(addr: &str, retries: u32, interval: Duration) -> LrthromeResult<TcpListener> {
+    let mut wait = interval;
+
+    for attempt in 1.. {
+        match listen(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if attempt <= retries => {
+                warn!(
+                    "Unable to bind, retrying in {:?} (addr = {}) (attempt = {}/{}): {}",
+                    wait, addr, attempt, retries, e
+                );
+
+                sleep(wait).await;
+
+                wait = (wait * 2).min(MAX_BIND_BACKOFF);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    unreachable!()
+}
+
+/// Listen on the first address `addr` resolves to that binds.
+///
+/// IPv6 listeners only accept IPv6, so `[::]` may share a port with `0.0.0.0`
+/// whatever the platform's dual stack default.
+async fn listen(addr: &str) -> io::Result<TcpListener> {
+    let mut last = None;
+
+    for addr in tokio::net::lookup_host(addr).await? {
+        match listen_on(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last = Some(e),
+        }
+    }
+
+    Err(last.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+fn listen_on(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Accept a connection from whichever listener has one first.
+///
+/// Never resolves without listeners.
+async fn accept(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    if listeners.is_empty() {
+        return future::pending().await;
+    }
+
+    let (accepted, _, _) =
+        future::select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await;
+
+    accepted
+}
+
+/// Sign the payload if the peer identified with a signing key,
+/// then checksum it if the peer opted in.
+///
+/// The checksum flag is set before signing, so the signature covers the header as sent.
+fn sign(signing_key: &Option<SigningKey>, checksum: bool, payload: Bytes) -> LrthromeResult<Bytes> {
+    let payload = match (signing_key, checksum) {
+        (Some(key), true) => key.sign(flag_checksum(payload))?,
+        (Some(key), false) => key.sign(payload)?,
+        (None, _) => payload,
+    };
+
+    Ok(if checksum {
+        checksummed(payload)
+    } else {
+        payload
+    })
+}
+
+impl Shared {
+    pub fn new(tx: mpsc::Sender<Message>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            cache: ArcSwap::from_pointee(Cache::new()),
+            generation: AtomicU64::new(0),
+            tx,
+            tempered: Notify::new(),
+            tempering: AtomicBool::new(false),
+            metrics,
+        }
+    }
+
+    /// Snapshot of the current tree, unaffected by tempers completing after.
+    fn snapshot(&self) -> Arc<Cache> {
+        self.cache.load_full()
+    }
+
+    /// Swap in a tempered tree atomically.
+    ///
+    /// Snapshots taken before keep serving the old tree until dropped.
+    fn swap(&self, cache: Cache) {
+        self.cache.store(Arc::new(cache));
+    }
+}
+
+impl PeerRegistry {
+    pub fn new(
+        tx_shutdown: watch::Sender<bool>,
+        tx_bytes: mpsc::Sender<Bytes>,
+        window: Arc<Window>,
+        grace: u32,
+    ) -> Self {
+        Self {
+            last_request: Instant::now(),
+            tx_shutdown,
+            tx_bytes,
+            established: false,
+            version: None,
+            checksum: false,
+            health_only: None,
+            lang: None,
+            identity: None,
+            signing_key: None,
+            window,
+            grace,
+            exempt: false,
+            last_match: None,
+        }
+    }
+}
+
+impl Peer {
+    pub fn new(
+        id: PeerId,
+        stream: TcpStream,
+        codec: LrthromeCodec,
+        rx_shutdown: watch::Receiver<bool>,
+        rx_bytes: mpsc::Receiver<Bytes>,
+        window: Arc<Window>,
+        egress: Option<Egress>,
+    ) -> Self {
+        Self {
+            id,
+            frame: codec.framed(stream),
+            rx_shutdown,
+            rx_bytes,
+            window,
+            egress,
+        }
+    }
+
+    /// Process the peer's frames & outgoing bytes until disconnect or shutdown,
+    /// writing out bytes queued upon shutdown for at most `drain_timeout`.
+    async fn run(mut self, shared: Arc<Shared>, drain_timeout: Duration) {
+        // Set upon a protocol error, after which the peer is only read from again
+        // once the main loop has responded with the error & shut it down.
+        let mut errored = false;
+
+        loop {
+            select! {
+                _ = self.rx_shutdown.changed() => {
+                    self.drain(drain_timeout).await;
+
+                    break;
+                }
+                Some(bytes) = self.rx_bytes.recv() => {
+                    // Neither frames nor further responses are handled while throttled.
+                    if let Some(egress) = &mut self.egress {
+                        let wait = egress.take(bytes.len(), Instant::now());
+
+                        if !wait.is_zero() {
+                            sleep(wait).await;
+                        }
+                    }
+
+                    if let Err(e) = self.frame.send(bytes).await {
+                        error!("Unable to send bytes to {}: {}", self.id, e);
+                    }
+
+                    self.window.release();
+                }
+                // Paused until the window is released.
+                _ = self.window.released(), if self.window.is_full() => {}
+                frame = self.frame.next(), if !errored && !self.window.is_full() => {
+                    match frame {
+                        Some(message) => {
+                            match message {
+                                Ok(frame) => {
+                                    errored = frame.is_err();
+
+                                    self.window.acquire();
+
+                                    let _ = shared.tx.send(Message::PeerFrame(self.id, frame)).await;
+                                },
+                                Err(_) => {
+                                    break;
+                                }
+                            }
+                        },
+                        None => {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Peer has no more frames, declare disconnect.
+        let _ = shared.tx.send(Message::PeerDisconnected(self.id)).await;
+
+        // Exiting this future will drop peer, dropping the connection
+    }
+
+    /// Write out bytes queued before shutdown, such as a final `ResponseError`,
+    /// so they reach the peer before the stream is dropped.
+    ///
+    /// Given up on after `timeout`, as a peer not reading would otherwise never be let go of.
+    async fn drain(&mut self, timeout: Duration) {
+        // Closing stops further sends, while retaining those already queued.
+        self.rx_bytes.close();
+
+        let id = self.id;
+        let rx_bytes = &mut self.rx_bytes;
+        let frame = &mut self.frame;
+
+        let drained = time::timeout(timeout, async move {
+            while let Some(bytes) = rx_bytes.recv().await {
+                if let Err(e) = frame.feed(bytes).await {
+                    error!("Unable to send bytes to {}: {}", id, e);
+
+                    return;
+                }
+            }
+
+            if let Err(e) = frame.flush().await {
+                error!("Unable to flush bytes to {}: {}", id, e);
+            }
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                "Peer did not read its final bytes within {:?}, dropping (addr = {})",
+                timeout, id
+            );
+        }
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Server of the sources & rate limit on an ephemeral port, configured before being returned.
+    #[allow(dead_code)]
+    async fn spawn_server(
+        sources: Sources,
+        rate_limit: u32,
+        configure: impl FnOnce(&mut Lrthrome),
+    ) -> Lrthrome {
+        let mut lrthrome = Lrthrome::new(
+            vec![TcpListener::bind("127.0.0.1:0").await.unwrap()],
+            sources,
+            NonZeroU32::new(rate_limit).unwrap(),
+            Arc::default(),
+        )
+        .unwrap();
+
+        configure(&mut lrthrome);
+
+        lrthrome
+    }
+
+    /// Register a peer of the address, yet to be sent `Established`,
+    /// returning the receivers of its responses & shutdown.
+    #[allow(dead_code)]
+    fn connect(
+        lrthrome: &mut Lrthrome,
+        addr: &str,
+    ) -> (PeerId, mpsc::Receiver<Bytes>, watch::Receiver<bool>) {
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let (tx_bytes, rx_bytes) = mpsc::channel(16);
+
+        let id = PeerId::new(addr.parse().unwrap());
+
+        lrthrome.peers.insert(
+            id,
+            PeerRegistry::new(tx_shutdown, tx_bytes, Arc::new(Window::new(4)), 0),
+        );
+
+        (id, rx_bytes, rx_shutdown)
+    }
+
+    #[test]
+    fn sign_checksummed() {
+        use crate::protocol::{crc32, CHECKSUM_FLAG, CHECKSUM_LEN};
+
+        let key = SigningKey::new(b"secret").unwrap();
+
+        let frame = sign(
+            &Some(key.clone()),
+            true,
+            Header::new(Variant::Pong).to_bytes().freeze(),
+        )
+        .unwrap();
+
+        let (signed, crc) = frame.split_at(frame.len() - CHECKSUM_LEN);
+
+        assert_eq!(crc, crc32(signed).to_le_bytes());
+
+        // Verified as sent, the checksum flag included.
+        let (header, _) = signed.split_at(signed.len() - SigningKey::SIGNATURE_LEN);
+
+        assert_eq!(header[1], Variant::Pong as u8 | CHECKSUM_FLAG);
+        assert_eq!(
+            &key.sign(Bytes::copy_from_slice(header)).unwrap()[..],
+            signed
+        );
+
+        let (_, header) = Header::parse(signed).unwrap();
+
+        assert_eq!(header.variant, Variant::Pong);
+        assert!(header.checksum);
+    }
+
+    /// Snapshot time of readers contending with each other & a tree swapped in continuously.
+    ///
+    /// Run with `cargo test --release bench_snapshot -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_snapshot() {
+        const READERS: usize = 8;
+        const SNAPSHOTS: u32 = 1_000_000;
+
+        let (tx, _rx) = mpsc::channel(1);
+        let shared = Arc::new(Shared::new(tx, Arc::default()));
+        let done = Arc::new(AtomicBool::new(false));
+
+        let swapper = {
+            let shared = shared.clone();
+            let done = done.clone();
+
+            std::thread::spawn(move || {
+                let mut swaps = 0;
+
+                while !done.load(Ordering::Relaxed) {
+                    shared.swap(Cache::new());
+                    swaps += 1;
                 }
-            }
+
+                swaps
+            })
+        };
+
+        let started = Instant::now();
+
+        let readers = (0..READERS)
+            .map(|_| {
+                let shared = shared.clone();
+
+                std::thread::spawn(move || {
+                    for _ in 0..SNAPSHOTS {
+                        std::hint::black_box(shared.snapshot());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        let elapsed = started.elapsed();
+
+        done.store(true, Ordering::Relaxed);
+
+        println!(
+            "{} readers: {:?} per snapshot, {} swaps",
+            READERS,
+            elapsed / SNAPSHOTS,
+            swapper.join().unwrap()
+        );
+    }
+
+    #[test]
+    fn disconnect_upon_full_queue() {
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let (tx_bytes, _rx_bytes) = mpsc::channel(1);
+
+        let id = PeerId::new("10.0.0.1:1000".parse().unwrap());
+        let mut peer = PeerRegistry::new(tx_shutdown, tx_bytes, Arc::new(Window::new(4)), 0);
+
+        Lrthrome::peer_send(&id, &mut peer, Pong.to_bytes());
+
+        assert!(!*rx_shutdown.borrow());
+
+        Lrthrome::peer_send(&id, &mut peer, Pong.to_bytes());
+
+        assert!(*rx_shutdown.borrow());
+    }
+
+    #[test]
+    fn jitter_within_bounds() {
+        let dur = Duration::from_secs(100);
+
+        assert_eq!(jitter(dur, 0), dur);
+
+        for _ in 0..100 {
+            let j = jitter(dur, 10);
+
+            assert!(j >= Duration::from_secs(90) && j <= Duration::from_secs(110));
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_from_any_listener() {
+        let listeners = vec![
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+            TcpListener::bind("127.0.0.1:0").await.unwrap(),
+        ];
+
+        for listener in &listeners {
+            let client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+
+            let (_, addr) = accept(&listeners).await.unwrap();
+
+            assert_eq!(addr, client.local_addr().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_both_families() {
+        let v4 = bind("0.0.0.0:0", 0, Duration::from_millis(10))
+            .await
+            .unwrap();
+        let port = v4.local_addr().unwrap().port();
+
+        let v6 = bind(&format!("[::]:{}", port), 0, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert_eq!(v6.local_addr().unwrap().port(), port);
+    }
+
+    #[tokio::test]
+    async fn bind_retries_until_available() {
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = held.local_addr().unwrap().to_string();
+
+        assert!(bind(&addr, 0, Duration::from_millis(10)).await.is_err());
+
+        let release = tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            drop(held);
         });
+
+        let listener = bind(&addr, 10, Duration::from_millis(10)).await.unwrap();
+
+        assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+
+        release.await.unwrap();
     }
-}
 
-impl Shared {
-    pub fn new(tx: mpsc::UnboundedSender<Message>) -> Self {
-        Self {
-            cache: RwLock::new(Cache::new()),
-            tx,
+    #[tokio::test]
+    async fn shutdown_bounded_by_grace() {
+        let mut lrthrome = spawn_server(Sources::new(), 1, |l| {
+            l.shutdown_grace(Duration::from_millis(50));
+        })
+        .await;
+
+        let (_, mut rx_bytes, rx_shutdown) = connect(&mut lrthrome, "127.0.0.1:27015");
+
+        // The peer never disconnects, so shutdown gives up once the grace elapses.
+        let started = Instant::now();
+
+        lrthrome.shutdown().await;
+
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(*rx_shutdown.borrow());
+
+        let resp = rx_bytes.recv().await.unwrap();
+
+        assert_eq!(resp[1], Variant::ResponseError as u8);
+        assert_eq!(resp[2], LrthromeError::ShuttingDown.code());
+    }
+
+    #[tokio::test]
+    async fn temper_runtime_drops_within_runtime() {
+        let runtime = TemperRuntime(Some(
+            runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .build()
+                .unwrap(),
+        ));
+
+        assert_eq!(runtime.spawn(async { 1 }).await.unwrap(), 1);
+
+        // Dropping a runtime outright from an async context panics.
+        drop(runtime);
+    }
+
+    #[tokio::test]
+    async fn last_match_reused_until_tempered() {
+        use crate::config::Mode;
+        use crate::sources::InMemory;
+
+        let tree = |cidr: &'static str| async move {
+            let mut sources = Sources::new();
+
+            sources.register(Box::new(InMemory::new(
+                vec![cidr.parse().unwrap()],
+                Mode::Deny,
+            )));
+
+            Arc::new(
+                Cache::temper(&sources, &Metrics::default(), None, None)
+                    .await
+                    .unwrap()
+                    .unwrap(),
+            )
+        };
+
+        let (tx, _rx) = mpsc::channel(16);
+        let (tx_shutdown, _rx_shutdown) = watch::channel(false);
+        let (tx_bytes, _rx_bytes) = mpsc::channel(16);
+
+        let shared = Shared::new(tx, Arc::default());
+        let mut peer = PeerRegistry::new(tx_shutdown, tx_bytes, Arc::new(Window::new(1)), 0);
+
+        let ip = "10.0.0.1".parse().unwrap();
+
+        shared.cache.store(tree("10.0.0.0/8").await);
+
+        let found = Lrthrome::lookup(&shared, &mut peer, ip, false, None).longest_match;
+
+        assert_eq!(found, Some(("10.0.0.0".parse().unwrap(), 8)));
+
+        // Swapped without a generation change, the last match is served regardless.
+        shared.cache.store(tree("10.1.0.0/16").await);
+
+        assert_eq!(
+            Lrthrome::lookup(&shared, &mut peer, ip, false, None).longest_match,
+            found
+        );
+
+        shared.generation.fetch_add(1, Ordering::AcqRel);
+
+        assert_eq!(
+            Lrthrome::lookup(&shared, &mut peer, ip, false, None).longest_match,
+            None
+        );
+    }
+
+    #[test]
+    fn ratelimited_by_identity() {
+        let one = NonZeroU32::new(1).unwrap();
+
+        let mut ratelimiter = KeyedRateLimiter::new(one, Duration::from_secs(5));
+        let mut identity_limiters = HashMap::new();
+
+        identity_limiters.insert(
+            "partner".to_string(),
+            DirectRateLimiter::new(NonZeroU32::new(2).unwrap(), Duration::from_secs(5)),
+        );
+
+        let registry = || {
+            let (tx_shutdown, _rx_shutdown) = watch::channel(false);
+            let (tx_bytes, _rx_bytes) = mpsc::channel(16);
+
+            PeerRegistry::new(tx_shutdown, tx_bytes, Arc::new(Window::new(1)), 0)
+        };
+
+        let mut anonymous = registry();
+        let mut identified = registry();
+
+        identified.identity = Some("partner".to_string());
+
+        // Behind the same address, as peers of a NAT are.
+        let id = PeerId::new("10.0.0.1:27015".parse().unwrap());
+
+        let mut limited = |peer: &mut PeerRegistry| {
+            Lrthrome::ratelimited(
+                &mut ratelimiter,
+                &mut identity_limiters,
+                Duration::from_secs(5),
+                &id,
+                peer,
+            )
+        };
+
+        let retry_after = (0..100).find_map(|_| limited(&mut anonymous)).unwrap();
+
+        // Within the window, as a single request is allowed per 5 seconds.
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(5));
+
+        // The identity's limit applies in place of the exhausted address's.
+        assert!(limited(&mut identified).is_none());
+        assert!((0..100).any(|_| limited(&mut identified).is_some()));
+
+        let mut exempt = registry();
+
+        exempt.exempt = true;
+
+        assert!((0..100).all(|_| limited(&mut exempt).is_none()));
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_swap() {
+        use crate::config::Mode;
+        use crate::sources::InMemory;
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            Mode::Deny,
+        )));
+
+        let (tx, _rx) = mpsc::channel(16);
+
+        let shared = Shared::new(tx, Arc::default());
+
+        shared.swap(
+            Cache::temper(&sources, &Metrics::default(), None, None)
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        let snapshot = shared.snapshot();
+
+        shared.swap(Cache::new());
+
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert!(snapshot.longest_match(ip).is_some());
+        assert!(shared.snapshot().longest_match(ip).is_none());
+    }
+
+    #[tokio::test]
+    async fn shrunk_tree_retained() {
+        use crate::config::Mode;
+        use crate::sources::InMemory;
+
+        let cidrs = |c: &[&str]| c.iter().map(|c| c.parse().unwrap()).collect::<Vec<_>>();
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(cidrs(&["10.0.0.0/8"]), Mode::Deny)));
+
+        let mut lrthrome = spawn_server(sources, 1, |_| {}).await;
+
+        let mut current = Sources::new();
+
+        current.register(Box::new(InMemory::new(
+            cidrs(&["10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16"]),
+            Mode::Deny,
+        )));
+
+        lrthrome.shared.swap(
+            Cache::temper(&current, &Metrics::default(), None, None)
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        // A third of the current tree is refused, without failing the temper.
+        lrthrome.temper_cache().await.unwrap();
+
+        assert_eq!(lrthrome.shared.snapshot().len(), 3);
+        assert!(!lrthrome.shared.tempering.load(Ordering::Acquire));
+
+        // Forced by an operator, only the next temper.
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+
+        lrthrome.process_admin(Command::ForceTemper(tx)).await;
+
+        match lrthrome.rx.recv().await {
+            Some(Message::Tempered(tempered)) => lrthrome.finish_temper(*tempered).unwrap(),
+            _ => panic!("Temper did not complete"),
         }
+
+        assert_eq!(lrthrome.shared.snapshot().len(), 1);
+        assert!(!lrthrome.force_admit);
+
+        lrthrome.shared.swap(
+            Cache::temper(&current, &Metrics::default(), None, None)
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+
+        lrthrome.sources.expire();
+        lrthrome.temper_cache().await.unwrap();
+
+        assert_eq!(lrthrome.shared.snapshot().len(), 3);
+
+        lrthrome.min_tree_percent(0);
+        lrthrome.temper_cache().await.unwrap();
+
+        assert_eq!(lrthrome.shared.snapshot().len(), 1);
     }
-}
 
-impl PeerRegistry {
-    pub fn new(tx_shutdown: watch::Sender<bool>, tx_bytes: mpsc::UnboundedSender<Bytes>) -> Self {
-        Self {
-            last_request: Instant::now(),
-            tx_shutdown,
-            tx_bytes,
+    #[tokio::test]
+    async fn connection_limits() {
+        let mut lrthrome = spawn_server(Sources::new(), 1, |_| {}).await;
+
+        connect(&mut lrthrome, "10.0.0.1:1000");
+        connect(&mut lrthrome, "10.0.0.1:1001");
+        connect(&mut lrthrome, "10.0.0.2:1000");
+
+        let id = PeerId::new("10.0.0.1:1002".parse().unwrap());
+        let other = PeerId::new("10.0.0.3:1000".parse().unwrap());
+
+        assert_eq!(lrthrome.connection_limit(&id), None);
+
+        lrthrome.max_connections_per_ip(2);
+
+        assert_eq!(lrthrome.connection_limit(&id), Some(("per_ip", 2)));
+        assert_eq!(lrthrome.connection_limit(&other), None);
+
+        lrthrome.max_connections(3);
+
+        assert_eq!(lrthrome.connection_limit(&other), Some(("global", 3)));
+    }
+
+    #[tokio::test]
+    async fn reload_config() {
+        let mut lrthrome = spawn_server(Sources::new(), 1, |_| {}).await;
+
+        // Retained entirely when unable to load.
+        lrthrome.config_path("missing.toml").reload_config();
+
+        assert_eq!(lrthrome.rate_limit.get(), 1);
+        assert!(lrthrome.sources.sources().is_empty());
+
+        let path =
+            std::env::temp_dir().join(format!("lrthrome-reload-{}.toml", std::process::id()));
+
+        std::fs::write(
+            &path,
+            include_str!("../config.toml.example").replace(
+                "remotes = [\"\"]",
+                "remotes = [\"https://example.com/a.netset\"]",
+            ),
+        )
+        .unwrap();
+
+        // Timers are re-armed upon a TTL change.
+        lrthrome.cache_ttl(0).config_path(&path).reload_config();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(lrthrome.rate_limit.get(), 100);
+        assert_eq!(lrthrome.banner, "Lrthrome | Glub Glub");
+        assert_eq!(lrthrome.cache_ttl, 86400);
+        assert_eq!(lrthrome.peer_ttl, 15);
+        assert_eq!(lrthrome.timers.len(), 2);
+        assert!(!lrthrome.sources.sources().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ban_upon_violations() {
+        let mut lrthrome = spawn_server(Sources::new(), 1, |_| {}).await;
+
+        let id = PeerId::new("10.0.0.1:1000".parse().unwrap());
+        let other = PeerId::new("10.0.0.1:1001".parse().unwrap());
+
+        // Disabled by default.
+        for _ in 0..10 {
+            lrthrome.record_violation(&id);
         }
+
+        assert!(!lrthrome.bans.contains(id.connection_key()));
+
+        lrthrome.ban_threshold(3, Duration::from_secs(60), Duration::from_secs(60));
+
+        // Counted per address, across its connections.
+        lrthrome.record_violation(&id);
+        lrthrome.record_violation(&other);
+
+        assert!(!lrthrome.bans.contains(id.connection_key()));
+
+        lrthrome.record_violation(&id);
+
+        assert!(lrthrome.bans.contains(id.connection_key()));
+        assert!(lrthrome.violations.is_empty());
     }
-}
 
-impl Peer {
-    pub fn new(
-        addr: SocketAddr,
-        stream: TcpStream,
-        rx_shutdown: watch::Receiver<bool>,
-        rx_bytes: mpsc::UnboundedReceiver<Bytes>,
-    ) -> Self {
-        Self {
-            addr,
-            frame: BytesCodec::new().framed(stream),
+    #[tokio::test]
+    async fn version_pinned_per_peer() {
+        use crate::protocol::PROTOCOL_VERSION;
+
+        let mut lrthrome = spawn_server(Sources::new(), 1, |_| {}).await;
+
+        let (id, mut rx_bytes, _rx_shutdown) = connect(&mut lrthrome, "10.0.0.1:1000");
+
+        let ping = || Frame {
+            header: Header::new(Variant::Ping),
+            payload: Bytes::new(),
+        };
+
+        assert!(lrthrome.process_frame(id, ping()).await.is_ok());
+        assert_eq!(lrthrome.peers[&id].version, Some(PROTOCOL_VERSION));
+
+        // Established, followed by the pong.
+        rx_bytes.recv().await.unwrap();
+        assert_eq!(rx_bytes.recv().await.unwrap()[1], Variant::Pong as u8);
+
+        // Negotiated otherwise, the same frame is refused.
+        lrthrome.peers.get_mut(&id).unwrap().version = Some(PROTOCOL_VERSION + 1);
+
+        match lrthrome.process_frame(id, ping()).await {
+            Err(FrameError {
+                error: LrthromeError::VersionMismatch { received, .. },
+                ..
+            }) => assert_eq!(received, PROTOCOL_VERSION),
+            _ => panic!("Frame of another version was not refused"),
+        }
+    }
+
+    #[tokio::test]
+    async fn health_skips_limits() {
+        let mut lrthrome = spawn_server(Sources::new(), 1, |l| {
+            l.peer_ttl(1);
+        })
+        .await;
+
+        let (id, mut rx_bytes, rx_shutdown) = connect(&mut lrthrome, "10.0.0.1:1000");
+
+        let peer = lrthrome.peers.get_mut(&id).unwrap();
+
+        peer.established = true;
+        peer.last_request = Instant::now() - Duration::from_secs(5);
+
+        let health = || Frame {
+            header: Header::new(Variant::Health),
+            payload: Bytes::new(),
+        };
+
+        // Well past the rate limit of 1.
+        for _ in 0..5 {
+            assert!(lrthrome.process_frame(id, health()).await.is_ok());
+            assert_eq!(
+                rx_bytes.recv().await.unwrap()[1],
+                Variant::ResponseHealth as u8
+            );
+        }
+
+        lrthrome.sweep_peers().unwrap();
+
+        assert!(!*rx_shutdown.borrow());
+
+        // Any other frame subjects the peer to sweeping from then on.
+        let ping = Frame {
+            header: Header::new(Variant::Ping),
+            payload: Bytes::new(),
+        };
+
+        assert!(lrthrome.process_frame(id, ping).await.is_ok());
+        assert_eq!(lrthrome.peers[&id].health_only, Some(false));
+
+        lrthrome.peers.get_mut(&id).unwrap().last_request = Instant::now() - Duration::from_secs(5);
+        lrthrome.sweep_peers().unwrap();
+
+        assert!(*rx_shutdown.borrow());
+    }
+
+    #[tokio::test]
+    async fn temper_in_background() {
+        use crate::config::Mode;
+        use crate::sources::InMemory;
+
+        let cidrs = |c: &[&str]| c.iter().map(|c| c.parse().unwrap()).collect::<Vec<_>>();
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(cidrs(&["10.0.0.0/8"]), Mode::Deny)));
+
+        let mut lrthrome = spawn_server(sources, 1, |_| {}).await;
+
+        lrthrome.spawn_temper();
+        lrthrome.spawn_temper();
+
+        // Skipped while the first is in progress, neither swapped in until handled.
+        assert_eq!(lrthrome.shared.metrics.temper_skipped.get(), 1);
+        assert_eq!(lrthrome.shared.snapshot().len(), 0);
+
+        let mut reloaded = Sources::new();
+
+        reloaded.register(Box::new(InMemory::new(
+            cidrs(&["10.0.0.0/8", "172.16.0.0/12"]),
+            Mode::Deny,
+        )));
+
+        lrthrome.sources = Arc::new(reloaded);
+
+        // The temper of the sources since reloaded is discarded, tempering again.
+        for _ in 0..2 {
+            match lrthrome.rx.recv().await {
+                Some(Message::Tempered(tempered)) => lrthrome.finish_temper(*tempered).unwrap(),
+                _ => panic!("Temper did not complete"),
+            }
+        }
+
+        assert_eq!(lrthrome.shared.snapshot().len(), 2);
+        assert!(!lrthrome.shared.tempering.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn unknown_sources_refused() {
+        use crate::config::Mode;
+        use crate::sources::InMemory;
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(Vec::new(), Mode::Deny).named("tor")));
+
+        let mut lrthrome = spawn_server(sources, 10, |_| {}).await;
+
+        let (id, mut rx_bytes, _rx_shutdown) = connect(&mut lrthrome, "10.0.0.1:1000");
+
+        lrthrome.peers.get_mut(&id).unwrap().established = true;
+
+        let request = |names: &str| {
+            let mut payload = vec![0x01, 0x00, 0x00, 0x0a, 0x01];
+
+            payload.extend_from_slice(b"sources\0");
+            payload.extend_from_slice(names.as_bytes());
+            payload.push(0);
+
+            Frame {
+                header: Header::new(Variant::Request),
+                payload: payload.into(),
+            }
+        };
+
+        assert!(lrthrome.process_frame(id, request("tor")).await.is_ok());
+        assert_eq!(
+            rx_bytes.recv().await.unwrap()[1],
+            Variant::ResponseOkNotFound as u8
+        );
+
+        match lrthrome.process_frame(id, request("tor,tr")).await {
+            Err(FrameError {
+                error: LrthromeError::UnknownSource(name),
+                ip_address: Some(_),
+            }) => assert_eq!(name, "tr"),
+            _ => panic!("Unknown source was disregarded"),
+        }
+
+        let mut payload = "2001:db8::1"
+            .parse::<std::net::Ipv6Addr>()
+            .unwrap()
+            .octets()
+            .to_vec();
+
+        payload.push(0x01);
+        payload.extend_from_slice(b"sources\0tr\0");
+
+        let frame = Frame {
+            header: Header::new(Variant::RequestV6),
+            payload: payload.into(),
+        };
+
+        match lrthrome.process_frame(id, frame).await {
+            Err(FrameError {
+                error: LrthromeError::UnknownSource(_),
+                ip_address: Some(ip_address),
+            }) => assert_eq!(ip_address, "2001:db8::1".parse::<IpAddr>().unwrap()),
+            _ => panic!("IPv6 request error was not attributed to its address"),
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_require_identity() {
+        let mut lrthrome = spawn_server(Sources::new(), 10, |_| {}).await;
+
+        let (id, mut rx_bytes, _rx_shutdown) = connect(&mut lrthrome, "10.0.0.1:1000");
+
+        lrthrome.peers.get_mut(&id).unwrap().established = true;
+
+        let stats = || Frame {
+            header: Header::new(Variant::RequestStats),
+            payload: Bytes::new(),
+        };
+
+        match lrthrome.process_frame(id, stats()).await {
+            Err(FrameError {
+                error: LrthromeError::Unauthorized,
+                ..
+            }) => {}
+            _ => panic!("Stats were served to an unidentified peer"),
+        }
+
+        lrthrome.shared.metrics.requests.get("found").add(3);
+        lrthrome.shared.metrics.requests.get("not_found").inc();
+        lrthrome.peers.get_mut(&id).unwrap().identity = Some("operator".to_string());
+
+        assert!(lrthrome.process_frame(id, stats()).await.is_ok());
+
+        let resp = rx_bytes.recv().await.unwrap();
+
+        assert_eq!(resp[1], Variant::ResponseStats as u8);
+        // Peers, followed by the requests served.
+        assert_eq!(&resp[2..6], &1u32.to_le_bytes());
+        assert_eq!(&resp[6..14], &4u64.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn peer_drains_before_shutdown() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+
+        let (tx, _rx) = mpsc::channel(16);
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let (tx_bytes, rx_bytes) = mpsc::channel(16);
+
+        // Both are ready by the time the peer is polled,
+        // the response must still be written out before the stream drops.
+        tx_bytes
+            .send(Bytes::from_static(b"farewell"))
+            .await
+            .unwrap();
+        tx_shutdown.send(true).unwrap();
+
+        Peer::new(
+            PeerId::new(addr),
+            stream,
+            LrthromeCodec::default(),
             rx_shutdown,
             rx_bytes,
+            Arc::new(Window::new(1)),
+            None,
+        )
+        .run(
+            Arc::new(Shared::new(tx, Arc::default())),
+            Duration::from_secs(5),
+        )
+        .await;
+
+        let mut received = Vec::new();
+
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, b"farewell");
+    }
+
+    #[tokio::test]
+    async fn drain_bounded_for_unread_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, addr) = listener.accept().await.unwrap();
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let (tx_bytes, rx_bytes) = mpsc::channel(16);
+
+        // Far more than the socket buffers hold, for a client that never reads.
+        for _ in 0..16 {
+            tx_bytes.send(Bytes::from(vec![0; 1 << 20])).await.unwrap();
         }
+
+        tx_shutdown.send(true).unwrap();
+
+        let peer = Peer::new(
+            PeerId::new(addr),
+            stream,
+            LrthromeCodec::default(),
+            rx_shutdown,
+            rx_bytes,
+            Arc::new(Window::new(1)),
+            None,
+        )
+        .run(
+            Arc::new(Shared::new(tx, Arc::default())),
+            Duration::from_millis(100),
+        );
+
+        time::timeout(Duration::from_secs(5), peer)
+            .await
+            .expect("Drain was not bounded");
+
+        assert!(matches!(
+            rx.recv().await,
+            Some(Message::PeerDisconnected(id)) if id == PeerId::new(addr)
+        ));
     }
 }
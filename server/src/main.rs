@@ -19,52 +19,324 @@ extern crate log;
 
 use std::env::var;
 use std::num::NonZeroU32;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 use env_logger::Env;
 
+mod admin;
+mod auth;
+mod ban;
 mod cache;
+mod cidr_set;
+mod codec;
 mod config;
 mod error;
+mod export;
+mod locale;
 mod lrthrome;
+mod metrics;
+mod peer;
 mod protocol;
+mod resolver;
 mod sources;
+mod validate;
 
+use auth::Auth;
+use ban::Bans;
+use cidr_set::CidrSet;
 use config::Config;
+use error::LrthromeError;
+use locale::Locales;
 use lrthrome::Lrthrome;
-use sources::{GeoLite, Remote, Sources};
+use metrics::Metrics;
+use resolver::Resolver;
+use sources::Sources;
+use validate::Report;
+
+/// Exit codes, as per sysexits.h, so a supervisor can tell
+/// an intentional stop from a crash worth restarting, from a misconfiguration that is not.
+mod exit {
+    /// Shut down upon request.
+    pub const OK: i32 = 0;
+
+    /// Invalid command line arguments.
+    pub const USAGE: i32 = 64;
+
+    /// A validated source does not look sane.
+    pub const DATAERR: i32 = 65;
+
+    /// A validated source could not be read.
+    pub const UNAVAILABLE: i32 = 69;
+
+    /// Shut down upon request with the tree never tempered, nothing was served off a complete tree.
+    pub const DEGRADED: i32 = 75;
+
+    /// Failed while serving.
+    pub const SOFTWARE: i32 = 70;
+
+    /// Unable to start, due to the config or binding its addresses.
+    pub const CONFIG: i32 = 78;
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
     let el_env = Env::default().filter_or("LRTHROME_LOG_LEVEL", "info");
 
     env_logger::init_from_env(el_env);
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.first().map(String::as_str) == Some("validate") {
+        process::exit(validate(&args[1..]).await);
+    }
+
+    let mut lrthrome = match setup().await {
+        Ok(lrthrome) => lrthrome,
+        Err(e) => {
+            error!("Unable to start: {}", e);
+
+            process::exit(exit::CONFIG);
+        }
+    };
+
+    info!("Lrthrome started");
+
+    let result = lrthrome.up().await;
+    let tempered = lrthrome.tempered();
+
+    let code = match result {
+        Ok(_) if tempered => exit::OK,
+        Ok(_) => {
+            warn!("Shutting down without the tree ever tempered");
+
+            exit::DEGRADED
+        }
+        Err(e) if tempered => {
+            error!("Lrthrome failed: {}", e);
+
+            exit::SOFTWARE
+        }
+        Err(e) => {
+            error!("Lrthrome failed before the tree was tempered: {}", e);
+
+            exit::SOFTWARE
+        }
+    };
+
+    info!("Lrthrome shutting down");
+
+    process::exit(code);
+}
+
+/// `validate [source...] [--json]`, reporting on sources' output without starting the server.
+///
+/// Sources are named as they are logged, every usable source of the config if none are named.
+/// URLs not configured are validated as a remote with the configured fetch options.
+async fn validate(args: &[String]) -> i32 {
+    let json = args.iter().any(|a| a == "--json");
+
+    let names = args
+        .iter()
+        .filter(|a| !a.starts_with("--"))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    let config_loc = var("LRTHROME_CONFIG").unwrap_or_else(|_| "config.toml".into());
+
+    let mut config = match Config::load(&config_loc).and_then(|c| c.validate().map(|_| c)) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Unable to load {}: {}", config_loc, e);
+
+            return exit::CONFIG;
+        }
+    };
+
+    validate::configure_urls(&mut config.sources, names.iter().copied());
+
+    // A tripped or failing breaker would report its last good output, rather than the source's.
+    config.sources.breaker_threshold = 0;
+
+    let sources = match Sources::from_config(config.sources, Arc::default()) {
+        Ok(sources) => sources,
+        Err(e) => {
+            error!("Unable to configure sources: {}", e);
+
+            return exit::CONFIG;
+        }
+    };
+
+    if let Some(unknown) = names
+        .iter()
+        .find(|n| !sources.sources().iter().any(|s| s.name() == **n))
+    {
+        eprintln!("Unknown source {}, neither configured nor a URL", unknown);
+        eprintln!("Usage: lrthrome validate [source...] [--json]");
+
+        return exit::USAGE;
+    }
+
+    let mut code = exit::OK;
+
+    for source in sources
+        .sources()
+        .iter()
+        .filter(|s| names.contains(&s.name()) || (names.is_empty() && s.usable()))
+    {
+        let report = match Report::read(source.as_ref(), &sources).await {
+            Ok(report) => report,
+            Err(e) => {
+                error!("Unable to read {}: {}", source.name(), e);
+
+                code = exit::UNAVAILABLE;
+
+                continue;
+            }
+        };
+
+        if json {
+            println!("{}", report.to_json(source.name()));
+        } else {
+            print!("{}", report.to_text(source.name()));
+        }
+
+        if !report.sane() && code == exit::OK {
+            code = exit::DATAERR;
+        }
+    }
+
+    code
+}
+
+/// Build the server from the config, binding its listeners.
+async fn setup() -> Result<Lrthrome, Box<dyn std::error::Error>> {
     let config_loc = var("LRTHROME_CONFIG").unwrap_or_else(|_| "config.toml".into());
 
-    let config: Config = toml::from_slice(&std::fs::read(config_loc)?)?;
+    let config = Config::load(&config_loc)?;
+
+    config.validate()?;
 
-    let mut sources = Sources::new();
+    let metrics = Arc::new(Metrics::default());
 
-    sources.register(Box::new(Remote::new(config.sources.remotes)));
-    sources.register(Box::new(GeoLite::new(config.sources.geolite)));
+    let allow_empty_sources = config.sources.allow_empty_sources;
+    let sources = Sources::from_config(config.sources, metrics.clone())?;
+
+    let bind_retries = config.general.bind_retries;
+    let bind_retry_interval = Duration::from_secs(config.general.bind_retry_secs as u64);
+
+    let mut listeners = Vec::with_capacity(config.general.bind_address.len());
+
+    for address in &config.general.bind_address {
+        listeners.push(lrthrome::bind(address, bind_retries, bind_retry_interval).await?);
+    }
+
+    match sources.usable() {
+        0 if allow_empty_sources => {
+            warn!("No usable sources are configured, every lookup will be not found");
+        }
+        0 => return Err(LrthromeError::NoSources.into()),
+        usable => info!(
+            "Registered {} sources, {} of which are usable",
+            sources.sources().len(),
+            usable
+        ),
+    }
+
+    let ratelimit_exempt = CidrSet::parse(&config.general.ratelimit_exempt)?;
+
+    if !ratelimit_exempt.is_empty() {
+        info!(
+            "Exempting {} entries from rate limits",
+            config.general.ratelimit_exempt.len()
+        );
+    }
 
     let mut lrthrome = Lrthrome::new(
-        config.general.bind_address,
+        listeners,
         sources,
         NonZeroU32::new(config.general.rate_limit).unwrap(),
-    )
-    .await?;
+        metrics,
+    )?;
 
     lrthrome
+        .config_path(config_loc)
+        .bind_address(config.general.bind_address)
         .cache_ttl(config.general.cache_ttl)
+        .cache_jitter(config.general.cache_jitter)
         .peer_ttl(config.general.peer_ttl)
-        .banner(config.general.banner);
+        .max_in_flight(config.general.max_in_flight as usize)
+        .max_queued_responses(config.general.max_queued_responses)
+        .max_cstring_len(config.general.max_cstring_len)
+        .max_frame_bytes(config.general.max_frame_bytes)
+        .max_meta_count(config.general.max_meta_count)
+        .max_batch(config.general.max_batch)
+        .banner(config.general.banner)
+        .omit_banner(config.general.omit_banner)
+        .locales(Locales::new(config.locales))
+        .defer_established(config.general.defer_established)
+        .frame_checksums(config.general.frame_checksums)
+        .rate_limit_grace(config.general.rate_limit_grace)
+        .ratelimit_action(config.general.ratelimit_action)
+        .ratelimit_exempt(ratelimit_exempt)
+        .ban_threshold(
+            config.general.ban_threshold,
+            Duration::from_secs(config.general.ban_window_secs),
+            Duration::from_secs(config.general.ban_duration_secs),
+        )
+        .rate_limit_window(Duration::from_secs(
+            config.general.rate_limit_window_secs.get() as u64,
+        ))
+        .min_tree_percent(config.general.min_tree_percent)
+        .max_connections(config.general.max_connections)
+        .max_connections_per_ip(config.general.max_connections_per_ip)
+        .shutdown_grace(Duration::from_secs(
+            config.general.shutdown_grace_secs as u64,
+        ));
 
-    info!("Lrthrome started");
+    if let Some(rate) = config.general.egress_rate {
+        lrthrome.egress(
+            rate,
+            config.general.egress_burst.unwrap_or_else(|| rate.get()),
+        );
+    }
 
-    lrthrome.up().await?;
+    if let Some(threads) = config.general.temper_threads {
+        lrthrome.temper_threads(threads)?;
+    }
 
-    info!("Lrthrome shutting down");
+    if let Some(path) = config.general.cache_persist_path {
+        lrthrome.cache_persist_path(path);
+    }
+
+    if let Some(linger) = config.general.linger_secs {
+        lrthrome.linger(Duration::from_secs(linger as u64));
+    }
+
+    if let Some(resolver) = config.resolver {
+        lrthrome.resolver(Resolver::new(resolver));
+    }
+
+    if let Some(auth) = config.auth {
+        lrthrome.auth(Auth::load(auth.token_file)?);
+    }
+
+    if let Some(metrics) = config.metrics {
+        lrthrome.metrics_listener(
+            lrthrome::bind(&metrics.bind_address, bind_retries, bind_retry_interval).await?,
+        );
+    }
+
+    if let Some(admin) = config.admin {
+        lrthrome
+            .admin(lrthrome::bind(&admin.bind_address, bind_retries, bind_retry_interval).await?)
+            .ban_duration(Duration::from_secs(admin.ban_secs));
+
+        if let Some(ban_file) = admin.ban_file {
+            lrthrome.bans(Bans::load(ban_file)?);
+        }
+    }
 
-    Ok(())
+    Ok(lrthrome)
 }
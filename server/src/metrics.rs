@@ -0,0 +1,520 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::error::LrthromeResult;
+
+/// Upper bounds in seconds of duration histogram buckets.
+///
+/// Skewed towards the microseconds a single lookup takes.
+const DURATION_BUCKETS: [f64; 11] = [
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
+/// Registry of operational metrics.
+///
+/// Every metric is a plain atomic, shared between the server and its peers without locking.
+/// Labeled metrics only lock to look up their label.
+#[derive(Default)]
+pub struct Metrics {
+    /// Cache ticks skipped, as a temper was in progress or overran its interval.
+    pub temper_skipped: Counter,
+
+    /// Time spent processing a frame, labeled by variant.
+    pub frame_duration: Family<Histogram>,
+
+    /// Remote fetches served, labeled by URL.
+    pub remote_fetches: Family<Counter>,
+
+    /// Remote fetches failed, labeled by URL.
+    pub remote_fetch_failures: Family<Counter>,
+
+    /// Failed fetches of sources behind a circuit breaker, labeled by source.
+    pub source_failures: Family<Counter>,
+
+    /// Whether a source's circuit breaker is open, labeled by source.
+    pub source_breaker_open: Family<Gauge>,
+
+    /// Lookups compared against shadow sources, labeled by outcome.
+    pub shadow_lookups: Family<Counter>,
+
+    /// Sources fetched in full by a temper, labeled by source.
+    pub source_tempers: Family<Counter>,
+
+    /// Sources that failed to fetch, or failed mid-stream, during a temper, labeled by source.
+    pub source_temper_failures: Family<Counter>,
+
+    /// Milliseconds the last temper took until a source began yielding entries, labeled by source.
+    pub source_fetch_millis: Family<Gauge>,
+
+    /// Milliseconds the last temper spent streaming & parsing a source's entries, labeled by source.
+    pub source_parse_millis: Family<Gauge>,
+
+    /// Entries a source inserted into the tree in the last temper, labeled by source.
+    pub source_entries: Family<Gauge>,
+
+    /// Mask lengths of the prefixes requests matched.
+    pub matched_mask_len: MaskHistogram,
+
+    /// Whether the last temper yielded no entries at all, every lookup being not found.
+    pub degraded: Gauge,
+
+    /// Addresses looked up, labeled by outcome, either `found` or `not_found`.
+    pub requests: Family<Counter>,
+
+    /// Frames refused as the peer exceeded its rate limit.
+    pub ratelimited: Counter,
+
+    /// Connected peers.
+    pub peers: Gauge,
+
+    /// Connections refused upon accept, labeled by the limit reached, either `global` or `per_ip`.
+    pub connections_refused: Family<Counter>,
+
+    /// Milliseconds the last temper took, from checking sources for updates to the tree being built.
+    pub temper_millis: Gauge,
+
+    /// Prefixes within the tree, across both modes & families.
+    pub tree_entries: Gauge,
+}
+
+impl Metrics {
+    /// Render in Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        self.temper_skipped
+            .render(&mut out, "lrthrome_temper_skipped_total");
+
+        self.frame_duration
+            .render(&mut out, "lrthrome_frame_duration_seconds", "variant");
+
+        self.remote_fetches
+            .render(&mut out, "lrthrome_remote_fetches_total", "url");
+
+        self.remote_fetch_failures
+            .render(&mut out, "lrthrome_remote_fetch_failures_total", "url");
+
+        self.source_failures
+            .render(&mut out, "lrthrome_source_failures_total", "source");
+
+        self.source_breaker_open
+            .render(&mut out, "lrthrome_source_breaker_open", "source");
+
+        self.shadow_lookups
+            .render(&mut out, "lrthrome_shadow_lookups_total", "outcome");
+
+        self.source_tempers
+            .render(&mut out, "lrthrome_source_tempers_total", "source");
+
+        self.source_temper_failures.render(
+            &mut out,
+            "lrthrome_source_temper_failures_total",
+            "source",
+        );
+
+        self.source_fetch_millis
+            .render(&mut out, "lrthrome_source_fetch_milliseconds", "source");
+
+        self.source_parse_millis
+            .render(&mut out, "lrthrome_source_parse_milliseconds", "source");
+
+        self.source_entries
+            .render(&mut out, "lrthrome_source_entries", "source");
+
+        self.matched_mask_len
+            .render(&mut out, "lrthrome_matched_mask_length");
+
+        self.degraded.render(&mut out, "lrthrome_degraded");
+
+        self.requests
+            .render(&mut out, "lrthrome_requests_total", "outcome");
+
+        self.ratelimited
+            .render(&mut out, "lrthrome_ratelimited_total");
+
+        self.peers.render(&mut out, "lrthrome_peers");
+
+        self.connections_refused
+            .render(&mut out, "lrthrome_connections_refused_total", "limit");
+
+        self.temper_millis
+            .render(&mut out, "lrthrome_temper_milliseconds");
+
+        self.tree_entries.render(&mut out, "lrthrome_tree_entries");
+
+        out
+    }
+}
+
+/// Longest request head read from a scraper, beyond which the request is answered regardless.
+const MAX_REQUEST_HEAD_LEN: usize = 8192;
+
+/// Serve the metrics over HTTP to scrapers, at `/metrics`.
+///
+/// Rendered straight from the shared registry, without going through the main loop.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    info!("Started serving metrics");
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, addr)) => {
+                let metrics = metrics.clone();
+
+                tokio::spawn(async move {
+                    if let Err(e) = respond(stream, &metrics).await {
+                        debug!("Metrics connection failed (addr = {}): {}", addr, e);
+                    }
+                });
+            }
+            Err(e) => error!("Unable to accept metrics connection: {}", e),
+        }
+    }
+}
+
+/// Answer a single request, closing the connection after.
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> LrthromeResult<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD_LEN {
+        match stream.read(&mut buf).await? {
+            0 => break,
+            n => head.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(line)
+        .unwrap_or_default()
+        .split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let res = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(res.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Monotonically increasing counter.
+#[derive(Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        let _ = writeln!(out, "{} {}", name, self.get());
+    }
+}
+
+/// Value that may go up & down.
+#[derive(Default)]
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub fn set(&self, n: u64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, self.get());
+    }
+}
+
+/// Distribution of durations over `DURATION_BUCKETS`.
+#[derive(Default)]
+pub struct Histogram {
+    /// Observations per bucket, non-cumulative.
+    ///
+    /// The trailing bucket holds observations above every bound.
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+
+    sum_nanos: AtomicU64,
+
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, dur: Duration) {
+        let secs = dur.as_secs_f64();
+
+        let i = DURATION_BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(dur.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+
+        for (i, le) in DURATION_BUCKETS.iter().enumerate() {
+            cumulative += self.buckets[i].load(Ordering::Relaxed);
+
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let labels = labels.trim_end_matches(',');
+
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// Distribution of IPv4 mask lengths, a bucket per length.
+pub struct MaskHistogram {
+    /// Observations per mask length, non-cumulative.
+    buckets: [AtomicU64; 33],
+
+    sum: AtomicU64,
+}
+
+impl Default for MaskHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MaskHistogram {
+    pub fn observe(&self, mask_len: u32) {
+        self.buckets[mask_len.min(32) as usize].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(mask_len as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str) {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        let mut cumulative = 0;
+
+        for (le, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, cumulative);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Metrics of the same name, keyed by a single label value.
+#[derive(Default)]
+pub struct Family<M>(RwLock<BTreeMap<String, Arc<M>>>);
+
+impl<M: Default> Family<M> {
+    /// Metric of the label value, created upon first use.
+    pub fn get(&self, label: &str) -> Arc<M> {
+        if let Some(m) = self.0.read().unwrap().get(label) {
+            return m.clone();
+        }
+
+        self.0
+            .write()
+            .unwrap()
+            .entry(label.to_string())
+            .or_default()
+            .clone()
+    }
+}
+
+impl Family<Counter> {
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        let _ = writeln!(out, "# TYPE {} counter", name);
+
+        for (value, counter) in self.0.read().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape(value),
+                counter.get()
+            );
+        }
+    }
+}
+
+impl Family<Gauge> {
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+
+        for (value, gauge) in self.0.read().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape(value),
+                gauge.get()
+            );
+        }
+    }
+}
+
+impl Family<Histogram> {
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        for (value, histogram) in self.0.read().unwrap().iter() {
+            histogram.render(out, name, &format!("{}=\"{}\",", label, escape(value)));
+        }
+    }
+}
+
+/// Escape a label value, as per the text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn serve_scrapes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let metrics = Arc::new(Metrics::default());
+
+        metrics.requests.get("found").inc();
+
+        tokio::spawn(serve(listener, metrics));
+
+        let scrape = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nHost: lrthrome\r\n\r\n", path).as_bytes())
+                .await
+                .unwrap();
+
+            let mut res = String::new();
+
+            stream.read_to_string(&mut res).await.unwrap();
+
+            res
+        };
+
+        let res = scrape("/metrics").await;
+
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("lrthrome_requests_total{outcome=\"found\"} 1\n"));
+
+        assert!(scrape("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn render_histogram() {
+        let metrics = Metrics::default();
+
+        metrics
+            .frame_duration
+            .get("Request")
+            .observe(Duration::from_micros(20));
+        metrics
+            .frame_duration
+            .get("Request")
+            .observe(Duration::from_secs(2));
+
+        let out = metrics.render();
+
+        assert!(out.contains(
+            "lrthrome_frame_duration_seconds_bucket{variant=\"Request\",le=\"0.00001\"} 0"
+        ));
+        assert!(out.contains(
+            "lrthrome_frame_duration_seconds_bucket{variant=\"Request\",le=\"0.00005\"} 1"
+        ));
+        assert!(
+            out.contains("lrthrome_frame_duration_seconds_bucket{variant=\"Request\",le=\"1\"} 1")
+        );
+        assert!(out
+            .contains("lrthrome_frame_duration_seconds_bucket{variant=\"Request\",le=\"+Inf\"} 2"));
+        assert!(out.contains("lrthrome_frame_duration_seconds_count{variant=\"Request\"} 2"));
+    }
+
+    #[test]
+    fn render_mask_histogram() {
+        let metrics = Metrics::default();
+
+        metrics.matched_mask_len.observe(8);
+        metrics.matched_mask_len.observe(24);
+        metrics.matched_mask_len.observe(32);
+
+        let out = metrics.render();
+
+        assert!(out.contains("lrthrome_matched_mask_length_bucket{le=\"7\"} 0"));
+        assert!(out.contains("lrthrome_matched_mask_length_bucket{le=\"8\"} 1"));
+        assert!(out.contains("lrthrome_matched_mask_length_bucket{le=\"24\"} 2"));
+        assert!(out.contains("lrthrome_matched_mask_length_bucket{le=\"+Inf\"} 3"));
+        assert!(out.contains("lrthrome_matched_mask_length_sum 64"));
+        assert!(out.contains("lrthrome_matched_mask_length_count 3"));
+    }
+}
@@ -0,0 +1,223 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+/// Identity of a connected peer, constructed once upon accept.
+///
+/// Decouples how a peer is keyed, limited and logged from its raw socket address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId {
+    /// Socket address the peer connected from.
+    addr: SocketAddr,
+
+    /// Address the peer is attributed to.
+    ///
+    /// IPv4-mapped IPv6 addresses are normalized to IPv4,
+    /// so a dual-stack listener does not split a single client in two.
+    ip: IpAddr,
+}
+
+impl PeerId {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            ip: normalize(addr.ip()),
+        }
+    }
+
+    /// Key the rate limit is applied against.
+    ///
+    /// Shared between all of the address's connections.
+    pub fn ratelimit_key(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Key connections are counted against.
+    pub fn connection_key(&self) -> IpAddr {
+        self.ip
+    }
+
+    /// Socket address the peer connected from.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// IPv4-mapped IPv6 addresses as IPv4, others as is.
+pub fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6),
+        },
+        v4 => v4,
+    }
+}
+
+/// In-flight window of a peer, shared between its task and the main loop.
+///
+/// Counts frames forwarded but not yet processed, and responses queued but not yet written.
+/// The peer stops reading frames while the window is full,
+/// so a client pipelining requests without reading responses is held back at its socket.
+pub struct Window {
+    /// Number of in-flight frames & responses the peer may have.
+    limit: usize,
+
+    in_flight: AtomicUsize,
+
+    /// Notified upon release, for a paused peer to re-check the window.
+    released: Notify,
+}
+
+impl Window {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_flight: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    /// Account a frame forwarded or response queued.
+    pub fn acquire(&self) {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Account a frame processed or response written.
+    pub fn release(&self) {
+        let _ = self
+            .in_flight
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+
+        self.released.notify_one();
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.in_flight.load(Ordering::Acquire) >= self.limit
+    }
+
+    /// Wait until anything is released from the window.
+    pub async fn released(&self) {
+        self.released.notified().await
+    }
+}
+
+/// Token bucket throttling how fast bytes are written out to a peer.
+///
+/// Writes larger than the bucket are let through, going into debt that is
+/// waited out before the next write.
+pub struct Egress {
+    /// Bytes replenished per second.
+    rate: u32,
+
+    /// Most bytes that may accumulate, written out at once.
+    burst: u32,
+
+    /// Available bytes, negative while in debt.
+    tokens: f64,
+
+    refilled: Instant,
+}
+
+impl Egress {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take bytes to be written out, returning how long to wait before writing them.
+    pub fn take(&mut self, len: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+
+        self.refilled = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.tokens -= len as f64;
+
+        if self.tokens >= 0.0 {
+            return Duration::default();
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.rate as f64)
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.addr)
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn normalize_ipv4_mapped() {
+        let id = PeerId::new("[::ffff:1.2.3.4]:25597".parse().unwrap());
+
+        assert_eq!(id.ratelimit_key(), "1.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(id.to_string(), "[::ffff:1.2.3.4]:25597");
+    }
+
+    #[test]
+    fn egress_throttles_past_burst() {
+        let now = Instant::now();
+
+        let mut egress = Egress::new(100, 50);
+
+        egress.refilled = now;
+
+        assert_eq!(egress.take(50, now), Duration::default());
+        assert_eq!(egress.take(50, now), Duration::from_millis(500));
+
+        // Refilled at the rate, though never past the burst.
+        assert_eq!(
+            egress.take(10, now + Duration::from_secs(2)),
+            Duration::default()
+        );
+        assert_eq!(egress.tokens, 40.0);
+    }
+
+    #[test]
+    fn window_fills_and_releases() {
+        let window = Window::new(2);
+
+        window.acquire();
+        assert!(!window.is_full());
+
+        window.acquire();
+        assert!(window.is_full());
+
+        window.release();
+        assert!(!window.is_full());
+
+        // Releasing an empty window does not underflow.
+        window.release();
+        window.release();
+        window.acquire();
+        assert!(!window.is_full());
+    }
+}
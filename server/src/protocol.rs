@@ -14,27 +14,82 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
 
 use bytes::{BufMut, Bytes, BytesMut};
 
-use nom::bytes::complete::{tag, take_while};
-use nom::combinator::{map, map_res};
-use nom::multi::count;
-use nom::number::complete::{le_u32, le_u8};
+use nom::bytes::complete::take;
+use nom::bytes::complete::{tag, take_while_m_n};
+use nom::combinator::{map, map_res, verify};
+use nom::multi::{count, length_count};
+use nom::number::complete::{le_u16, le_u32, le_u8};
 use nom::sequence::{pair, terminated};
 use nom::IResult;
 
 use crate::error::LrthromeError;
+use crate::resolver::ResolveStatus;
 
+/// Preferred protocol version, responses are sent in.
 pub const PROTOCOL_VERSION: u8 = 1;
 
+/// Oldest protocol version still accepted from peers.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Protocol versions accepted from peers, advertised in `Established`.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u8> = MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+
+/// Default longest string accepted within peer frames, excluding its NUL terminator.
+pub const MAX_CSTRING_LEN: usize = 1024;
+
+/// Default most meta pairs accepted within a request.
+pub const MAX_META_COUNT: u8 = 16;
+
+/// Request meta key to look up disregarding the allowlist, with a value of `1`.
+///
+/// Only permitted to identities configured to, refused otherwise.
+pub const IGNORE_ALLOW_META_KEY: &str = "ignore_allow";
+
+/// `Established` capability flag, set if IPv6 requests are served.
+pub const CAPABILITY_IPV6: u8 = 1 << 0;
+
+/// `Established` capability flag, set if frames may carry a trailing checksum.
+pub const CAPABILITY_CHECKSUM: u8 = 1 << 1;
+
+/// Flag on the variant byte of a frame followed by a CRC-32 of the frame as a `u32`.
+///
+/// Peers opt in by flagging their frames, the server's responses are flagged from then on.
+pub const CHECKSUM_FLAG: u8 = 1 << 7;
+
+/// Length of the trailing checksum of a flagged frame.
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Request meta key to restrict the lookup to matches of the comma separated source names,
+/// such as `sources=https://example.com/tor.netset,bogon`.
+///
+/// Sources are named as they are labeled in metrics. Every source is looked up if absent.
+/// A name matching no source is refused with `UnknownSource`.
+pub const SOURCES_META_KEY: &str = "sources";
+
+/// Request meta key to have a found match also rendered in CIDR notation, with a value of `1`.
+pub const CIDR_META_KEY: &str = "cidr";
+
+/// Request meta key to have a found match followed by the names of the sources listing it,
+/// with a value of `1`.
+pub const MATCHED_SOURCES_META_KEY: &str = "matched_sources";
+
 #[derive(Debug, PartialEq)]
 pub struct ProtocolVersion(u8);
 
+impl ProtocolVersion {
+    pub fn get(&self) -> u8 {
+        self.0
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Header {
     /// Current protocol version.
@@ -45,6 +100,9 @@ pub struct Header {
     /// Message variant to indicate parsing procedure.
     /// Field is repr as u8 in networking.
     pub variant: Variant,
+
+    /// Whether the frame is followed by a checksum, flagged on the variant byte.
+    pub checksum: bool,
 }
 
 /// Message variants for parsing procedure hint.
@@ -61,7 +119,8 @@ pub enum Variant {
 
     /// Optional peer payload to server to identify or authenticate itself.
     ///
-    /// Authentication may grant higher limits in the future.
+    /// Authentication grants the identity's rate limit in place of the address's.
+    /// Responses are signed from then on if the identity has a signing key.
     Identify = 1,
 
     /// Request to check ip address against tree.
@@ -74,29 +133,134 @@ pub enum Variant {
     ResponseOkNotFound = 4,
 
     /// Unsuccessful response.
-    /// This response is considered fatal, and peer should attempt at another time.
+    /// This response is considered fatal, and peer should attempt at another time,
+    /// unless ratelimited with the server configured to only reject the request.
     ResponseError = 5,
+
+    /// Request to resolve a hostname server-side,
+    /// and check each of its addresses against tree.
+    RequestHost = 6,
+
+    /// Response to a hostname request, with a result per resolved address.
+    ResponseHost = 7,
+
+    /// Request to check an IPv6 address against tree.
+    ///
+    /// Only sent by peers once `Established` advertises `CAPABILITY_IPV6`,
+    /// IPv4 requests are unaffected.
+    RequestV6 = 8,
+
+    /// Successful response to an IPv6 request indicating a longest match was found.
+    ResponseOkFoundV6 = 9,
+
+    /// Successful response to an IPv6 request indicating no result.
+    ResponseOkNotFoundV6 = 10,
+
+    /// Request to check whether an entire block is covered by the tree.
+    RequestCovered = 11,
+
+    /// Successful response indicating a prefix covering the entire block was found.
+    ResponseCovered = 12,
+
+    /// Successful response indicating the block is not entirely covered.
+    ResponseNotCovered = 13,
+
+    /// Request to check several IPv4 addresses against tree at once, without meta.
+    ///
+    /// Counts as a single request towards the rate limit.
+    RequestBatch = 14,
+
+    /// Successful response to a batch request, with a result per address in request order.
+    ResponseBatch = 15,
+
+    /// Keepalive from peer, without payload.
+    ///
+    /// Refreshes the peer's time-to-live without a lookup, nor counting towards the rate limit.
+    Ping = 16,
+
+    /// Response to a ping, without payload.
+    Pong = 17,
+
+    /// Liveness probe, such as of a load balancer, without payload.
+    ///
+    /// Answered right away without counting towards the rate limit.
+    /// A connection only ever probing health is never swept upon `peer_ttl`.
+    Health = 18,
+
+    /// Response to a health probe, with the server's status.
+    ResponseHealth = 19,
+
+    /// Request for live server stats, without payload.
+    ///
+    /// Only answered for peers identified through `Identify`, counting towards the rate limit.
+    RequestStats = 20,
+
+    /// Response to a stats request.
+    ResponseStats = 21,
+
+    /// Request for every prefix within the tree containing an IPv4 address,
+    /// rather than only the longest.
+    RequestAll = 22,
+
+    /// Response to a request for every containing prefix.
+    ResponseAll = 23,
+
+    /// Unsuccessful response to an IPv6 request, as `ResponseError` with the address in 16 bytes.
+    ///
+    /// Only sent to peers that sent `RequestV6`, IPv4 requests are answered with `ResponseError`.
+    ResponseErrorV6 = 24,
 }
 
 /// Server public data transmitted to peers.
 /// Peer should save and update this information upon receiving.
 pub struct Established<'a> {
-    /// Rate limit over the span of 5 seconds, allowing burst.
+    /// Rate limit over the span of `rate_limit_window`, allowing burst.
     pub rate_limit: u32,
 
     /// Number of entries within the lookup tree.
     pub tree_size: u32,
 
     /// Cache time-to-live.
-    /// Interval in seconds the cache will be purged and fetched again.
+    /// Interval in seconds the cache will be purged and fetched again, 0 if never.
     pub cache_ttl: u32,
 
     /// Peer time-to-live.
     /// Interval that a peer's connection can stay alive without additional requests.
+    /// 0 if peers never time out.
     pub peer_ttl: u32,
 
     /// Optional banner message
-    pub banner: &'a str,
+    ///
+    /// None if omitted by configuration, in which case the field is absent entirely,
+    /// NUL terminator included, and the data age directly follows the peer TTL.
+    /// Peers must be configured to expect the omission.
+    pub banner: Option<&'a str>,
+
+    /// Seconds since the tree was last successfully tempered,
+    /// `u32::MAX` if it never was.
+    ///
+    /// Appended after the banner, so peers unaware of it are unaffected.
+    pub data_age: u32,
+
+    /// Flags of optional features served, such as `CAPABILITY_IPV6`.
+    ///
+    /// Appended after the data age.
+    pub capabilities: u8,
+
+    /// Range of protocol versions accepted, appended after the capabilities
+    /// as the preferred version, followed by the minimum.
+    ///
+    /// Lets a peer tell whether it is supported, rather than being disconnected upon its first frame.
+    pub versions: RangeInclusive<u8>,
+
+    /// Seconds the rate limit applies over, appended after the versions.
+    pub rate_limit_window: u32,
+
+    /// Unix timestamp in seconds the tree was last successfully tempered, 0 if it never was.
+    ///
+    /// Appended after the rate limit window. Unlike the data age, it stays exact
+    /// for peers caching results across long-lived connections.
+    pub last_tempered_unix: u64,
 }
 
 /// Optional peer request to identify/authenticate.
@@ -111,12 +275,57 @@ pub struct Request<'n> {
     pub ip_address: Ipv4Addr,
 
     /// Number of key value pairs to read
+    #[allow(dead_code)]
     pub meta_count: u8,
 
     /// Key-value pairs
+    ///
+    /// Keys are unique, a request repeating a key is malformed.
+    pub meta: HashMap<&'n str, &'n str>,
+}
+
+/// Request to check an IPv6 address against the tree.
+///
+/// Identical to `Request` but for the address' family.
+pub struct RequestV6<'n> {
+    /// IPv6 address to check the tree for, in network byte order.
+    pub ip_address: Ipv6Addr,
+
+    /// Key-value pairs
+    ///
+    /// Keys are unique, a request repeating a key is malformed.
     pub meta: HashMap<&'n str, &'n str>,
 }
 
+/// Request for every prefix containing the address.
+pub struct RequestAll {
+    /// IP address to check against tree.
+    pub ip_address: Ipv4Addr,
+}
+
+/// Request to check several IPv4 addresses against the tree.
+pub struct RequestBatch {
+    /// IPv4 addresses to check the tree for, preceded by their count as a `u16`.
+    pub ip_addresses: Vec<Ipv4Addr>,
+}
+
+/// Request to resolve a hostname and check its addresses against the tree.
+pub struct RequestHost<'n> {
+    /// Hostname to resolve server-side.
+    pub hostname: &'n str,
+}
+
+/// Request to check whether an entire block is covered by the tree.
+///
+/// Host bits of the prefix are disregarded.
+pub struct RequestCovered {
+    /// Network address of the block.
+    pub prefix: Ipv4Addr,
+
+    /// Mask length of the block, at most 32.
+    pub mask_len: u8,
+}
+
 /// Successful response indicating a longest match was found.
 pub struct ResponseOkFound {
     /// IP address in which the result was found.
@@ -127,16 +336,169 @@ pub struct ResponseOkFound {
 
     /// Prefix mask length.
     pub mask_len: u32,
+
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+
+    /// Append the match in CIDR notation as a string, such as `1.2.3.0/24`,
+    /// for clients that would rather not recombine the prefix & mask length.
+    ///
+    /// Only sent if requested through `CIDR_META_KEY`.
+    pub with_cidr: bool,
+
+    /// Comma separated names of the sources listing the match, as a string following the CIDR if any.
+    ///
+    /// Only sent if requested through `MATCHED_SOURCES_META_KEY`.
+    pub sources: Option<String>,
 }
 
 /// Successful response indicating no result.
 pub struct ResponseOkNotFound {
     /// IP address in which the result was not found.
     pub ip_address: Ipv4Addr,
+
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+}
+
+/// Successful response to an IPv6 request indicating a longest match was found.
+///
+/// Identical to `ResponseOkFound` but for the address' family,
+/// with addresses in network byte order.
+pub struct ResponseOkFoundV6 {
+    pub ip_address: Ipv6Addr,
+
+    pub prefix: Ipv6Addr,
+
+    pub mask_len: u32,
+
+    pub data_age: u32,
+
+    pub with_cidr: bool,
+
+    pub sources: Option<String>,
+}
+
+/// Successful response to an IPv6 request indicating no result.
+pub struct ResponseOkNotFoundV6 {
+    pub ip_address: Ipv6Addr,
+
+    pub data_age: u32,
+}
+
+/// Successful response indicating a prefix covering the entire block was found.
+pub struct ResponseCovered {
+    /// Network address of the block as requested.
+    pub prefix: Ipv4Addr,
+
+    /// Mask length of the block as requested.
+    pub mask_len: u32,
+
+    /// Longest prefix containing the entire block.
+    pub covering_prefix: Ipv4Addr,
+
+    /// Covering prefix mask length, at most the block's.
+    pub covering_mask_len: u32,
+
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+}
+
+/// Successful response indicating the block is not entirely covered.
+///
+/// Parts of the block may still be, as would be found by `Request`.
+pub struct ResponseNotCovered {
+    /// Network address of the block as requested.
+    pub prefix: Ipv4Addr,
+
+    /// Mask length of the block as requested.
+    pub mask_len: u32,
+
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+}
+
+/// Response to a hostname request.
+pub struct ResponseHost<'a> {
+    /// Hostname as requested.
+    pub hostname: &'a str,
+
+    /// Outcome of the resolution.
+    /// Addresses are only present when resolved.
+    pub status: ResolveStatus,
+
+    /// Resolved IPv4 addresses, with their longest match if any.
+    ///
+    /// Truncated to 255 addresses.
+    pub matches: Vec<(Ipv4Addr, Option<(Ipv4Addr, u32)>)>,
+}
+
+/// Response to a ping.
+pub struct Pong;
+
+/// Response to a health probe.
+///
+/// Laid out after the header as `tree_size`, `data_age` & `uptime`, each a little endian `u32`.
+pub struct ResponseHealth {
+    /// Number of entries within the lookup tree.
+    pub tree_size: u32,
+
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+
+    /// Seconds since the server started.
+    pub uptime: u32,
+}
+
+/// Response to a stats request, every field being little endian in the order declared.
+pub struct ResponseStats {
+    /// Connected peers, as a `u32`.
+    pub peers: u32,
+
+    /// Addresses looked up since startup, as a `u64`.
+    pub requests: u64,
+
+    /// Addresses found within the tree, as a `u64`.
+    pub found: u64,
+
+    /// Addresses not found within the tree, as a `u64`.
+    pub not_found: u64,
+
+    /// Number of entries within the lookup tree, as a `u32`.
+    pub tree_size: u32,
+
+    /// Unix timestamp of the last successful temper, 0 if never, as a `u64`.
+    pub last_tempered_unix: u64,
+}
+
+/// Response to a request for every containing prefix.
+pub struct ResponseAll {
+    /// IP address as requested.
+    pub ip_address: Ipv4Addr,
+
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+
+    /// Prefixes containing the address & their mask lengths, longest first.
+    ///
+    /// Preceded by their count as a `u8`, at most 33 as there is one per mask length.
+    pub matches: Vec<(Ipv4Addr, u32)>,
+}
+
+/// Response to a batch request.
+pub struct ResponseBatch {
+    /// Seconds since the tree was last successfully tempered, as in `Established`.
+    pub data_age: u32,
+
+    /// Requested addresses in request order, with their longest match if any.
+    ///
+    /// Laid out as in `ResponseHost`, preceded by their count as a `u16`.
+    pub matches: Vec<(Ipv4Addr, Option<(Ipv4Addr, u32)>)>,
 }
 
 /// Unsuccessful response.
-/// This response is considered fatal, and peer should attempt at another time.
+/// This response is considered fatal, and peer should attempt at another time,
+/// unless ratelimited with the server configured to only reject the request.
 pub struct ResponseError<'a> {
     /// Corresponding error code for the message.
     /// Useful for peer-side handling of error.
@@ -144,15 +506,28 @@ pub struct ResponseError<'a> {
 
     /// Human facing error message.
     pub message: &'a str,
+
+    /// Requested address the error arose from,
+    /// unspecified (0.0.0.0) if not tied to a specific request.
+    ///
+    /// Appended after the message, so peers unaware of it are unaffected.
+    /// An IPv6 address is sent as `ResponseErrorV6`.
+    pub ip_address: IpAddr,
+
+    /// Milliseconds until the peer may retry, 0 if unspecified.
+    ///
+    /// Set for `Ratelimited`, appended after the address.
+    pub retry_after_ms: u32,
 }
 
 impl TryFrom<u8> for ProtocolVersion {
     type Error = LrthromeError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        if value != PROTOCOL_VERSION {
+        if !SUPPORTED_VERSIONS.contains(&value) {
             return Err(LrthromeError::VersionMismatch {
-                expected: PROTOCOL_VERSION,
+                minimum: *SUPPORTED_VERSIONS.start(),
+                preferred: *SUPPORTED_VERSIONS.end(),
                 received: value,
             });
         }
@@ -165,13 +540,16 @@ impl Header {
     pub fn parse(input: &[u8]) -> IResult<&[u8], Header> {
         let (input, protocol_version) = map_res(le_u8, ProtocolVersion::try_from)(input)?;
 
-        let (input, variant) = map_res(le_u8, Variant::try_from)(input)?;
+        let (input, (variant, checksum)) = map_res(le_u8, |b| {
+            Variant::try_from(b & !CHECKSUM_FLAG).map(|v| (v, b & CHECKSUM_FLAG != 0))
+        })(input)?;
 
         Ok((
             input,
             Header {
                 protocol_version,
                 variant,
+                checksum,
             },
         ))
     }
@@ -180,19 +558,64 @@ impl Header {
         Self {
             protocol_version: ProtocolVersion(PROTOCOL_VERSION),
             variant,
+            checksum: false,
         }
     }
 
     pub fn to_bytes(&self) -> BytesMut {
         let mut buf = BytesMut::new();
 
+        let flag = if self.checksum { CHECKSUM_FLAG } else { 0 };
+
         buf.put_u8(self.protocol_version.0);
-        buf.put_u8(self.variant.clone() as u8);
+        buf.put_u8(self.variant.clone() as u8 | flag);
 
         buf
     }
 }
 
+/// CRC-32 (IEEE) of the bytes, as frame checksums are computed.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for b in bytes {
+        crc ^= *b as u32;
+
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+/// Flag the frame as checksummed, without appending the checksum yet.
+///
+/// Flagged before a signature, if any, so the signature covers the header as sent.
+pub fn flag_checksum(frame: Bytes) -> Bytes {
+    let mut buf = BytesMut::from(&frame[..]);
+
+    buf[1] |= CHECKSUM_FLAG;
+
+    buf.freeze()
+}
+
+/// Flag the frame as checksummed, and append the checksum of it.
+///
+/// Appended after a signature, if any, so the checksum covers the frame as sent.
+pub fn checksummed(frame: Bytes) -> Bytes {
+    let mut buf = BytesMut::with_capacity(frame.len() + CHECKSUM_LEN);
+
+    buf.put_slice(&frame);
+    buf[1] |= CHECKSUM_FLAG;
+
+    let crc = crc32(&buf);
+
+    buf.put_u32_le(crc);
+
+    buf.freeze()
+}
+
 impl TryFrom<u8> for Variant {
     type Error = LrthromeError;
 
@@ -204,6 +627,25 @@ impl TryFrom<u8> for Variant {
             x if x == Variant::ResponseOkFound as u8 => Ok(Variant::ResponseOkFound),
             x if x == Variant::ResponseOkNotFound as u8 => Ok(Variant::ResponseOkNotFound),
             x if x == Variant::ResponseError as u8 => Ok(Variant::ResponseError),
+            x if x == Variant::RequestHost as u8 => Ok(Variant::RequestHost),
+            x if x == Variant::ResponseHost as u8 => Ok(Variant::ResponseHost),
+            x if x == Variant::RequestV6 as u8 => Ok(Variant::RequestV6),
+            x if x == Variant::ResponseOkFoundV6 as u8 => Ok(Variant::ResponseOkFoundV6),
+            x if x == Variant::ResponseOkNotFoundV6 as u8 => Ok(Variant::ResponseOkNotFoundV6),
+            x if x == Variant::RequestCovered as u8 => Ok(Variant::RequestCovered),
+            x if x == Variant::ResponseCovered as u8 => Ok(Variant::ResponseCovered),
+            x if x == Variant::ResponseNotCovered as u8 => Ok(Variant::ResponseNotCovered),
+            x if x == Variant::RequestBatch as u8 => Ok(Variant::RequestBatch),
+            x if x == Variant::ResponseBatch as u8 => Ok(Variant::ResponseBatch),
+            x if x == Variant::Ping as u8 => Ok(Variant::Ping),
+            x if x == Variant::Pong as u8 => Ok(Variant::Pong),
+            x if x == Variant::Health as u8 => Ok(Variant::Health),
+            x if x == Variant::ResponseHealth as u8 => Ok(Variant::ResponseHealth),
+            x if x == Variant::RequestStats as u8 => Ok(Variant::RequestStats),
+            x if x == Variant::ResponseStats as u8 => Ok(Variant::ResponseStats),
+            x if x == Variant::RequestAll as u8 => Ok(Variant::RequestAll),
+            x if x == Variant::ResponseAll as u8 => Ok(Variant::ResponseAll),
+            x if x == Variant::ResponseErrorV6 as u8 => Ok(Variant::ResponseErrorV6),
             x => Err(LrthromeError::InvalidMessageVariant(x)),
         }
     }
@@ -223,39 +665,110 @@ impl<'a> Established<'a> {
         buf.put_u32_le(self.tree_size);
         buf.put_u32_le(self.cache_ttl);
         buf.put_u32_le(self.peer_ttl);
-        buf.put_slice(self.banner.as_bytes());
-        buf.put_u8(0);
+
+        if let Some(banner) = self.banner {
+            buf.put_slice(banner.as_bytes());
+            buf.put_u8(0);
+        }
+
+        buf.put_u32_le(self.data_age);
+        buf.put_u8(self.capabilities);
+        buf.put_u8(*self.versions.end());
+        buf.put_u8(*self.versions.start());
+        buf.put_u32_le(self.rate_limit_window);
+        buf.put_u64_le(self.last_tempered_unix);
 
         buf.freeze()
     }
 }
 
 impl<'n> Identify<'n> {
-    pub fn parse(input: &'n [u8]) -> IResult<&'n [u8], Identify<'n>> {
-        let (input, identification) = parse_cstring(input)?;
+    pub fn parse(input: &'n [u8], max_cstring_len: usize) -> IResult<&'n [u8], Identify<'n>> {
+        let (input, identification) = parse_cstring(max_cstring_len)(input)?;
 
         Ok((input, Identify { identification }))
     }
 }
 
 impl<'n> Request<'n> {
-    pub fn parse(input: &'n [u8]) -> IResult<&'n [u8], Request<'n>> {
+    pub fn parse(
+        input: &'n [u8],
+        max_cstring_len: usize,
+        max_meta_count: u8,
+    ) -> IResult<&'n [u8], Request<'n>> {
         let (input, ip_address) = map(le_u32, Ipv4Addr::from)(input)?;
-        let (input, meta_count) = le_u8(input)?;
-
-        let (input, v) = count(pair(parse_cstring, parse_cstring), meta_count as usize)(input)?;
+        let (input, meta_count) = parse_meta_count(max_meta_count)(input)?;
+        let (input, meta) = parse_meta(input, meta_count, max_cstring_len)?;
 
         Ok((
             input,
             Request {
                 ip_address,
                 meta_count,
-                meta: v.into_iter().collect(),
+                meta,
             },
         ))
     }
 }
 
+impl<'n> RequestV6<'n> {
+    pub fn parse(
+        input: &'n [u8],
+        max_cstring_len: usize,
+        max_meta_count: u8,
+    ) -> IResult<&'n [u8], RequestV6<'n>> {
+        let (input, ip_address) = map(take(16usize), |octets: &[u8]| {
+            let mut buf = [0; 16];
+
+            buf.copy_from_slice(octets);
+
+            Ipv6Addr::from(buf)
+        })(input)?;
+        let (input, meta_count) = parse_meta_count(max_meta_count)(input)?;
+        let (input, meta) = parse_meta(input, meta_count, max_cstring_len)?;
+
+        Ok((input, RequestV6 { ip_address, meta }))
+    }
+}
+
+impl RequestCovered {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], RequestCovered> {
+        let (input, prefix) = map(le_u32, Ipv4Addr::from)(input)?;
+        let (input, mask_len) = verify(le_u8, |len: &u8| *len <= 32)(input)?;
+
+        Ok((input, RequestCovered { prefix, mask_len }))
+    }
+}
+
+impl RequestAll {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], RequestAll> {
+        let (input, ip_address) = map(le_u32, Ipv4Addr::from)(input)?;
+
+        Ok((input, RequestAll { ip_address }))
+    }
+}
+
+impl RequestBatch {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], RequestBatch> {
+        let (input, ip_addresses) = length_count(le_u16, map(le_u32, Ipv4Addr::from))(input)?;
+
+        Ok((input, RequestBatch { ip_addresses }))
+    }
+}
+
+impl<'n> RequestHost<'n> {
+    /// Longest hostname permitted, as per RFC 1035.
+    pub const MAX_HOSTNAME_LEN: usize = 253;
+
+    pub fn parse(input: &'n [u8], max_cstring_len: usize) -> IResult<&'n [u8], RequestHost<'n>> {
+        let (input, hostname) = verify(parse_cstring(max_cstring_len), |h: &str| {
+            !h.is_empty() && h.len() <= Self::MAX_HOSTNAME_LEN
+        })(input)?;
+
+        Ok((input, RequestHost { hostname }))
+    }
+}
+
 impl ResponseOkFound {
     pub fn to_bytes(&self) -> Bytes {
         let mut buf = Header::new(Variant::ResponseOkFound).to_bytes();
@@ -263,6 +776,17 @@ impl ResponseOkFound {
         buf.put_u32_le(u32::from(self.ip_address));
         buf.put_u32_le(u32::from(self.prefix));
         buf.put_u32_le(self.mask_len);
+        buf.put_u32_le(self.data_age);
+
+        if self.with_cidr {
+            buf.put_slice(format!("{}/{}", self.prefix, self.mask_len).as_bytes());
+            buf.put_u8(0);
+        }
+
+        if let Some(sources) = &self.sources {
+            buf.put_slice(sources.as_bytes());
+            buf.put_u8(0);
+        }
 
         buf.freeze()
     }
@@ -273,6 +797,171 @@ impl ResponseOkNotFound {
         let mut buf = Header::new(Variant::ResponseOkNotFound).to_bytes();
 
         buf.put_u32_le(u32::from(self.ip_address));
+        buf.put_u32_le(self.data_age);
+
+        buf.freeze()
+    }
+}
+
+impl ResponseOkFoundV6 {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseOkFoundV6).to_bytes();
+
+        buf.put_slice(&self.ip_address.octets());
+        buf.put_slice(&self.prefix.octets());
+        buf.put_u32_le(self.mask_len);
+        buf.put_u32_le(self.data_age);
+
+        if self.with_cidr {
+            buf.put_slice(format!("{}/{}", self.prefix, self.mask_len).as_bytes());
+            buf.put_u8(0);
+        }
+
+        if let Some(sources) = &self.sources {
+            buf.put_slice(sources.as_bytes());
+            buf.put_u8(0);
+        }
+
+        buf.freeze()
+    }
+}
+
+impl ResponseOkNotFoundV6 {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseOkNotFoundV6).to_bytes();
+
+        buf.put_slice(&self.ip_address.octets());
+        buf.put_u32_le(self.data_age);
+
+        buf.freeze()
+    }
+}
+
+impl ResponseCovered {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseCovered).to_bytes();
+
+        buf.put_u32_le(u32::from(self.prefix));
+        buf.put_u32_le(self.mask_len);
+        buf.put_u32_le(u32::from(self.covering_prefix));
+        buf.put_u32_le(self.covering_mask_len);
+        buf.put_u32_le(self.data_age);
+
+        buf.freeze()
+    }
+}
+
+impl ResponseNotCovered {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseNotCovered).to_bytes();
+
+        buf.put_u32_le(u32::from(self.prefix));
+        buf.put_u32_le(self.mask_len);
+        buf.put_u32_le(self.data_age);
+
+        buf.freeze()
+    }
+}
+
+impl<'a> ResponseHost<'a> {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseHost).to_bytes();
+
+        let count = self.matches.len().min(u8::MAX as usize);
+
+        buf.put_slice(self.hostname.as_bytes());
+        buf.put_u8(0);
+        buf.put_u8(self.status as u8);
+        buf.put_u8(count as u8);
+
+        for (ip_address, m) in self.matches.iter().take(count) {
+            let (found, prefix, mask_len) = match m {
+                Some((prefix, mask_len)) => (1, *prefix, *mask_len),
+                None => (0, Ipv4Addr::UNSPECIFIED, 0),
+            };
+
+            buf.put_u32_le(u32::from(*ip_address));
+            buf.put_u8(found);
+            buf.put_u32_le(u32::from(prefix));
+            buf.put_u32_le(mask_len);
+        }
+
+        buf.freeze()
+    }
+}
+
+impl Pong {
+    pub fn to_bytes(&self) -> Bytes {
+        Header::new(Variant::Pong).to_bytes().freeze()
+    }
+}
+
+impl ResponseAll {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseAll).to_bytes();
+
+        let count = self.matches.len().min(u8::MAX as usize);
+
+        buf.put_u32_le(u32::from(self.ip_address));
+        buf.put_u32_le(self.data_age);
+        buf.put_u8(count as u8);
+
+        for (prefix, mask_len) in self.matches.iter().take(count) {
+            buf.put_u32_le(u32::from(*prefix));
+            buf.put_u32_le(*mask_len);
+        }
+
+        buf.freeze()
+    }
+}
+
+impl ResponseStats {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseStats).to_bytes();
+
+        buf.put_u32_le(self.peers);
+        buf.put_u64_le(self.requests);
+        buf.put_u64_le(self.found);
+        buf.put_u64_le(self.not_found);
+        buf.put_u32_le(self.tree_size);
+        buf.put_u64_le(self.last_tempered_unix);
+
+        buf.freeze()
+    }
+}
+
+impl ResponseHealth {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseHealth).to_bytes();
+
+        buf.put_u32_le(self.tree_size);
+        buf.put_u32_le(self.data_age);
+        buf.put_u32_le(self.uptime);
+
+        buf.freeze()
+    }
+}
+
+impl ResponseBatch {
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = Header::new(Variant::ResponseBatch).to_bytes();
+
+        let count = self.matches.len().min(u16::MAX as usize);
+
+        buf.put_u32_le(self.data_age);
+        buf.put_u16_le(count as u16);
+
+        for (ip_address, m) in self.matches.iter().take(count) {
+            let (found, prefix, mask_len) = match m {
+                Some((prefix, mask_len)) => (1, *prefix, *mask_len),
+                None => (0, Ipv4Addr::UNSPECIFIED, 0),
+            };
+
+            buf.put_u32_le(u32::from(*ip_address));
+            buf.put_u8(found);
+            buf.put_u32_le(u32::from(prefix));
+            buf.put_u32_le(mask_len);
+        }
 
         buf.freeze()
     }
@@ -280,23 +969,85 @@ impl ResponseOkNotFound {
 
 impl<'a> ResponseError<'a> {
     pub fn to_bytes(&self) -> Bytes {
-        let mut buf = Header::new(Variant::ResponseError).to_bytes();
+        let variant = match self.ip_address {
+            IpAddr::V4(_) => Variant::ResponseError,
+            IpAddr::V6(_) => Variant::ResponseErrorV6,
+        };
+
+        let mut buf = Header::new(variant).to_bytes();
 
         buf.put_u8(self.code);
         buf.put_slice(self.message.as_bytes());
         buf.put_u8(0);
 
+        match self.ip_address {
+            IpAddr::V4(ip) => buf.put_u32_le(u32::from(ip)),
+            IpAddr::V6(ip) => buf.put_slice(&ip.octets()),
+        }
+
+        buf.put_u32_le(self.retry_after_ms);
+
         buf.freeze()
     }
 }
 
-fn parse_cstring(input: &[u8]) -> IResult<&[u8], &str> {
-    map_res(
-        terminated(take_while(|b| b != 0), tag([0])),
-        std::str::from_utf8,
+/// Body of a frame, malformed if it failed to parse or bytes trail it.
+///
+/// The codec delimits frames by the same layout, so trailing bytes are not expected,
+/// though are refused rather than silently ignored should the two ever disagree.
+pub fn complete<T>(parsed: IResult<&[u8], T>) -> Result<T, LrthromeError> {
+    match parsed {
+        Ok(([], body)) => Ok(body),
+        _ => Err(LrthromeError::MalformedPayload),
+    }
+}
+
+/// Number of meta pairs, refused above `max` before any pair is parsed.
+fn parse_meta_count(max: u8) -> impl Fn(&[u8]) -> IResult<&[u8], u8> {
+    move |input| verify(le_u8, |count: &u8| *count <= max)(input)
+}
+
+/// Meta key-value pairs.
+///
+/// Duplicate keys are refused, rather than the last silently overwriting the rest.
+fn parse_meta(
+    input: &[u8],
+    meta_count: u8,
+    max_cstring_len: usize,
+) -> IResult<&[u8], HashMap<&str, &str>> {
+    map(
+        verify(
+            count(
+                pair(
+                    parse_cstring(max_cstring_len),
+                    parse_cstring(max_cstring_len),
+                ),
+                meta_count as usize,
+            ),
+            |pairs: &Vec<(&str, &str)>| unique_keys(pairs),
+        ),
+        |pairs| pairs.into_iter().collect(),
     )(input)
 }
 
+fn unique_keys(pairs: &[(&str, &str)]) -> bool {
+    let mut seen = HashSet::with_capacity(pairs.len());
+
+    pairs.iter().all(|(key, _)| seen.insert(*key))
+}
+
+/// NUL terminated string of at most `max_len` bytes, excluding the terminator.
+///
+/// Scanning stops at the limit, a longer string is an error whether terminated or not.
+fn parse_cstring(max_len: usize) -> impl Fn(&[u8]) -> IResult<&[u8], &str> {
+    move |input| {
+        map_res(
+            terminated(take_while_m_n(0, max_len, |b| b != 0), tag([0])),
+            std::str::from_utf8,
+        )(input)
+    }
+}
+
 mod tests {
     #[allow(unused_imports)]
     use super::*;
@@ -315,10 +1066,27 @@ mod tests {
             Header {
                 protocol_version: ProtocolVersion(1),
                 variant: Variant::Established,
+                checksum: false,
             }
         );
     }
 
+    #[test]
+    fn checksum_frame() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let frame = checksummed(Header::new(Variant::Pong).to_bytes().freeze());
+
+        assert_eq!(frame.len(), 2 + CHECKSUM_LEN);
+        assert_eq!(frame[1], Variant::Pong as u8 | CHECKSUM_FLAG);
+        assert_eq!(&frame[2..], &crc32(&frame[..2]).to_le_bytes());
+
+        let (_, header) = Header::parse(&frame).unwrap();
+
+        assert_eq!(header.variant, Variant::Pong);
+        assert!(header.checksum);
+    }
+
     #[test]
     #[rustfmt::skip]
     fn parse_invalid_version_header() {
@@ -331,6 +1099,10 @@ mod tests {
         let h = Header::parse(payload);
 
         assert!(h.is_err());
+        assert!(matches!(
+            ProtocolVersion::try_from(payload[0]),
+            Err(LrthromeError::VersionMismatch { minimum: 1, preferred: 1, received: 0x64 })
+        ));
     }
 
     #[test]
@@ -357,7 +1129,7 @@ mod tests {
 
         assert_eq!(h.1.variant, Variant::Identify);
 
-        let i = Identify::parse(h.0).unwrap();
+        let i = Identify::parse(h.0, MAX_CSTRING_LEN).unwrap();
 
         assert_eq!(i.1.identification, "fishy");
     }
@@ -395,11 +1167,352 @@ mod tests {
 
         assert_eq!(h.1.variant, Variant::Request);
 
-        let r = Request::parse(h.0).unwrap();
+        let r = Request::parse(h.0, MAX_CSTRING_LEN, MAX_META_COUNT).unwrap();
 
         assert_eq!(r.1.ip_address, Ipv4Addr::new(1, 1, 1, 1));
         assert_eq!(r.1.meta_count, 2);
         assert_eq!(r.1.meta["foo"], "We live in a twilight world");
         assert_eq!(r.1.meta["bar"], "and there are no friends at dusk");
     }
+
+    #[test]
+    #[rustfmt::skip]
+    fn parse_valid_request_v6() {
+        let payload: &[u8] = &[
+            PROTOCOL_VERSION, Variant::RequestV6 as u8,
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // IP address
+            0x01, // Meta count
+            0x66, 0x6f, 0x6f, 0x00, // foo
+            0x62, 0x61, 0x72, 0x00, // bar
+        ];
+
+        let h = Header::parse(payload).unwrap();
+
+        assert_eq!(h.1.variant, Variant::RequestV6);
+
+        let r = RequestV6::parse(h.0, MAX_CSTRING_LEN, MAX_META_COUNT).unwrap();
+
+        assert_eq!(r.1.ip_address, "2001:db8::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(r.1.meta["foo"], "bar");
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn established_omits_banner() {
+        let established = |banner| {
+            Established {
+                rate_limit: 100,
+                tree_size: 2,
+                cache_ttl: 0,
+                peer_ttl: 15,
+                banner,
+                data_age: 7,
+                capabilities: CAPABILITY_IPV6,
+                versions: 1..=2,
+                rate_limit_window: 5,
+                last_tempered_unix: 1_600_000_000,
+            }
+            .to_bytes()
+        };
+
+        let with = established(Some("Glub"));
+        let without = established(None);
+
+        assert_eq!(with.len(), without.len() + 5);
+        assert_eq!(&with[18..23], b"Glub\0");
+        assert_eq!(&without[18..], &[
+            0x07, 0x00, 0x00, 0x00, // Data age
+            CAPABILITY_IPV6, // Capabilities
+            0x02, // Newest version
+            0x01, // Oldest version
+            0x05, 0x00, 0x00, 0x00, // Rate limit window
+            0x00, 0x10, 0x5e, 0x5f, 0x00, 0x00, 0x00, 0x00, // Last tempered
+        ][..]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn parse_request_covered() {
+        let payload: &[u8] = &[
+            0x00, 0x00, 0x10, 0xac, // Prefix
+            0x17, // Mask length
+        ];
+
+        let r = RequestCovered::parse(payload).unwrap();
+
+        assert_eq!(r.1.prefix, Ipv4Addr::new(172, 16, 0, 0));
+        assert_eq!(r.1.mask_len, 23);
+
+        assert!(RequestCovered::parse(&[0x00, 0x00, 0x10, 0xac, 0x21]).is_err());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn parse_request_batch() {
+        let payload: &[u8] = &[
+            0x02, 0x00, // Count
+            0x04, 0x03, 0x02, 0x01, // IP address
+            0x01, 0x00, 0x00, 0x0a, // IP address
+        ];
+
+        let r = RequestBatch::parse(payload).unwrap();
+
+        assert!(r.0.is_empty());
+        assert_eq!(
+            r.1.ip_addresses,
+            vec![Ipv4Addr::new(1, 2, 3, 4), Ipv4Addr::new(10, 0, 0, 1)]
+        );
+
+        assert!(RequestBatch::parse(&[0x02, 0x00, 0x04, 0x03, 0x02, 0x01]).is_err());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_batch_layout() {
+        let bytes = ResponseBatch {
+            data_age: 7,
+            matches: vec![
+                (Ipv4Addr::new(1, 2, 3, 4), Some((Ipv4Addr::new(1, 2, 3, 0), 24))),
+                (Ipv4Addr::new(10, 0, 0, 1), None),
+            ],
+        }
+        .to_bytes();
+
+        assert_eq!(&bytes[2..], &[
+            0x07, 0x00, 0x00, 0x00, // Data age
+            0x02, 0x00, // Count
+            0x04, 0x03, 0x02, 0x01, 0x01, 0x00, 0x03, 0x02, 0x01, 0x18, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ][..]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_all_layout() {
+        assert_eq!(
+            RequestAll::parse(&[0x04, 0x03, 0x02, 0x01]).unwrap().1.ip_address,
+            Ipv4Addr::new(1, 2, 3, 4)
+        );
+
+        let bytes = ResponseAll {
+            ip_address: Ipv4Addr::new(10, 1, 2, 3),
+            data_age: 7,
+            matches: vec![
+                (Ipv4Addr::new(10, 1, 0, 0), 16),
+                (Ipv4Addr::new(10, 0, 0, 0), 8),
+            ],
+        }
+        .to_bytes();
+
+        assert_eq!(bytes[1], Variant::ResponseAll as u8);
+        assert_eq!(&bytes[2..], &[
+            0x03, 0x02, 0x01, 0x0a, // IP address
+            0x07, 0x00, 0x00, 0x00, // Data age
+            0x02, // Count
+            0x00, 0x00, 0x01, 0x0a, 0x10, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x0a, 0x08, 0x00, 0x00, 0x00,
+        ][..]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_stats_layout() {
+        let bytes = ResponseStats {
+            peers: 2,
+            requests: 10,
+            found: 3,
+            not_found: 7,
+            tree_size: 300,
+            last_tempered_unix: 1_600_000_000,
+        }
+        .to_bytes();
+
+        assert_eq!(bytes[1], Variant::ResponseStats as u8);
+        assert_eq!(&bytes[2..], &[
+            0x02, 0x00, 0x00, 0x00, // Peers
+            0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Requests
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Found
+            0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // Not found
+            0x2c, 0x01, 0x00, 0x00, // Tree size
+            0x00, 0x10, 0x5e, 0x5f, 0x00, 0x00, 0x00, 0x00, // Last tempered
+        ][..]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_health_layout() {
+        let bytes = ResponseHealth {
+            tree_size: 300,
+            data_age: 7,
+            uptime: 65536,
+        }
+        .to_bytes();
+
+        assert_eq!(bytes[1], Variant::ResponseHealth as u8);
+        assert_eq!(&bytes[2..], &[
+            0x2c, 0x01, 0x00, 0x00, // Tree size
+            0x07, 0x00, 0x00, 0x00, // Data age
+            0x00, 0x00, 0x01, 0x00, // Uptime
+        ][..]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn parse_duplicate_meta_key() {
+        let payload: &[u8] = &[
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0x02, // Meta count
+            0x66, 0x6f, 0x6f, 0x00, // foo
+            0x61, 0x00, // a
+            0x66, 0x6f, 0x6f, 0x00, // foo
+            0x62, 0x00, // b
+        ];
+
+        assert!(Request::parse(payload, MAX_CSTRING_LEN, MAX_META_COUNT).is_err());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn parse_oversized_meta() {
+        let payload: &[u8] = &[
+            0x01, 0x01, 0x01, 0x01, // IP address
+            0x03, // Meta count
+            0x61, 0x00, 0x62, 0x00, // a = b
+            0x63, 0x00, 0x64, 0x00, // c = d
+            0x65, 0x00, 0x66, 0x00, // e = f
+        ];
+
+        assert!(Request::parse(payload, MAX_CSTRING_LEN, 3).is_ok());
+        assert!(matches!(
+            complete(Request::parse(payload, MAX_CSTRING_LEN, 2)),
+            Err(LrthromeError::MalformedPayload)
+        ));
+
+        // Refused from the count alone, however few pairs follow.
+        let mut v6 = vec![0x00; 16];
+        v6.push(0xFF);
+
+        assert!(matches!(
+            complete(RequestV6::parse(&v6, MAX_CSTRING_LEN, MAX_META_COUNT)),
+            Err(LrthromeError::MalformedPayload)
+        ));
+
+        // A value longer than the limit is refused, rather than allocated.
+        let mut long = vec![0x01, 0x01, 0x01, 0x01, 0x01, 0x61, 0x00];
+        long.extend_from_slice(&[0x62; 257]);
+        long.push(0x00);
+
+        assert!(Request::parse(&long, 256, MAX_META_COUNT).is_err());
+        assert!(Request::parse(&long, 257, MAX_META_COUNT).is_ok());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn parse_valid_request_host() {
+        let payload: &[u8] = &[
+            PROTOCOL_VERSION, Variant::RequestHost as u8,
+            0x61, 0x2e, 0x63, 0x6f, 0x6d, 0x00, // a.com
+        ];
+
+        let h = Header::parse(payload).unwrap();
+
+        assert_eq!(h.1.variant, Variant::RequestHost);
+
+        let r = RequestHost::parse(h.0, MAX_CSTRING_LEN).unwrap();
+
+        assert_eq!(r.1.hostname, "a.com");
+    }
+
+    #[test]
+    fn parse_invalid_request_host() {
+        assert!(RequestHost::parse(&[0x00], MAX_CSTRING_LEN).is_err());
+
+        let mut long = vec![0x61; RequestHost::MAX_HOSTNAME_LEN + 1];
+        long.push(0x00);
+
+        assert!(RequestHost::parse(&long, MAX_CSTRING_LEN).is_err());
+    }
+
+    #[test]
+    fn parse_trailing_bytes() {
+        assert!(complete(Identify::parse(b"token\0", MAX_CSTRING_LEN)).is_ok());
+        assert!(matches!(
+            complete(Identify::parse(b"token\0\x01", MAX_CSTRING_LEN)),
+            Err(LrthromeError::MalformedPayload)
+        ));
+    }
+
+    #[test]
+    fn parse_unterminated_cstring() {
+        let huge = vec![0x61; 4 * 1024 * 1024];
+
+        assert!(Identify::parse(&huge, MAX_CSTRING_LEN).is_err());
+
+        let mut bounded = vec![0x61; 16];
+        bounded.push(0x00);
+
+        assert!(Identify::parse(&bounded, 16).is_ok());
+        assert!(Identify::parse(&bounded, 15).is_err());
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_found_appends_cidr() {
+        let found = |with_cidr, sources: Option<&str>| ResponseOkFound {
+            ip_address: Ipv4Addr::new(1, 2, 3, 4),
+            prefix: Ipv4Addr::new(1, 2, 3, 0),
+            mask_len: 24,
+            data_age: 0,
+            with_cidr,
+            sources: sources.map(str::to_string),
+        }
+        .to_bytes();
+
+        assert_eq!(found(false, None).len(), 18);
+        assert_eq!(&found(true, None)[18..], b"1.2.3.0/24\0");
+        assert_eq!(&found(false, Some("bogon"))[18..], b"bogon\0");
+        assert_eq!(&found(true, Some("a,b"))[18..], b"1.2.3.0/24\0a,b\0");
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_error_appends_ip() {
+        let bytes = ResponseError {
+            code: 1,
+            message: "fish",
+            ip_address: Ipv4Addr::new(1, 2, 3, 4).into(),
+            retry_after_ms: 1500,
+        }
+        .to_bytes();
+
+        assert_eq!(&bytes[..], &[
+            PROTOCOL_VERSION, Variant::ResponseError as u8,
+            0x01, // Code
+            0x66, 0x69, 0x73, 0x68, 0x00, // fish
+            0x04, 0x03, 0x02, 0x01, // IP address
+            0xdc, 0x05, 0x00, 0x00, // Retry after
+        ][..]);
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    fn response_error_appends_ipv6() {
+        let bytes = ResponseError {
+            code: 1,
+            message: "fish",
+            ip_address: "2001:db8::1".parse().unwrap(),
+            retry_after_ms: 1500,
+        }
+        .to_bytes();
+
+        assert_eq!(&bytes[..], &[
+            PROTOCOL_VERSION, Variant::ResponseErrorV6 as u8,
+            0x01, // Code
+            0x66, 0x69, 0x73, 0x68, 0x00, // fish
+            0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00, // IP address
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+            0xdc, 0x05, 0x00, 0x00, // Retry after
+        ][..]);
+    }
 }
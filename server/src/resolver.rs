@@ -0,0 +1,126 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use tokio::net::lookup_host;
+use tokio::sync::Semaphore;
+use tokio::time::{timeout, Duration};
+
+use crate::config::Resolver as ResolverConfig;
+
+/// Maximum number of hostnames retained in the result cache.
+const MAX_CACHED_HOSTS: usize = 4096;
+
+/// Server-side hostname resolution for `RequestHost`.
+///
+/// Resolution is bounded in concurrency and time, and results are cached,
+/// so that peers cannot use the server to amplify DNS traffic.
+pub struct Resolver {
+    /// Bounds the number of in-flight resolutions across all peers.
+    permits: Semaphore,
+
+    /// Time allowed for a single resolution.
+    timeout: Duration,
+
+    /// Time a resolved result is served from cache.
+    cache_ttl: Duration,
+
+    /// Hostname to instant resolved & IPv4 addresses.
+    cache: Mutex<HashMap<String, (Instant, Vec<Ipv4Addr>)>>,
+}
+
+/// Outcome of a resolution, as reported to the peer.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ResolveStatus {
+    Resolved = 0,
+
+    Failed = 1,
+
+    TimedOut = 2,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig) -> Self {
+        Self {
+            permits: Semaphore::new(config.max_concurrent.max(1)),
+            timeout: Duration::from_secs(config.timeout_secs as u64),
+            cache_ttl: Duration::from_secs(config.cache_ttl as u64),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve the hostname's IPv4 addresses.
+    pub async fn resolve(&self, host: &str) -> (ResolveStatus, Vec<Ipv4Addr>) {
+        if let Some(addrs) = self.cached(host) {
+            return (ResolveStatus::Resolved, addrs);
+        }
+
+        let _permit = self.permits.acquire().await;
+
+        // Another resolution of the same host may have completed while waiting.
+        if let Some(addrs) = self.cached(host) {
+            return (ResolveStatus::Resolved, addrs);
+        }
+
+        let addrs = match timeout(self.timeout, lookup_host((host, 0))).await {
+            Ok(Ok(addrs)) => addrs
+                .filter_map(|a| match a.ip() {
+                    IpAddr::V4(ip) => Some(ip),
+                    IpAddr::V6(_) => None,
+                })
+                .collect::<Vec<_>>(),
+            Ok(Err(e)) => {
+                debug!("Unable to resolve {}: {}", host, e);
+
+                return (ResolveStatus::Failed, Vec::new());
+            }
+            Err(_) => {
+                warn!("Resolution of {} timed out", host);
+
+                return (ResolveStatus::TimedOut, Vec::new());
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.len() >= MAX_CACHED_HOSTS {
+            let ttl = self.cache_ttl;
+
+            cache.retain(|_, (at, _)| at.elapsed() < ttl);
+
+            if cache.len() >= MAX_CACHED_HOSTS {
+                cache.clear();
+            }
+        }
+
+        cache.insert(host.to_string(), (Instant::now(), addrs.clone()));
+
+        (ResolveStatus::Resolved, addrs)
+    }
+
+    fn cached(&self, host: &str) -> Option<Vec<Ipv4Addr>> {
+        let cache = self.cache.lock().unwrap();
+
+        cache
+            .get(host)
+            .filter(|(at, _)| at.elapsed() < self.cache_ttl)
+            .map(|(_, addrs)| addrs.clone())
+    }
+}
@@ -0,0 +1,146 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+
+use cidr::IpCidr;
+
+use futures::stream;
+
+use crate::config::{Bogon as BogonConfig, Mode};
+use crate::error::LrthromeResult;
+
+use super::{CidrStream, Fetcher, Normalize};
+
+/// Private, shared and reserved IPv4 ranges that are never routed on the public internet.
+///
+/// Sourced from the IANA IPv4 Special-Purpose Address Registry.
+pub const BOGONS: &[&str] = &[
+    // "This network", RFC 1122.
+    "0.0.0.0/8",
+    // Private-use, RFC 1918.
+    "10.0.0.0/8",
+    // Shared address space (carrier-grade NAT), RFC 6598.
+    "100.64.0.0/10",
+    // Loopback, RFC 1122.
+    "127.0.0.0/8",
+    // Link local, RFC 3927.
+    "169.254.0.0/16",
+    // Private-use, RFC 1918.
+    "172.16.0.0/12",
+    // IETF protocol assignments, RFC 6890.
+    "192.0.0.0/24",
+    // Documentation (TEST-NET-1), RFC 5737.
+    "192.0.2.0/24",
+    // Private-use, RFC 1918.
+    "192.168.0.0/16",
+    // Benchmarking, RFC 2544.
+    "198.18.0.0/15",
+    // Documentation (TEST-NET-2), RFC 5737.
+    "198.51.100.0/24",
+    // Documentation (TEST-NET-3), RFC 5737.
+    "203.0.113.0/24",
+    // Multicast, RFC 5771.
+    "224.0.0.0/4",
+    // Reserved for future use & limited broadcast, RFC 1112 & RFC 919.
+    "240.0.0.0/4",
+];
+
+/// Built-in source of the `BOGONS` ranges, requiring no network or files.
+pub struct Bogon {
+    normalize: Normalize,
+
+    mode: Mode,
+
+    priority: i32,
+
+    /// Whether the ranges have been yielded, after which they never change.
+    yielded: AtomicBool,
+}
+
+impl Bogon {
+    pub fn new(config: BogonConfig) -> Self {
+        Self {
+            normalize: Normalize::default(),
+            mode: config.mode,
+            priority: config.priority,
+            yielded: AtomicBool::new(false),
+        }
+    }
+}
+
+#[async_trait]
+impl Fetcher for Bogon {
+    // Compiled in, carried over from the current tree once yielded.
+    async fn has_update(&self) -> bool {
+        !self.yielded.load(Ordering::Relaxed)
+    }
+
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+        self.yielded.store(true, Ordering::Relaxed);
+
+        let cidrs = BOGONS
+            .iter()
+            .map(|c| Ok(IpCidr::from_str(c).expect("bogon ranges are valid networks")));
+
+        Ok(Box::pin(stream::iter(cidrs)))
+    }
+
+    fn name(&self) -> &str {
+        "bogon"
+    }
+
+    fn normalize(&self) -> &Normalize {
+        &self.normalize
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use cidr::Ipv4Cidr;
+
+    #[test]
+    fn bogons_are_networks() {
+        for bogon in BOGONS {
+            assert!(Ipv4Cidr::from_str(bogon).is_ok(), "{}", bogon);
+        }
+    }
+
+    #[tokio::test]
+    async fn update_until_yielded() {
+        let bogon = Bogon::new(BogonConfig::default());
+
+        assert!(bogon.has_update().await);
+
+        let _ = bogon.iterate_cidr().await.unwrap();
+
+        assert!(!bogon.has_update().await);
+    }
+}
@@ -0,0 +1,329 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use cidr::IpCidr;
+
+use futures::stream::{self, StreamExt};
+
+use crate::config::Mode;
+use crate::error::{LrthromeError, LrthromeResult};
+use crate::metrics::Metrics;
+
+use super::{CidrStream, Fetcher, Normalize};
+
+/// Circuit breaker around a source.
+///
+/// After `threshold` consecutive failures the breaker opens, and the source is no longer
+/// fetched from, with its last successfully fetched entries served in its place.
+/// Once `cooldown` has passed, a single probe fetch is allowed through (half-open),
+/// closing the breaker on success or reopening it on failure.
+///
+/// A fetch is only known to have succeeded once its stream completes,
+/// so entries are collected before being yielded, and the last successful fetch
+/// is kept whole in memory, as large as the source itself.
+///
+/// Until a fetch has succeeded there is nothing to serve in its place,
+/// and a failed or skipped fetch fails as the source would.
+pub struct Breaker {
+    inner: Box<dyn Fetcher>,
+
+    threshold: u32,
+
+    cooldown: Duration,
+
+    state: Mutex<State>,
+
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Consecutive failed fetches.
+    failures: u32,
+
+    /// Instant the breaker opened, absent while closed.
+    opened_at: Option<Instant>,
+
+    /// Entries of the last successful fetch, absent until one has succeeded.
+    last: Option<Arc<Vec<IpCidr>>>,
+}
+
+impl Breaker {
+    pub fn new(
+        inner: Box<dyn Fetcher>,
+        threshold: u32,
+        cooldown: Duration,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            inner,
+            threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+            metrics,
+        }
+    }
+
+    /// Whether the source is skipped, being open and not yet due a probe.
+    fn skipped(&self) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .opened_at
+            .is_some_and(|opened_at| opened_at.elapsed() < self.cooldown)
+    }
+
+    /// Fetch every entry of the source, failing if the stream does.
+    async fn fetch(&self) -> LrthromeResult<Vec<IpCidr>> {
+        let mut stream = self.inner.iterate_cidr().await?;
+        let mut entries = Vec::new();
+
+        while let Some(cidr) = stream.next().await {
+            entries.push(cidr?);
+        }
+
+        Ok(entries)
+    }
+
+    fn record_success(&self, entries: Vec<IpCidr>) -> Arc<Vec<IpCidr>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.opened_at.take().is_some() {
+            info!("Source {} recovered, closing its breaker", self.name());
+        }
+
+        let entries = Arc::new(entries);
+
+        state.failures = 0;
+        state.last = Some(entries.clone());
+
+        self.metrics.source_breaker_open.get(self.name()).set(0);
+
+        entries
+    }
+
+    fn record_failure(&self) -> Option<Arc<Vec<IpCidr>>> {
+        let mut state = self.state.lock().unwrap();
+
+        state.failures += 1;
+
+        self.metrics.source_failures.get(self.name()).inc();
+
+        // A failed probe reopens the breaker, regardless of the threshold.
+        if state.opened_at.is_some() || state.failures >= self.threshold {
+            warn!(
+                "Source {} failed {} consecutive times, opening its breaker for {:?}",
+                self.name(),
+                state.failures,
+                self.cooldown
+            );
+
+            state.opened_at = Some(Instant::now());
+
+            self.metrics.source_breaker_open.get(self.name()).set(1);
+        }
+
+        state.last.clone()
+    }
+}
+
+#[async_trait]
+impl Fetcher for Breaker {
+    // An open breaker does not check the source,
+    // as the check may be what is failing.
+    async fn has_update(&self) -> bool {
+        !self.skipped() && self.inner.has_update().await
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn normalize(&self) -> &Normalize {
+        self.inner.normalize()
+    }
+
+    fn mode(&self) -> Mode {
+        self.inner.mode()
+    }
+
+    fn priority(&self) -> i32 {
+        self.inner.priority()
+    }
+
+    fn usable(&self) -> bool {
+        self.inner.usable()
+    }
+
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+        let entries = if self.skipped() {
+            let last = self.state.lock().unwrap().last.clone();
+
+            match last {
+                Some(last) => {
+                    info!(
+                        "Breaker of source {} is open, serving its last entries",
+                        self.name()
+                    );
+
+                    last
+                }
+                None => return Err(LrthromeError::SourceUnavailable(self.name().to_string())),
+            }
+        } else {
+            match self.fetch().await {
+                Ok(entries) => self.record_success(entries),
+                Err(e) => match self.record_failure() {
+                    Some(last) => {
+                        error!(
+                            "Source {} failed, serving its last entries: {}",
+                            self.name(),
+                            e
+                        );
+
+                        last
+                    }
+                    None => return Err(e),
+                },
+            }
+        };
+
+        let cidrs = (0..entries.len()).map(move |i| Ok(entries[i].clone()));
+
+        Ok(Box::pin(stream::iter(cidrs)))
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::str::FromStr;
+
+    #[allow(unused_imports)]
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[allow(unused_imports)]
+    use futures::StreamExt;
+
+    /// Source yielding a single entry, or failing while `failing` is set.
+    #[allow(dead_code)]
+    struct Flaky {
+        failing: Arc<AtomicBool>,
+
+        normalize: Normalize,
+    }
+
+    #[async_trait]
+    impl Fetcher for Flaky {
+        async fn has_update(&self) -> bool {
+            true
+        }
+
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn normalize(&self) -> &Normalize {
+            &self.normalize
+        }
+
+        async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+            if self.failing.load(Ordering::Relaxed) {
+                return Err(LrthromeError::SourceUnavailable("flaky".to_string()));
+            }
+
+            let cidr = IpCidr::from_str("10.0.0.0/8").unwrap();
+
+            Ok(Box::pin(stream::iter(vec![Ok(cidr)])))
+        }
+    }
+
+    #[allow(dead_code)]
+    async fn count(breaker: &Breaker) -> usize {
+        breaker
+            .iterate_cidr()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .len()
+    }
+
+    #[tokio::test]
+    async fn opens_and_probes() {
+        let failing = Arc::new(AtomicBool::new(false));
+        let metrics = Arc::new(Metrics::default());
+
+        let breaker = Breaker::new(
+            Box::new(Flaky {
+                failing: failing.clone(),
+                normalize: Normalize::default(),
+            }),
+            2,
+            Duration::from_millis(50),
+            metrics.clone(),
+        );
+
+        assert_eq!(count(&breaker).await, 1);
+
+        failing.store(true, Ordering::Relaxed);
+
+        // Last entries are served while failing, with the breaker opening on the second failure.
+        assert_eq!(count(&breaker).await, 1);
+        assert!(!breaker.skipped());
+        assert_eq!(count(&breaker).await, 1);
+        assert!(breaker.skipped());
+        assert!(!breaker.has_update().await);
+        assert_eq!(metrics.source_breaker_open.get("flaky").get(), 1);
+
+        failing.store(false, Ordering::Relaxed);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // Half-open, the probe succeeds and closes the breaker.
+        assert!(!breaker.skipped());
+        assert_eq!(count(&breaker).await, 1);
+        assert_eq!(metrics.source_breaker_open.get("flaky").get(), 0);
+        assert_eq!(metrics.source_failures.get("flaky").get(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_without_last_entries() {
+        let failing = Arc::new(AtomicBool::new(true));
+
+        let breaker = Breaker::new(
+            Box::new(Flaky {
+                failing: failing.clone(),
+                normalize: Normalize::default(),
+            }),
+            1,
+            Duration::from_secs(60),
+            Arc::default(),
+        );
+
+        // Never having succeeded, neither a failed fetch nor an open breaker yield an empty source.
+        assert!(breaker.iterate_cidr().await.is_err());
+        assert!(breaker.skipped());
+        assert!(breaker.iterate_cidr().await.is_err());
+    }
+}
@@ -15,27 +15,40 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::collections::HashMap;
-use std::str::FromStr;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 
-use cidr::Ipv4Cidr;
+use cidr::IpCidr;
 
 use csv::Reader;
 
-use crate::config::GeoLite as GeoLiteConfig;
+use futures::stream;
+
+use crate::config::{GeoLite as GeoLiteConfig, GeoLiteFormat, Mode};
 use crate::error::LrthromeResult;
 
-use super::Fetcher;
+use super::mmdb::{self, Field};
+use super::{CidrStream, Fetcher, Normalize};
 
 pub struct GeoLite {
+    normalize: Normalize,
+
+    format: GeoLiteFormat,
+
+    mode: Mode,
+
+    priority: i32,
+
     asn_path: String,
     geo_paths: [String; 2],
 
     // Combine city & country geoname ids, O(1) lookup.
-    geoname_ids: HashMap<String, ()>,
+    geoname_ids: Arc<HashMap<String, ()>>,
     // ASN is kept separate in event of duplicate key.
-    asns: HashMap<String, ()>,
+    asns: Arc<HashMap<String, ()>>,
 }
 
 impl GeoLite {
@@ -68,10 +81,14 @@ impl GeoLite {
         };
 
         Self {
+            normalize: Normalize::new(config.normalize),
+            format: config.format,
+            mode: config.mode,
+            priority: config.priority,
             asn_path,
             geo_paths,
-            geoname_ids,
-            asns,
+            geoname_ids: Arc::new(geoname_ids),
+            asns: Arc::new(asns),
         }
     }
 }
@@ -83,49 +100,130 @@ impl Fetcher for GeoLite {
         true
     }
 
-    async fn iterate_cidr(&self) -> LrthromeResult<Box<dyn Iterator<Item = Ipv4Cidr>>> {
-        let mut cidrs = Vec::new();
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+        match self.format {
+            GeoLiteFormat::Csv => self.iterate_csv(),
+            GeoLiteFormat::Mmdb => self.iterate_mmdb().await,
+        }
+    }
+
+    fn name(&self) -> &str {
+        "geolite"
+    }
+
+    fn normalize(&self) -> &Normalize {
+        &self.normalize
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    // Missing databases are skipped by temper, leaving the source empty.
+    fn usable(&self) -> bool {
+        let exists = |path: &String| Path::new(path).exists();
+
+        (!self.asns.is_empty() && exists(&self.asn_path))
+            || (!self.geoname_ids.is_empty() && self.geo_paths.iter().any(exists))
+    }
+}
+
+impl GeoLite {
+    fn iterate_csv(&self) -> LrthromeResult<CidrStream> {
+        // Records are read and filtered lazily as temper pulls them,
+        // so the databases never fully reside in memory alongside the tree.
+        let mut iter: Box<dyn Iterator<Item = LrthromeResult<IpCidr>> + Send> =
+            Box::new(std::iter::empty());
 
         for geo in self.geo_paths.iter() {
             match Reader::from_path(geo) {
-                Ok(mut r) => {
-                    for result in r.records() {
-                        let record = result?;
-
-                        if let Some(geo_id) = record.get(1) {
-                            if self.geoname_ids.contains_key(geo_id) {
-                                if let Some(network) = record.get(0) {
-                                    if let Ok(cidr) = Ipv4Cidr::from_str(network) {
-                                        cidrs.push(cidr);
-                                    }
-                                }
-                            }
-                        }
-                    }
+                Ok(r) => {
+                    iter = Box::new(iter.chain(matching_records(
+                        r,
+                        self.geoname_ids.clone(),
+                        self.normalize.clone(),
+                    )))
                 }
                 Err(_) => warn!("Unable to open {}. Skipped.", geo),
             }
         }
 
         match Reader::from_path(&self.asn_path) {
-            Ok(mut r) => {
-                for result in r.records() {
-                    let record = result?;
-
-                    if let Some(asn) = record.get(1) {
-                        if self.asns.contains_key(asn) {
-                            if let Some(network) = record.get(0) {
-                                if let Ok(cidr) = Ipv4Cidr::from_str(network) {
-                                    cidrs.push(cidr);
-                                }
-                            }
-                        }
-                    }
-                }
+            Ok(r) => {
+                iter = Box::new(iter.chain(matching_records(
+                    r,
+                    self.asns.clone(),
+                    self.normalize.clone(),
+                )))
             }
             Err(_) => warn!("Unable to open {}. Skipped.", self.asn_path),
         }
 
-        Ok(Box::new(cidrs.into_iter()))
+        Ok(Box::pin(stream::iter(iter)))
+    }
+
+    /// Each database is walked off the runtime, only its matching networks kept.
+    async fn iterate_mmdb(&self) -> LrthromeResult<CidrStream> {
+        let mut networks = Vec::new();
+
+        let databases = self
+            .geo_paths
+            .iter()
+            .map(|path| (path, Field::Geoname, &self.geoname_ids))
+            .chain(std::iter::once((&self.asn_path, Field::Asn, &self.asns)));
+
+        for (path, field, ids) in databases {
+            // Skipped outright, rather than reading a database without anything to match.
+            if ids.is_empty() {
+                continue;
+            }
+
+            let walked = tokio::task::spawn_blocking({
+                let path = path.clone();
+                let ids = ids.clone();
+
+                move || mmdb::networks(path, field, &ids)
+            })
+            .await?;
+
+            match walked {
+                Ok(walked) => networks.extend(walked),
+                Err(e) => warn!("Unable to read {}: {}. Skipped.", path, e),
+            }
+        }
+
+        Ok(Box::pin(stream::iter(networks.into_iter().map(Ok))))
     }
 }
+
+/// Lazily yield networks of records whose id column is within `ids`.
+fn matching_records(
+    reader: Reader<File>,
+    ids: Arc<HashMap<String, ()>>,
+    normalize: Normalize,
+) -> impl Iterator<Item = LrthromeResult<IpCidr>> {
+    reader.into_records().flat_map(move |result| {
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => return vec![Err(e.into())],
+        };
+
+        match (record.get(0), record.get(1)) {
+            (Some(network), Some(id)) if ids.contains_key(id) => {
+                let line = record.position().map_or(0, |p| p.line() as usize);
+
+                normalize
+                    .parse_line(line, network)
+                    .into_iter()
+                    .flatten()
+                    .map(Ok)
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    })
+}
@@ -0,0 +1,347 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use futures::stream::{self, Stream, StreamExt};
+
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use crate::config::{Git as GitConfig, Mode};
+use crate::error::LrthromeResult;
+
+use super::{CidrStream, Fetcher, Normalize};
+
+/// Lists tracked in a Git repository, pulled with the `git` executable.
+pub struct Git {
+    url: String,
+
+    /// Branch tracked, the remote's default branch if absent.
+    branch: Option<String>,
+
+    checkout: PathBuf,
+
+    /// Files read, relative to the repository root.
+    files: Vec<String>,
+
+    normalize: Normalize,
+
+    mode: Mode,
+
+    priority: i32,
+
+    /// Commit of the checkout as of the last pull.
+    last_seen: Mutex<Option<String>>,
+
+    /// Commit of the remote as of the last update check.
+    remote_head: Mutex<Option<String>>,
+}
+
+impl Git {
+    pub fn new(config: GitConfig) -> Self {
+        Self {
+            url: config.url,
+            branch: config.branch,
+            checkout: PathBuf::from(config.checkout_dir),
+            files: config.files,
+            normalize: Normalize::new(config.normalize),
+            mode: config.mode,
+            priority: config.priority,
+            last_seen: Mutex::new(None),
+            remote_head: Mutex::new(None),
+        }
+    }
+
+    fn refspec(&self) -> String {
+        match &self.branch {
+            Some(branch) => format!("refs/heads/{}", branch),
+            None => "HEAD".to_string(),
+        }
+    }
+
+    /// Bring the checkout up to date with the remote, cloning it if absent.
+    async fn pull(&self) -> io::Result<()> {
+        let branch = self.branch.as_deref().unwrap_or("HEAD");
+
+        let checkout = self.checkout_str();
+
+        if self.checkout.join(".git").exists() {
+            let remote_head = self.remote_head.lock().unwrap().clone();
+
+            if remote_head.is_none() || remote_head != self.local_head().await {
+                git(&["-C", &checkout, "fetch", "--depth", "1", "origin", branch]).await?;
+                git(&["-C", &checkout, "reset", "--hard", "FETCH_HEAD"]).await?;
+            }
+        } else {
+            let mut args = vec!["clone", "--depth", "1"];
+
+            if let Some(branch) = &self.branch {
+                args.extend(&["--branch", branch.as_str()]);
+            }
+
+            args.extend(&[self.url.as_str(), checkout.as_str()]);
+
+            git(&args).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn local_head(&self) -> Option<String> {
+        git(&["-C", &self.checkout_str(), "rev-parse", "HEAD"])
+            .await
+            .ok()
+    }
+
+    fn checkout_str(&self) -> String {
+        self.checkout.to_string_lossy().into_owned()
+    }
+}
+
+#[async_trait]
+impl Fetcher for Git {
+    // Compare the remote's commit to the one last pulled,
+    // without fetching any objects.
+    async fn has_update(&self) -> bool {
+        let remote_head = git(&["ls-remote", &self.url, &self.refspec()])
+            .await
+            .ok()
+            .and_then(|out| out.split_whitespace().next().map(|h| h.to_string()));
+
+        let last_seen = self.last_seen.lock().unwrap().clone();
+
+        match remote_head {
+            Some(head) => {
+                let updated = last_seen.as_ref() != Some(&head);
+
+                *self.remote_head.lock().unwrap() = Some(head);
+
+                updated
+            }
+            None => {
+                warn!("Unable to check {} for updates", self.url);
+
+                // Fall back to any existing checkout on first load.
+                last_seen.is_none()
+            }
+        }
+    }
+
+    // An existing checkout is read should pulling fail,
+    // while failing to clone leaves nothing to read.
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+        if let Err(e) = self.pull().await {
+            if !self.checkout.join(".git").exists() {
+                error!("Unable to clone {}: {}", self.url, e);
+
+                return Err(e.into());
+            }
+
+            warn!(
+                "Unable to pull {}, reading existing checkout: {}",
+                self.url, e
+            );
+        }
+
+        let head = self.local_head().await;
+
+        info!(
+            "Reading {} at {}",
+            self.url,
+            head.as_deref().unwrap_or("no commit")
+        );
+
+        *self.last_seen.lock().unwrap() = head;
+
+        let mut lines = Vec::new();
+
+        for file in &self.files {
+            let path = self.checkout.join(file);
+
+            match File::open(&path).await {
+                Ok(f) => lines.push(read_lines(f).enumerate()),
+                Err(_) => warn!("Unable to open {}. Skipped.", path.display()),
+            }
+        }
+
+        let normalize = self.normalize.clone();
+
+        // Line numbers are within each file.
+        let cidrs = stream::iter(lines).flatten().flat_map(move |(i, line)| {
+            stream::iter(match line {
+                Ok(line) => normalize
+                    .parse_line(i + 1, &line)
+                    .into_iter()
+                    .flatten()
+                    .map(Ok)
+                    .collect(),
+                Err(e) => vec![Err(e.into())],
+            })
+        });
+
+        Ok(Box::pin(cidrs))
+    }
+
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn normalize(&self) -> &Normalize {
+        &self.normalize
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn usable(&self) -> bool {
+        !self.files.is_empty()
+    }
+}
+
+/// Lazily read the file's lines.
+fn read_lines(file: File) -> impl Stream<Item = io::Result<String>> {
+    stream::unfold(BufReader::new(file).lines(), |mut lines| async move {
+        lines
+            .next_line()
+            .await
+            .transpose()
+            .map(|line| (line, lines))
+    })
+}
+
+/// Run `git` with the arguments, returning its trimmed stdout.
+async fn git(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("git").args(args).output().await?;
+
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    /// Run `git` in the directory, panicking should it fail.
+    #[allow(dead_code)]
+    fn run(dir: &std::path::Path, args: &[&str]) {
+        let status = std::process::Command::new("git")
+            .args([
+                "-c",
+                "user.name=lrthrome",
+                "-c",
+                "user.email=lrthrome@localhost",
+            ])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[allow(dead_code)]
+    fn source(url: &std::path::Path, checkout: &std::path::Path) -> Git {
+        Git::new(
+            toml::from_str(&format!(
+                "url = {:?}\nbranch = \"main\"\ncheckout_dir = {:?}\nfiles = [\"list.netset\"]",
+                url.to_string_lossy(),
+                checkout.to_string_lossy(),
+            ))
+            .unwrap(),
+        )
+    }
+
+    #[allow(dead_code)]
+    async fn count(git: &Git) -> usize {
+        git.iterate_cidr()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+            .len()
+    }
+
+    #[tokio::test]
+    async fn clone_and_pull() {
+        let dir = std::env::temp_dir().join(format!("lrthrome-git-{}", std::process::id()));
+        let remote = dir.join("remote.git");
+        let work = dir.join("work");
+
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&work).unwrap();
+
+        run(&dir, &["init", "-q", "--bare", "-b", "main", "remote.git"]);
+        run(&work, &["init", "-q", "-b", "main"]);
+        run(
+            &work,
+            &["remote", "add", "origin", &remote.to_string_lossy()],
+        );
+
+        std::fs::write(work.join("list.netset"), "10.0.0.0/8\n").unwrap();
+
+        run(&work, &["add", "list.netset"]);
+        run(&work, &["commit", "-q", "-m", "Add list"]);
+        run(&work, &["push", "-q", "origin", "main"]);
+
+        let git = source(&remote, &dir.join("checkout"));
+
+        assert!(git.has_update().await);
+        assert_eq!(count(&git).await, 1);
+        assert!(!git.has_update().await);
+
+        std::fs::write(work.join("list.netset"), "10.0.0.0/8\n192.168.0.0/16\n").unwrap();
+
+        run(&work, &["commit", "-q", "-am", "Extend list"]);
+        run(&work, &["push", "-q", "origin", "main"]);
+
+        assert!(git.has_update().await);
+        assert_eq!(count(&git).await, 2);
+
+        // The checkout is read as last pulled once the remote is gone.
+        std::fs::remove_dir_all(&remote).unwrap();
+
+        assert_eq!(count(&git).await, 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn clone_failure_without_checkout() {
+        let dir = std::env::temp_dir().join(format!("lrthrome-git-missing-{}", std::process::id()));
+
+        let git = source(&dir.join("missing.git"), &dir.join("checkout"));
+
+        assert!(git.iterate_cidr().await.is_err());
+        assert!(!dir.join("checkout").exists());
+    }
+}
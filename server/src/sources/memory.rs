@@ -0,0 +1,116 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::iter;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+
+use cidr::Ipv4Cidr;
+
+use futures::stream;
+
+use crate::config::Mode;
+use crate::error::{LrthromeError, LrthromeResult};
+
+use super::{CidrStream, Fetcher, Normalize};
+
+/// Source of a fixed set of entries, for deterministic tests without network or files.
+pub struct InMemory {
+    entries: Vec<Ipv4Cidr>,
+
+    has_update: AtomicBool,
+
+    name: &'static str,
+
+    normalize: Normalize,
+
+    mode: Mode,
+
+    /// Entries yielded before failing, never failing if absent.
+    fail_after: Option<usize>,
+}
+
+impl InMemory {
+    pub fn new(entries: Vec<Ipv4Cidr>, mode: Mode) -> Self {
+        Self {
+            entries,
+            has_update: AtomicBool::new(true),
+            name: "memory",
+            normalize: Normalize::default(),
+            mode,
+            fail_after: None,
+        }
+    }
+
+    /// Name the source apart from others.
+    pub fn named(mut self, name: &'static str) -> Self {
+        self.name = name;
+
+        self
+    }
+
+    /// Normalize the entries as a configured source would.
+    pub fn normalized(mut self, normalize: Normalize) -> Self {
+        self.normalize = normalize;
+
+        self
+    }
+
+    /// Fail after yielding `n` entries, as a source failing mid-stream would, or outright if 0.
+    pub fn failing_after(mut self, n: usize) -> Self {
+        self.fail_after = Some(n);
+
+        self
+    }
+
+    /// Set the result of subsequent update checks.
+    pub fn set_has_update(&self, has_update: bool) {
+        self.has_update.store(has_update, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl Fetcher for InMemory {
+    async fn has_update(&self) -> bool {
+        self.has_update.load(Ordering::Relaxed)
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn normalize(&self) -> &Normalize {
+        &self.normalize
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+        let entries = self.entries.clone().into_iter().map(|c| Ok(c.into()));
+        let failure = || LrthromeError::SourceUnavailable(self.name.to_string());
+
+        match self.fail_after {
+            Some(0) => Err(failure()),
+            Some(n) => Ok(Box::pin(stream::iter(
+                entries.take(n).chain(iter::once(Err(failure()))),
+            ))),
+            None => Ok(Box::pin(stream::iter(entries))),
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Enumeration of MaxMind DB (`.mmdb`) networks of either family, by the `maxminddb` crate.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use cidr::{Cidr, IpCidr};
+
+use ipnetwork::IpNetwork;
+
+use maxminddb::Reader;
+
+use serde::Deserialize;
+
+use crate::error::LrthromeResult;
+
+/// Id a record is matched on.
+#[derive(Clone, Copy)]
+pub enum Field {
+    /// Geoname id of the city or country, cities & countries alike.
+    Geoname,
+
+    /// Autonomous system number.
+    Asn,
+}
+
+/// Fields of a record that are matched on, the rest left undecoded.
+#[derive(Deserialize)]
+struct Record {
+    city: Option<Geoname>,
+
+    country: Option<Geoname>,
+
+    autonomous_system_number: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Geoname {
+    geoname_id: Option<u32>,
+}
+
+impl Record {
+    fn ids(&self, field: Field) -> Vec<u32> {
+        match field {
+            Field::Geoname => [&self.city, &self.country]
+                .iter()
+                .filter_map(|g| g.as_ref().and_then(|g| g.geoname_id))
+                .collect(),
+            Field::Asn => self.autonomous_system_number.into_iter().collect(),
+        }
+    }
+}
+
+/// Networks of either family whose record's field is within `ids`.
+///
+/// The database is read into memory in full, and dropped once walked.
+pub fn networks<P: AsRef<Path>>(
+    path: P,
+    field: Field,
+    ids: &HashMap<String, ()>,
+) -> LrthromeResult<Vec<IpCidr>> {
+    let reader = Reader::open_readfile(path)?;
+
+    // IPv4 space embedded in IPv6 databases is yielded as IPv4 networks.
+    let root = match reader.metadata.ip_version {
+        6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    let root = IpNetwork::new(root, 0).expect("Default route is a valid network");
+
+    let mut networks = Vec::new();
+
+    for item in reader.within::<Record>(root)? {
+        let item = item?;
+
+        let matched = item
+            .info
+            .ids(field)
+            .iter()
+            .any(|id| ids.contains_key(&id.to_string()));
+
+        if matched {
+            networks.extend(IpCidr::new(item.ip_net.network(), item.ip_net.prefix()).ok());
+        }
+    }
+
+    Ok(networks)
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[test]
+    fn record_ids() {
+        let geoname = |id| {
+            Some(Geoname {
+                geoname_id: Some(id),
+            })
+        };
+
+        let record = Record {
+            city: geoname(4180439),
+            country: geoname(6252001),
+            autonomous_system_number: None,
+        };
+
+        assert_eq!(record.ids(Field::Geoname), [4180439, 6252001]);
+        assert!(record.ids(Field::Asn).is_empty());
+
+        let record = Record {
+            city: None,
+            country: None,
+            autonomous_system_number: Some(13335),
+        };
+
+        assert!(record.ids(Field::Geoname).is_empty());
+        assert_eq!(record.ids(Field::Asn), [13335]);
+    }
+}
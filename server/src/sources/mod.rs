@@ -14,44 +14,361 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use async_trait::async_trait;
 
-use cidr::Ipv4Cidr;
+use cidr::{Cidr, IpCidr};
+
+use futures::Stream;
 
-use crate::error::LrthromeResult;
+use crate::config::{Mode, Sources as SourcesConfig};
+use crate::error::{LrthromeError, LrthromeResult};
+use crate::metrics::Metrics;
 
+mod bogon;
+mod breaker;
 mod geolite;
+mod git;
+#[cfg(test)]
+mod memory;
+mod mmdb;
+mod normalize;
+mod parse;
 mod remote;
 
+pub use bogon::Bogon;
+pub use breaker::Breaker;
 pub use geolite::GeoLite;
-pub use remote::Remote;
+pub use git::Git;
+#[cfg(test)]
+pub use memory::InMemory;
+pub use normalize::Normalize;
+pub use remote::{Remote, DEFAULT_FETCH_TIMEOUT};
+
+/// Stream of CIDRs of either family yielded by a fetcher as they become available.
+///
+/// An error item aborts the remainder of that fetcher's stream.
+pub type CidrStream = Pin<Box<dyn Stream<Item = LrthromeResult<IpCidr>> + Send>>;
 
 #[async_trait]
-pub trait Fetcher {
+pub trait Fetcher: Send + Sync {
     /// Check if fetcher has update available.
     ///
-    /// If false, the fetcher will be skipped
+    /// If no fetcher has an update, temper is skipped and the tree retained.
+    /// Otherwise every fetcher is iterated, as the tree is rebuilt from scratch.
     async fn has_update(&self) -> bool;
 
-    async fn iterate_cidr(&self) -> LrthromeResult<Box<dyn Iterator<Item = Ipv4Cidr>>>;
+    /// Name the fetcher is logged & labeled in metrics by.
+    fn name(&self) -> &str;
+
+    /// Normalization applied to entries yielded by the fetcher.
+    fn normalize(&self) -> &Normalize;
+
+    /// Whether the fetcher's entries are blocked or allowed.
+    fn mode(&self) -> Mode {
+        Mode::Deny
+    }
+
+    /// Priority over sources of another mode listing the same prefix, higher winning.
+    ///
+    /// Allowed prefixes win ties.
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    /// Whether the fetcher is configured to yield anything at all,
+    /// such as a GeoLite source with IDs to match in a database that exists.
+    fn usable(&self) -> bool {
+        true
+    }
+
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream>;
 }
 
+/// Bitmask of sources by their registration order.
+pub type SourceSet = u64;
+
 pub struct Sources {
     sources: Vec<Box<dyn Fetcher>>,
+
+    /// Refresh interval & state of each source, in registration order.
+    refresh: Vec<Refresh>,
+
+    /// Broadest mask length allowed into the tree, regardless of source.
+    prefix_floor: u8,
+
+    /// Consecutive failures & cooldown of the circuit breaker sources are registered behind.
+    breaker: Option<(u32, Duration, Arc<Metrics>)>,
+
+    /// Keep denied prefixes in a tree per source, rather than merging them.
+    per_source_trees: bool,
+
+    /// Collapse & merge each source's prefixes before insertion.
+    aggregate: bool,
+}
+
+/// Refresh interval & state of a source.
+#[derive(Default)]
+struct Refresh {
+    /// Interval of the source's own, refreshed along `cache_ttl` otherwise.
+    ttl: Option<Duration>,
+
+    /// Start of the temper the source was last refreshed by, none if never.
+    last: Mutex<Option<Instant>>,
 }
 
 impl Sources {
     pub fn new() -> Self {
         Self {
             sources: Vec::new(),
+            refresh: Vec::new(),
+
+            // Default to refusing only the default route.
+            prefix_floor: 1,
+            breaker: None,
+            per_source_trees: false,
+            aggregate: false,
+        }
+    }
+
+    /// Register the sources configured, on startup & reload alike.
+    pub fn from_config(config: SourcesConfig, metrics: Arc<Metrics>) -> LrthromeResult<Self> {
+        let mut sources = Self::new();
+
+        sources
+            .prefix_floor(config.prefix_floor)
+            .per_source_trees(config.per_source_trees)
+            .aggregate_cidrs(config.aggregate_cidrs)
+            .breaker(
+                config.breaker_threshold,
+                Duration::from_secs(config.breaker_cooldown_secs),
+                metrics.clone(),
+            );
+
+        for remote in config.remotes {
+            let mut remote = Remote::new(remote, metrics.clone())?;
+
+            remote
+                .retry(
+                    config.fetch_retries,
+                    Duration::from_millis(config.fetch_retry_base_ms),
+                )
+                .fetch_timeout(Duration::from_secs(config.fetch_timeout_secs))?;
+
+            sources.register(Box::new(remote));
+        }
+
+        sources.register(Box::new(GeoLite::new(config.geolite)));
+
+        for git in config.git {
+            sources.register(Box::new(Git::new(git)));
+        }
+
+        if config.bogon.enabled {
+            sources.register(Box::new(Bogon::new(config.bogon)));
+        }
+
+        for (name, secs) in config.refresh_secs {
+            if !sources.refresh_every(&name, Duration::from_secs(secs as u64)) {
+                warn!(
+                    "Ignored refresh interval of an unknown source (source = {})",
+                    name
+                );
+            }
         }
+
+        Ok(sources)
+    }
+
+    pub fn prefix_floor(&mut self, floor: u8) -> &mut Self {
+        self.prefix_floor = floor;
+
+        self
+    }
+
+    /// Keep denied prefixes in a tree per source, each queried at lookup,
+    /// trading lookup speed for the structure of each source.
+    pub fn per_source_trees(&mut self, enabled: bool) -> &mut Self {
+        self.per_source_trees = enabled;
+
+        self
+    }
+
+    /// Collapse duplicate & contained prefixes of each source, and merge adjacent ones,
+    /// trading the exact prefix reported for a smaller tree.
+    pub fn aggregate_cidrs(&mut self, enabled: bool) -> &mut Self {
+        self.aggregate = enabled;
+
+        self
+    }
+
+    /// Register sources behind a circuit breaker, only applying to sources registered after.
+    ///
+    /// A threshold of 0 disables the breaker.
+    pub fn breaker(
+        &mut self,
+        threshold: u32,
+        cooldown: Duration,
+        metrics: Arc<Metrics>,
+    ) -> &mut Self {
+        self.breaker = match threshold {
+            0 => None,
+            _ => Some((threshold, cooldown, metrics)),
+        };
+
+        self
     }
 
     pub fn register(&mut self, source: Box<dyn Fetcher>) {
+        let source = match &self.breaker {
+            Some((threshold, cooldown, metrics)) => {
+                Box::new(Breaker::new(source, *threshold, *cooldown, metrics.clone()))
+            }
+            None => source,
+        };
+
         self.sources.push(source);
+        self.refresh.push(Refresh::default());
+    }
+
+    /// Refresh the sources by the name every `ttl`, rather than along `cache_ttl`.
+    ///
+    /// False if no source is registered by the name.
+    pub fn refresh_every(&mut self, name: &str, ttl: Duration) -> bool {
+        let mut found = false;
+
+        for (source, refresh) in self.sources.iter().zip(&mut self.refresh) {
+            if source.name() == name {
+                refresh.ttl = Some(ttl);
+                found = true;
+            }
+        }
+
+        found
+    }
+
+    /// Interval the sources are to be checked at, the shortest of theirs & `cache_ttl`.
+    ///
+    /// None if neither is set, the sources only being fetched upon startup.
+    pub fn refresh_interval(&self, cache_ttl: Option<Duration>) -> Option<Duration> {
+        self.refresh
+            .iter()
+            .filter_map(|refresh| refresh.ttl)
+            .chain(cache_ttl)
+            .min()
+    }
+
+    /// Whether the source at the index is due for a refresh by the temper started at `now`.
+    ///
+    /// Ticks landing early by jitter count, as long as half the interval has passed.
+    /// Sources beyond the first 64 are always due, as their entries cannot be told apart in the tree.
+    pub fn due(&self, index: usize, cache_ttl: Option<Duration>, now: Instant) -> bool {
+        let refresh = &self.refresh[index];
+
+        let last = match *refresh.last.lock().unwrap() {
+            Some(last) if Self::source_bit(index) != 0 => last,
+            _ => return true,
+        };
+
+        match refresh.ttl.or(cache_ttl) {
+            Some(ttl) => {
+                let interval = self.refresh_interval(cache_ttl).unwrap_or(ttl);
+
+                now + interval / 2 >= last + ttl
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the entries attributed to the source at the index in the current tree are its own,
+    /// it having been refreshed since registered, so they may be carried over.
+    ///
+    /// Sources beyond the first 64 cannot be told apart in the tree.
+    pub fn retained(&self, index: usize) -> bool {
+        Self::source_bit(index) != 0 && self.refresh[index].last.lock().unwrap().is_some()
+    }
+
+    /// Record the source at the index as refreshed by the temper started at `now`.
+    pub fn refreshed(&self, index: usize, now: Instant) {
+        *self.refresh[index].last.lock().unwrap() = Some(now);
+    }
+
+    /// Mark every source due, such as once the tree they were refreshed into was refused.
+    pub fn expire(&self) {
+        for refresh in &self.refresh {
+            *refresh.last.lock().unwrap() = None;
+        }
     }
 
     pub fn sources(&self) -> &Vec<Box<dyn Fetcher>> {
         &self.sources
     }
+
+    /// Bit attributing entries to the source at the index, within a `SourceSet`.
+    ///
+    /// Only the first 64 sources are attributed, later ones cannot be filtered for.
+    pub fn source_bit(index: usize) -> SourceSet {
+        match index {
+            i if i < 64 => 1 << i,
+            _ => 0,
+        }
+    }
+
+    /// Set of the sources by name, refused should a name match no source.
+    pub fn source_set<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        names: I,
+    ) -> LrthromeResult<SourceSet> {
+        names.into_iter().try_fold(0, |set, name| {
+            let mut named = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter(|(_, source)| source.name() == name)
+                .peekable();
+
+            if named.peek().is_none() {
+                return Err(LrthromeError::UnknownSource(name.to_string()));
+            }
+
+            Ok(named.fold(set, |set, (i, _)| set | Self::source_bit(i)))
+        })
+    }
+
+    /// Names of the sources in the set, in registration order.
+    pub fn names(&self, set: SourceSet) -> Vec<&str> {
+        self.sources
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Self::source_bit(*i) & set != 0)
+            .map(|(_, source)| source.name())
+            .collect()
+    }
+
+    /// Number of sources configured to yield anything at all.
+    pub fn usable(&self) -> usize {
+        self.sources.iter().filter(|source| source.usable()).count()
+    }
+
+    /// Whether denied prefixes are kept in a tree per source.
+    pub fn tree_per_source(&self) -> bool {
+        self.per_source_trees
+    }
+
+    /// Broadest mask length allowed into the tree.
+    pub fn floor(&self) -> u8 {
+        self.prefix_floor
+    }
+
+    pub fn aggregate(&self) -> bool {
+        self.aggregate
+    }
+
+    /// Whether the entry is specific enough to be inserted into the tree.
+    pub fn above_floor(&self, cidr: &IpCidr) -> bool {
+        cidr.network_length() >= self.prefix_floor
+    }
 }
@@ -0,0 +1,215 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+
+use cidr::{Cidr, IpCidr, Ipv4Cidr};
+
+use super::parse;
+use crate::config::Normalize as NormalizeConfig;
+
+/// Number of invalid lines kept as samples per temper.
+const MAX_INVALID_SAMPLES: usize = 5;
+
+/// Lines of a source that failed to parse, the first few kept with their line number.
+#[derive(Default, Debug)]
+pub struct InvalidLines {
+    /// Lines read in all, blank & comment lines included.
+    pub lines: usize,
+
+    pub count: usize,
+
+    pub samples: Vec<(usize, String)>,
+}
+
+/// Per-source normalization applied as CIDRs flow from a fetcher into the tree.
+#[derive(Clone)]
+pub struct Normalize {
+    /// Broadest mask length accepted from the source.
+    min_mask: u8,
+
+    /// Narrowest mask length accepted from the source.
+    max_mask: u8,
+
+    /// Accept entries with host bits set, truncating them to their network.
+    ///
+    /// `1.2.3.4/24` becomes `1.2.3.0/24` rather than being rejected.
+    host_bits: bool,
+
+    /// Lines that failed to parse since last taken, shared by clones handed to streams.
+    invalid: Arc<Mutex<InvalidLines>>,
+}
+
+impl Normalize {
+    pub fn new(config: NormalizeConfig) -> Self {
+        Self {
+            min_mask: config.min_mask,
+            max_mask: config.max_mask,
+            host_bits: config.normalize_host_bits,
+            invalid: Arc::default(),
+        }
+    }
+
+    /// Parse a single entry of either family, normalizing host bits if configured to.
+    ///
+    /// A `start-end` range yields the prefixes covering it.
+    pub fn parse(&self, s: &str) -> Option<Vec<IpCidr>> {
+        parse::entry(s, self.host_bits)
+    }
+
+    /// Parse a line of a list, recording it as invalid if it fails to parse.
+    ///
+    /// Blank & comment lines are skipped without being recorded.
+    pub fn parse_line(&self, line_no: usize, line: &str) -> Option<Vec<IpCidr>> {
+        self.invalid.lock().unwrap().lines += 1;
+
+        if is_blank(line) {
+            return None;
+        }
+
+        let cidr = self.parse(line.trim());
+
+        if cidr.is_none() {
+            let mut invalid = self.invalid.lock().unwrap();
+
+            invalid.count += 1;
+
+            if invalid.samples.len() < MAX_INVALID_SAMPLES {
+                invalid.samples.push((line_no, line.to_string()));
+            }
+        }
+
+        cidr
+    }
+
+    /// Lines recorded as invalid since last taken.
+    pub fn take_invalid(&self) -> InvalidLines {
+        std::mem::take(&mut *self.invalid.lock().unwrap())
+    }
+
+    /// Whether the IPv4 entry falls within the configured mask bounds.
+    ///
+    /// The bounds are IPv4 mask lengths, IPv6 entries are not bounded.
+    pub fn admits(&self, cidr: &Ipv4Cidr) -> bool {
+        let len = cidr.network_length();
+
+        len >= self.min_mask && len <= self.max_mask
+    }
+
+    pub fn min_mask(&self) -> u8 {
+        self.min_mask
+    }
+
+    pub fn max_mask(&self) -> u8 {
+        self.max_mask
+    }
+}
+
+/// Whether the line is blank or a comment, starting with `#` or `;`.
+pub fn is_blank(line: &str) -> bool {
+    let trimmed = line.trim();
+
+    trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';')
+}
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Self::new(NormalizeConfig::default())
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::str::FromStr;
+
+    #[test]
+    fn parse_host_bits() {
+        let strict = Normalize::default();
+
+        assert!(strict.parse("1.2.3.4/24").is_none());
+
+        let lenient = Normalize::new(NormalizeConfig {
+            normalize_host_bits: true,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            lenient.parse("1.2.3.4/24"),
+            IpCidr::from_str("1.2.3.0/24").ok().map(|c| vec![c])
+        );
+        assert_eq!(
+            lenient.parse("2001:db8::1/32"),
+            IpCidr::from_str("2001:db8::/32").ok().map(|c| vec![c])
+        );
+    }
+
+    #[test]
+    fn record_invalid_lines() {
+        let n = Normalize::default();
+        let clone = n.clone();
+
+        assert!(clone.parse_line(1, "# comment").is_none());
+        assert!(clone.parse_line(2, "").is_none());
+        assert!(clone.parse_line(3, " 10.0.0.0/8 ").is_some());
+        assert_eq!(
+            clone.parse_line(3, "10.0.0.0 - 10.0.1.255").unwrap().len(),
+            1
+        );
+        assert!(clone.parse_line(4, "10.0.0.1/8").is_none());
+        assert!(clone.parse_line(5, "10.0.1.0-10.0.0.0").is_none());
+
+        for i in 6..12 {
+            clone.parse_line(i, "garbage");
+        }
+
+        let invalid = n.take_invalid();
+
+        assert_eq!(invalid.lines, 12);
+        assert_eq!(invalid.count, 8);
+        assert_eq!(invalid.samples.len(), MAX_INVALID_SAMPLES);
+        assert_eq!(invalid.samples[0], (4, "10.0.0.1/8".to_string()));
+        assert_eq!(n.take_invalid().count, 0);
+    }
+
+    #[test]
+    fn skip_blank_lines() {
+        let n = Normalize::default();
+
+        for line in &["", "   ", "\t", "# comment", "  # indented", "; comment"] {
+            assert!(n.parse_line(1, line).is_none());
+        }
+
+        assert!(n.parse_line(2, "\t10.0.0.0/8  \r").is_some());
+        assert_eq!(n.take_invalid().count, 0);
+    }
+
+    #[test]
+    fn admits_mask_bounds() {
+        let n = Normalize::new(NormalizeConfig {
+            min_mask: 8,
+            max_mask: 24,
+            ..Default::default()
+        });
+
+        assert!(!n.admits(&Ipv4Cidr::from_str("0.0.0.0/0").unwrap()));
+        assert!(n.admits(&Ipv4Cidr::from_str("10.0.0.0/8").unwrap()));
+        assert!(n.admits(&Ipv4Cidr::from_str("10.1.2.0/24").unwrap()));
+        assert!(!n.admits(&Ipv4Cidr::from_str("10.1.2.3/32").unwrap()));
+    }
+}
@@ -0,0 +1,182 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Parsing of list entries, either CIDRs, bare addresses or dash delimited ranges.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use cidr::{Cidr, Inet, IpCidr, IpInet, Ipv4Cidr, Ipv6Cidr};
+
+/// Parse an entry of either family into the prefixes it spans.
+///
+/// A CIDR yields itself, its host bits truncated if `host_bits`, refused otherwise.
+/// A bare address yields itself as a host, a /32 or a /128.
+/// A range such as `1.2.3.0-1.2.3.255` yields the fewest prefixes covering it exactly.
+pub fn entry(s: &str, host_bits: bool) -> Option<Vec<IpCidr>> {
+    if s.contains('-') {
+        return range(s);
+    }
+
+    // Parsed strictly, rather than as shorthand such as `10` for 10.0.0.0.
+    if !s.contains('/') {
+        return s.parse().ok().map(|addr| vec![IpCidr::new_host(addr)]);
+    }
+
+    let cidr = if host_bits {
+        IpInet::from_str(s).ok().map(|i| i.network())
+    } else {
+        IpCidr::from_str(s).ok()
+    }?;
+
+    Some(vec![cidr])
+}
+
+/// Parse an inclusive range of addresses of the same family, refused if it ends before it starts.
+pub fn range(s: &str) -> Option<Vec<IpCidr>> {
+    let (start, end) = s.split_once('-')?;
+
+    match (start.trim().parse().ok()?, end.trim().parse().ok()?) {
+        (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => Some(
+            covering(u32::from(start) as u128, u32::from(end) as u128, 32)
+                .into_iter()
+                .map(|(network, len)| {
+                    IpCidr::V4(Ipv4Cidr::new(Ipv4Addr::from(network as u32), len).unwrap())
+                })
+                .collect(),
+        ),
+        (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => Some(
+            covering(u128::from(start), u128::from(end), 128)
+                .into_iter()
+                .map(|(network, len)| {
+                    IpCidr::V6(Ipv6Cidr::new(Ipv6Addr::from(network), len).unwrap())
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// Fewest prefixes covering `start..=end` exactly, as networks & mask lengths of `bits` wide addresses.
+fn covering(mut start: u128, end: u128, bits: u32) -> Vec<(u128, u8)> {
+    let ones = |host: u32| match host {
+        128 => u128::MAX,
+        host => (1 << host) - 1,
+    };
+
+    let mut prefixes = Vec::new();
+
+    loop {
+        // Largest block aligned at the start, shrunk until it ends within the range.
+        let mut host = start.trailing_zeros().min(bits);
+
+        while start + ones(host) > end {
+            host -= 1;
+        }
+
+        prefixes.push((start, (bits - host) as u8));
+
+        let last = start + ones(host);
+
+        if last >= end {
+            return prefixes;
+        }
+
+        start = last + 1;
+    }
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[allow(unused_imports)]
+    use std::str::FromStr;
+
+    #[allow(dead_code)]
+    fn cidrs(list: &[&str]) -> Vec<IpCidr> {
+        list.iter().map(|c| IpCidr::from_str(c).unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_cidr() {
+        assert_eq!(entry("10.0.0.0/8", false), Some(cidrs(&["10.0.0.0/8"])));
+        assert_eq!(entry("10.0.0.1/8", false), None);
+        assert_eq!(entry("10.0.0.1/8", true), Some(cidrs(&["10.0.0.0/8"])));
+        assert_eq!(entry("garbage", false), None);
+    }
+
+    #[test]
+    fn parse_bare_address() {
+        assert_eq!(entry("1.2.3.4", false), Some(cidrs(&["1.2.3.4/32"])));
+        assert_eq!(
+            entry("2001:db8::1", false),
+            Some(cidrs(&["2001:db8::1/128"]))
+        );
+        assert_eq!(entry("10", false), None);
+        assert_eq!(entry("10.1", true), None);
+    }
+
+    #[test]
+    fn parse_aligned_range() {
+        assert_eq!(
+            entry("1.2.3.0-1.2.3.255", false),
+            Some(cidrs(&["1.2.3.0/24"]))
+        );
+        assert_eq!(
+            entry("1.2.3.4 - 1.2.3.4", false),
+            Some(cidrs(&["1.2.3.4/32"]))
+        );
+        assert_eq!(
+            range("0.0.0.0-255.255.255.255"),
+            Some(cidrs(&["0.0.0.0/0"]))
+        );
+        assert_eq!(
+            range("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            Some(cidrs(&["::/0"]))
+        );
+    }
+
+    #[test]
+    fn parse_unaligned_range() {
+        assert_eq!(
+            range("1.2.3.1-1.2.3.10"),
+            Some(cidrs(&[
+                "1.2.3.1/32",
+                "1.2.3.2/31",
+                "1.2.3.4/30",
+                "1.2.3.8/31",
+                "1.2.3.10/32",
+            ]))
+        );
+        assert_eq!(
+            range("10.0.0.255-10.0.2.0"),
+            Some(cidrs(&["10.0.0.255/32", "10.0.1.0/24", "10.0.2.0/32"]))
+        );
+        assert_eq!(
+            range("2001:db8::1-2001:db8::3"),
+            Some(cidrs(&["2001:db8::1/128", "2001:db8::2/127"]))
+        );
+    }
+
+    #[test]
+    fn refuse_invalid_range() {
+        assert_eq!(range("1.2.3.10-1.2.3.1"), None);
+        assert_eq!(range("1.2.3.0-2001:db8::"), None);
+        assert_eq!(range("1.2.3.0-"), None);
+        assert_eq!(range("1.2.3.0/24-1.2.4.0/24"), None);
+    }
+}
@@ -14,53 +14,693 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::str::FromStr;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use async_trait::async_trait;
 
-use reqwest::Client;
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 
-use cidr::Ipv4Cidr;
+use bytes::{Bytes, BytesMut};
 
-use crate::error::LrthromeResult;
+use encoding_rs::Encoding;
 
-use super::Fetcher;
+use futures::stream::{self, Stream, StreamExt};
+
+use crate::config::{Mode, Remote as RemoteConfig};
+use crate::error::{LrthromeError, LrthromeResult};
+use crate::metrics::Metrics;
+
+use super::{CidrStream, Fetcher, Normalize};
+
+/// Default seconds a fetch may wait to connect, for response headers, or between body chunks.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest delay between retries of a fetch, however many attempts preceded it.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
 
 pub struct Remote {
+    /// Primary endpoint, followed by its mirrors in order of preference.
     endpoints: Vec<String>,
+
+    /// Reused across refreshes, keeping connections to endpoints alive.
+    client: Client,
+
+    /// Longest wait to connect, for response headers, or between body chunks.
+    ///
+    /// Bounds each wait rather than the whole fetch, so a large list over a slow link still completes.
+    timeout: Duration,
+
+    normalize: Normalize,
+
+    /// Encoding of lines that are not valid UTF-8, refused if absent.
+    fallback: Option<&'static Encoding>,
+
+    /// Sent with every request to every endpoint.
+    headers: HeaderMap,
+
+    mode: Mode,
+
+    priority: i32,
+
+    metrics: Arc<Metrics>,
+
+    /// Validators of the last response of each endpoint, checked before fetching again.
+    validators: Mutex<HashMap<String, Validators>>,
+
+    /// Times a failed fetch of an endpoint is retried, before failing over to the next.
+    retries: u32,
+
+    /// Delay before the first retry, doubling after every attempt up to `MAX_RETRY_BACKOFF`.
+    retry_base: Duration,
+}
+
+/// Response headers identifying the version of a body.
+#[derive(Clone)]
+struct Validators {
+    etag: Option<HeaderValue>,
+
+    last_modified: Option<HeaderValue>,
 }
 
 impl Remote {
-    pub fn new(endpoints: Vec<String>) -> Self {
-        Self { endpoints }
+    pub fn new(config: RemoteConfig, metrics: Arc<Metrics>) -> LrthromeResult<Self> {
+        Ok(match config {
+            RemoteConfig::Url(endpoint) => Self {
+                endpoints: vec![endpoint],
+                client: client(DEFAULT_FETCH_TIMEOUT)?,
+                timeout: DEFAULT_FETCH_TIMEOUT,
+                normalize: Normalize::default(),
+                fallback: None,
+                headers: HeaderMap::new(),
+                mode: Mode::Deny,
+                priority: 0,
+                metrics,
+                validators: Mutex::default(),
+                retries: 0,
+                retry_base: Duration::default(),
+            },
+            RemoteConfig::Table {
+                url,
+                mirrors,
+                encoding,
+                headers,
+                mode,
+                priority,
+                normalize,
+            } => Self {
+                endpoints: std::iter::once(url).chain(mirrors).collect(),
+                client: client(DEFAULT_FETCH_TIMEOUT)?,
+                timeout: DEFAULT_FETCH_TIMEOUT,
+                normalize: Normalize::new(normalize),
+                fallback: match encoding {
+                    Some(label) => Some(
+                        Encoding::for_label(label.as_bytes())
+                            .ok_or(LrthromeError::UnknownEncoding(label))?,
+                    ),
+                    None => None,
+                },
+                headers: header_map(headers)?,
+                mode,
+                priority,
+                metrics,
+                validators: Mutex::default(),
+                retries: 0,
+                retry_base: Duration::default(),
+            },
+        })
+    }
+
+    /// Retry failed fetches of each endpoint with exponential backoff.
+    pub fn retry(&mut self, retries: u32, base: Duration) -> &mut Self {
+        self.retries = retries;
+        self.retry_base = base;
+
+        self
+    }
+
+    pub fn fetch_timeout(&mut self, timeout: Duration) -> LrthromeResult<&mut Self> {
+        self.client = client(timeout)?;
+        self.timeout = timeout;
+
+        Ok(self)
+    }
+
+    /// Send the request, failing on an error status or if headers are not received in time.
+    async fn send(&self, req: RequestBuilder) -> LrthromeResult<Response> {
+        match tokio::time::timeout(self.timeout, req.send()).await {
+            Ok(res) => Ok(res.and_then(|res| res.error_for_status())?),
+            Err(_) => Err(LrthromeError::TimedOut(self.timeout)),
+        }
+    }
+
+    /// Fetch from the first endpoint that succeeds, retrying each before failing over.
+    ///
+    /// Failing over is only possible before the body is streamed,
+    /// an endpoint failing mid-stream aborts the fetch.
+    async fn fetch(&self) -> Option<Response> {
+        for endpoint in &self.endpoints {
+            let mut attempt = 0;
+
+            loop {
+                match self
+                    .send(self.client.get(endpoint).headers(self.headers.clone()))
+                    .await
+                {
+                    Ok(res) => {
+                        info!("Fetching from {}", endpoint);
+
+                        self.record_validators(endpoint, &res);
+
+                        self.metrics.remote_fetches.get(endpoint).inc();
+
+                        return Some(res);
+                    }
+                    Err(e) => {
+                        warn!(
+                            "Unable to fetch {} (attempt = {}): {}",
+                            endpoint,
+                            attempt + 1,
+                            e
+                        );
+
+                        self.metrics.remote_fetch_failures.get(endpoint).inc();
+
+                        if attempt >= self.retries || !transient(&e) {
+                            break;
+                        }
+                    }
+                }
+
+                tokio::time::sleep(backoff(self.retry_base, attempt)).await;
+
+                attempt += 1;
+            }
+        }
+
+        None
+    }
+
+    fn record_validators(&self, endpoint: &str, res: &Response) {
+        let validators = Validators {
+            etag: res.headers().get(ETAG).cloned(),
+            last_modified: res.headers().get(LAST_MODIFIED).cloned(),
+        };
+
+        let mut all = self.validators.lock().unwrap();
+
+        if validators.etag.is_none() && validators.last_modified.is_none() {
+            all.remove(endpoint);
+        } else {
+            all.insert(endpoint.to_string(), validators);
+        }
+    }
+
+    /// Whether the endpoint that would be fetched from answers that its body is unchanged.
+    ///
+    /// Endpoints are tried in the order they are fetched from,
+    /// the first to answer decides, whether it was the one last fetched from or not.
+    async fn not_modified(&self) -> bool {
+        for endpoint in &self.endpoints {
+            let validators = self.validators.lock().unwrap().get(endpoint).cloned();
+
+            let mut req = self.client.head(endpoint).headers(self.headers.clone());
+
+            if let Some(validators) = &validators {
+                if let Some(etag) = &validators.etag {
+                    req = req.header(IF_NONE_MATCH, etag.clone());
+                }
+
+                if let Some(last_modified) = &validators.last_modified {
+                    req = req.header(IF_MODIFIED_SINCE, last_modified.clone());
+                }
+            }
+
+            match self.send(req).await {
+                Ok(res) => {
+                    let unchanged = res.status() == StatusCode::NOT_MODIFIED;
+
+                    if unchanged {
+                        debug!("{} is not modified since last fetched", endpoint);
+                    }
+
+                    return unchanged;
+                }
+                Err(e) => debug!("Unable to check {} for updates: {}", endpoint, e),
+            }
+        }
+
+        false
     }
 }
 
 #[async_trait]
 impl Fetcher for Remote {
-    // It is uncertain until the file is fetched again
-    // Not all endpoints has E-tag to verify
+    // Endpoints without validators are assumed updated,
+    // as are endpoints that could not be checked, leaving failing over to the fetch.
     async fn has_update(&self) -> bool {
-        true
+        !self.not_modified().await
     }
 
-    async fn iterate_cidr(&self) -> LrthromeResult<Box<dyn Iterator<Item = Ipv4Cidr>>> {
-        let client = Client::new();
+    async fn iterate_cidr(&self) -> LrthromeResult<CidrStream> {
+        let res = self
+            .fetch()
+            .await
+            .ok_or_else(|| LrthromeError::SourceUnavailable(self.name().to_string()))?;
 
-        let mut cidrs = Vec::new();
+        let url = res.url().to_string();
+        let normalize = self.normalize.clone();
+        let fallback = self.fallback;
 
-        for endpoint in &self.endpoints {
-            if let Ok(res) = client.get(endpoint).send().await {
-                if let Ok(resp) = res.text().await {
-                    for line in resp.lines() {
-                        if let Ok(cidr) = Ipv4Cidr::from_str(line) {
-                            cidrs.push(cidr);
+        // An undecodable line aborts the stream, rather than being mistaken for an empty feed.
+        let cidrs = lines(res, self.timeout)
+            .enumerate()
+            .flat_map(move |(i, line)| {
+                stream::iter(match line {
+                    Ok(line) => match decode(&line, fallback) {
+                        Some(l) => normalize
+                            .parse_line(i + 1, &l)
+                            .into_iter()
+                            .flatten()
+                            .map(Ok)
+                            .collect(),
+                        None => vec![Err(LrthromeError::UndecodableSource {
+                            url: url.clone(),
+                            line: i + 1,
+                        })],
+                    },
+                    Err(e) => vec![Err(e)],
+                })
+            });
+
+        Ok(Box::pin(cidrs))
+    }
+
+    fn name(&self) -> &str {
+        &self.endpoints[0]
+    }
+
+    fn normalize(&self) -> &Normalize {
+        &self.normalize
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    fn usable(&self) -> bool {
+        self.endpoints.iter().any(|url| !url.trim().is_empty())
+    }
+}
+
+/// Configured headers, refusing invalid names or values at startup rather than upon fetching.
+///
+/// Values are never logged, as they commonly hold credentials.
+fn header_map(headers: HashMap<String, String>) -> LrthromeResult<HeaderMap> {
+    headers
+        .into_iter()
+        .map(|(name, value)| {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| LrthromeError::InvalidHeader(name.clone()))?;
+            let mut value =
+                HeaderValue::from_str(&value).map_err(|_| LrthromeError::InvalidHeader(name))?;
+
+            value.set_sensitive(true);
+
+            Ok((header, value))
+        })
+        .collect()
+}
+
+fn client(timeout: Duration) -> LrthromeResult<Client> {
+    Ok(Client::builder().connect_timeout(timeout).build()?)
+}
+
+/// Whether a failed fetch may succeed if retried.
+///
+/// Client errors other than being rate limited would only fail again.
+fn transient(e: &LrthromeError) -> bool {
+    match e {
+        LrthromeError::ReqwestError(e) => match e.status() {
+            Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+            None => true,
+        },
+        _ => true,
+    }
+}
+
+/// Decode a line as UTF-8, or with the fallback encoding if it is not valid UTF-8.
+fn decode<'a>(line: &'a [u8], fallback: Option<&'static Encoding>) -> Option<Cow<'a, str>> {
+    match std::str::from_utf8(line) {
+        Ok(l) => Some(Cow::Borrowed(l)),
+        Err(_) => fallback.map(|encoding| encoding.decode_without_bom_handling(line).0),
+    }
+}
+
+/// Split a response body into lines as its chunks arrive,
+/// without buffering the entire body.
+///
+/// Line terminators, `\n` or `\r\n`, are stripped.
+/// Fails if the next chunk does not arrive within the timeout.
+fn lines(res: Response, timeout: Duration) -> impl Stream<Item = LrthromeResult<Bytes>> {
+    stream::unfold(
+        (Some(res), BytesMut::new()),
+        move |(mut res, mut buf)| async move {
+            loop {
+                if let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                    let line = buf.split_to(pos + 1);
+
+                    return Some((Ok(strip_terminator(line)), (res, buf)));
+                }
+
+                match res.as_mut() {
+                    Some(r) => match tokio::time::timeout(timeout, r.chunk()).await {
+                        Ok(Ok(Some(chunk))) => buf.extend_from_slice(&chunk),
+                        Ok(Ok(None)) => res = None,
+                        Ok(Err(e)) => return Some((Err(e.into()), (None, BytesMut::new()))),
+                        Err(_) => {
+                            return Some((
+                                Err(LrthromeError::TimedOut(timeout)),
+                                (None, BytesMut::new()),
+                            ))
                         }
+                    },
+                    // Body exhausted, yield the final unterminated line if any.
+                    None if !buf.is_empty() => {
+                        let line = buf.split();
+
+                        return Some((Ok(strip_terminator(line)), (None, buf)));
                     }
+                    None => return None,
                 }
             }
-        }
+        },
+    )
+}
+
+/// Delay before the retry following `attempt`, doubling the base per attempt.
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    base.checked_mul(2u32.saturating_pow(attempt))
+        .unwrap_or(MAX_RETRY_BACKOFF)
+        .min(MAX_RETRY_BACKOFF)
+}
+
+fn strip_terminator(mut line: BytesMut) -> Bytes {
+    if line.ends_with(b"\n") {
+        line.truncate(line.len() - 1);
+    }
+
+    if line.ends_with(b"\r") {
+        line.truncate(line.len() - 1);
+    }
+
+    line.freeze()
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn conditional_fetch() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.netset", listener.local_addr().unwrap());
+
+        // Answers with an unchanged body to requests carrying its ETag.
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+
+                let res = if req.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    let body = "10.0.0.0/8\n";
+
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        if req.starts_with("head") { "" } else { body },
+                    )
+                };
+
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+
+        let remote = Remote::new(RemoteConfig::Url(url), Arc::default()).unwrap();
+
+        assert!(remote.has_update().await);
+
+        let cidrs = remote
+            .iterate_cidr()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(cidrs.len(), 1);
+        assert!(!remote.has_update().await);
+    }
+
+    #[tokio::test]
+    async fn iterate_mixed_entries() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.netset", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+
+            assert!(n > 0);
+
+            let body = "# mixed\n10.0.0.0/8\n1.2.3.4\n2001:db8::1\n192.168.0.1-192.168.0.2\n";
+
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body,
+            );
+
+            stream.write_all(res.as_bytes()).await.unwrap();
+        });
+
+        let remote = Remote::new(RemoteConfig::Url(url), Arc::default()).unwrap();
+
+        let cidrs = remote
+            .iterate_cidr()
+            .await
+            .unwrap()
+            .map(|c| c.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        let expected = [
+            "10.0.0.0/8",
+            "1.2.3.4/32",
+            "2001:db8::1/128",
+            "192.168.0.1/32",
+            "192.168.0.2/32",
+        ];
+
+        assert_eq!(
+            cidrs,
+            expected
+                .iter()
+                .map(|c| c.parse().unwrap())
+                .collect::<Vec<cidr::IpCidr>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_transient_failures() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.netset", listener.local_addr().unwrap());
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let served = requests.clone();
+
+        // Unavailable for the first two requests.
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+
+                let res = match served.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    _ => "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\n10.0.0.0/8\n",
+                };
+
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+
+        let mut remote = Remote::new(RemoteConfig::Url(url), Arc::default()).unwrap();
+
+        remote.retry(1, Duration::from_millis(1));
+
+        assert!(remote.iterate_cidr().await.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        remote.retry(2, Duration::from_millis(1));
+
+        let cidrs = remote
+            .iterate_cidr()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(cidrs.len(), 1);
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn send_headers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.netset", listener.local_addr().unwrap());
+
+        // Refuses requests without the key.
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let req = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+
+                let res = if req.contains("x-api-key: secret") {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 11\r\nConnection: close\r\n\r\n10.0.0.0/8\n"
+                } else {
+                    "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                };
+
+                stream.write_all(res.as_bytes()).await.unwrap();
+            }
+        });
+
+        let remote = |headers: &str| {
+            let config: RemoteConfig =
+                toml::from_str(&format!("url = \"{}\"\nheaders = {{ {} }}", url, headers)).unwrap();
+
+            Remote::new(config, Arc::default())
+        };
+
+        assert!(remote("").unwrap().iterate_cidr().await.is_err());
+
+        let cidrs = remote(r#"X-Api-Key = "secret""#)
+            .unwrap()
+            .iterate_cidr()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(cidrs.len(), 1);
+        assert!(matches!(
+            remote(r#"X-Api-Key = "new\nline""#),
+            Err(LrthromeError::InvalidHeader(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetch_timeout() {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.netset", listener.local_addr().unwrap());
+
+        // Headers & the first line are sent, the rest of the body never is.
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 64\r\n\r\n10.0.0.0/8\n")
+                    .await
+                    .unwrap();
+
+                held.push(stream);
+            }
+        });
+
+        let mut remote = Remote::new(RemoteConfig::Url(url), Arc::default()).unwrap();
+
+        remote.fetch_timeout(Duration::from_millis(50)).unwrap();
+
+        let cidrs = remote
+            .iterate_cidr()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(cidrs.len(), 2);
+        assert!(cidrs[0].is_ok());
+        assert!(matches!(cidrs[1], Err(LrthromeError::TimedOut(_))));
+    }
+
+    #[test]
+    fn cap_backoff() {
+        let base = Duration::from_millis(500);
+
+        assert_eq!(backoff(base, 0), base);
+        assert_eq!(backoff(base, 3), Duration::from_secs(4));
+        assert_eq!(backoff(base, 31), MAX_RETRY_BACKOFF);
+        assert_eq!(backoff(Duration::from_secs(u64::MAX), 1), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn strip_line_terminators() {
+        let strip = |line: &[u8]| strip_terminator(BytesMut::from(line));
+
+        assert_eq!(&strip(b"1.2.3.0/24\n")[..], b"1.2.3.0/24");
+        assert_eq!(&strip(b"1.2.3.0/24\r\n")[..], b"1.2.3.0/24");
+        assert_eq!(&strip(b"1.2.3.0/24")[..], b"1.2.3.0/24");
+        assert_eq!(&strip(b"\n")[..], b"");
+    }
+
+    #[test]
+    fn decode_fallback() {
+        let latin1 = b"1.2.3.0/24 # M\xfcnchen";
 
-        Ok(Box::new(cidrs.into_iter()))
+        assert_eq!(decode(b"1.2.3.0/24", None).as_deref(), Some("1.2.3.0/24"));
+        assert_eq!(decode(latin1, None), None);
+        assert_eq!(
+            decode(latin1, Encoding::for_label(b"latin1")).as_deref(),
+            Some("1.2.3.0/24 # M\u{fc}nchen")
+        );
     }
 }
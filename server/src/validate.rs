@@ -0,0 +1,306 @@
+// Lrthrome - Fast and light TCP-server based IPv4 CIDR filter lookup server over minimal binary protocol, and memory footprint
+// Copyright (C) 2021  rumblefrog
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Offline check of a source's output, without starting the server.
+//!
+//! Sources are built from the config & read as a temper reads them, so the report reflects
+//! what would make it into the tree, and what would be silently skipped.
+
+use std::fmt::Write;
+
+use cidr::{Cidr, IpCidr};
+
+use futures::StreamExt;
+
+use crate::config::{Remote as RemoteConfig, Sources as SourcesConfig};
+use crate::error::LrthromeResult;
+use crate::sources::{Fetcher, Sources};
+
+/// Mask lengths at or below this are reported as broad.
+const BROAD_MASK: u8 = 8;
+
+pub struct Report {
+    /// Lines read from the source, blank & comment lines included.
+    lines: usize,
+
+    /// Parsed entries, a range counting as the prefixes covering it.
+    parsed: usize,
+
+    /// Parsed entries that are IPv6.
+    ipv6: usize,
+
+    /// Parsed IPv4 entries outside the source's mask bounds, dropped from the tree.
+    out_of_bounds: usize,
+
+    /// Parsed entries broader than the prefix floor, refused from the tree.
+    below_floor: usize,
+
+    failures: usize,
+
+    /// Line number & content of the first failed lines.
+    samples: Vec<(usize, String)>,
+
+    /// Number of parsed IPv4 entries per mask length, IPv6 default routes included.
+    masks: [usize; 33],
+}
+
+impl Report {
+    /// Read the source's entries as a temper would, failing should the source fail.
+    pub async fn read(source: &dyn Fetcher, sources: &Sources) -> LrthromeResult<Self> {
+        let mut report = Self {
+            lines: 0,
+            parsed: 0,
+            ipv6: 0,
+            out_of_bounds: 0,
+            below_floor: 0,
+            failures: 0,
+            samples: Vec::new(),
+            masks: [0; 33],
+        };
+
+        let mut stream = source.iterate_cidr().await?;
+
+        while let Some(cidr) = stream.next().await {
+            let cidr = cidr?;
+
+            report.parsed += 1;
+
+            if !sources.above_floor(&cidr) {
+                report.below_floor += 1;
+            }
+
+            match cidr {
+                IpCidr::V4(cidr) => {
+                    report.masks[cidr.network_length() as usize] += 1;
+
+                    if !source.normalize().admits(&cidr) {
+                        report.out_of_bounds += 1;
+                    }
+                }
+                IpCidr::V6(cidr) => {
+                    report.ipv6 += 1;
+
+                    if cidr.network_length() == 0 {
+                        report.masks[0] += 1;
+                    }
+                }
+            }
+        }
+
+        let invalid = source.normalize().take_invalid();
+
+        report.lines = invalid.lines;
+        report.failures = invalid.count;
+        report.samples = invalid.samples;
+
+        Ok(report)
+    }
+
+    /// Entries at or broader than `BROAD_MASK`, default routes included.
+    pub fn broad(&self) -> usize {
+        self.masks[..=BROAD_MASK as usize].iter().sum()
+    }
+
+    /// Whether the source looks fit to be wired in, yielding entries without failures or default routes.
+    pub fn sane(&self) -> bool {
+        self.parsed > 0 && self.failures == 0 && self.masks[0] == 0
+    }
+
+    pub fn to_text(&self, source: &str) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "Source: {}", source);
+        let _ = writeln!(out, "Lines: {}", self.lines);
+        let _ = writeln!(out, "Parsed: {}", self.parsed);
+        let _ = writeln!(out, "IPv6: {}", self.ipv6);
+        let _ = writeln!(out, "Out of mask bounds: {}", self.out_of_bounds);
+        let _ = writeln!(out, "Below prefix floor: {}", self.below_floor);
+        let _ = writeln!(out, "Failures: {}", self.failures);
+
+        for (line, content) in &self.samples {
+            let _ = writeln!(out, "    line {}: {:?}", line, content);
+        }
+
+        let _ = writeln!(out, "Mask lengths:");
+
+        for (len, count) in self.masks.iter().enumerate().filter(|(_, c)| **c > 0) {
+            let _ = writeln!(out, "    /{}: {}", len, count);
+        }
+
+        let _ = writeln!(
+            out,
+            "Broad prefixes (/{} or broader): {}",
+            BROAD_MASK,
+            self.broad()
+        );
+        let _ = writeln!(out, "Default routes: {}", self.masks[0]);
+
+        out
+    }
+
+    pub fn to_json(&self, source: &str) -> String {
+        let samples = self
+            .samples
+            .iter()
+            .map(|(line, content)| format!("{{\"line\":{},\"content\":{}}}", line, quote(content)))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let masks = self
+            .masks
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(len, count)| format!("\"{}\":{}", len, count))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            "{{\"source\":{},\"lines\":{},\"parsed\":{},\"out_of_bounds\":{},\"below_floor\":{},\"failures\":{},\"samples\":[{}],\"masks\":{{{}}},\"broad\":{},\"default_routes\":{},\"ipv6\":{}}}",
+            quote(source),
+            self.lines,
+            self.parsed,
+            self.out_of_bounds,
+            self.below_floor,
+            self.failures,
+            samples,
+            masks,
+            self.broad(),
+            self.masks[0],
+            self.ipv6,
+        )
+    }
+}
+
+/// Configure each URL not already a remote or Git source as a remote,
+/// fetched with the configured fetch options like any other.
+pub fn configure_urls<'a, I: IntoIterator<Item = &'a str>>(config: &mut SourcesConfig, urls: I) {
+    for url in urls {
+        let configured = config.remotes.iter().any(|r| match r {
+            RemoteConfig::Url(u) | RemoteConfig::Table { url: u, .. } => u == url,
+        }) || config.git.iter().any(|g| g.url == url);
+
+        if !configured && (url.starts_with("http://") || url.starts_with("https://")) {
+            config.remotes.push(RemoteConfig::Url(url.to_string()));
+        }
+    }
+}
+
+/// JSON string literal.
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+
+    out.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+
+    out
+}
+
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[tokio::test]
+    async fn report_remote() {
+        use std::sync::Arc;
+
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        use crate::sources::Remote;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.netset", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0; 4096];
+            let n = stream.read(&mut buf).await.unwrap();
+
+            assert!(n > 0);
+
+            let body = "# comment\n\n1.2.3.0/24\n10.0.0.0/8\n0.0.0.0/0\n1.2.3.4/24\nnot a cidr\n2001:db8::/32\n";
+
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body,
+            );
+
+            stream.write_all(res.as_bytes()).await.unwrap();
+        });
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(
+            Remote::new(RemoteConfig::Url(url), Arc::default()).unwrap(),
+        ));
+
+        let report = Report::read(sources.sources()[0].as_ref(), &sources)
+            .await
+            .unwrap();
+
+        assert_eq!(report.lines, 8);
+        assert_eq!(report.parsed, 4);
+        assert_eq!(report.ipv6, 1);
+        assert_eq!(report.below_floor, 1);
+        assert_eq!(report.failures, 2);
+        assert_eq!(
+            report.samples,
+            vec![(6, "1.2.3.4/24".to_string()), (7, "not a cidr".to_string())]
+        );
+        assert_eq!(report.broad(), 2);
+        assert!(!report.sane());
+
+        assert_eq!(
+            report.to_json("list.netset"),
+            "{\"source\":\"list.netset\",\"lines\":8,\"parsed\":4,\"out_of_bounds\":0,\"below_floor\":1,\"failures\":2,\
+             \"samples\":[{\"line\":6,\"content\":\"1.2.3.4/24\"},{\"line\":7,\"content\":\"not a cidr\"}],\
+             \"masks\":{\"0\":1,\"8\":1,\"24\":1},\"broad\":2,\"default_routes\":1,\"ipv6\":1}"
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_source_not_sane() {
+        use crate::config::Mode;
+        use crate::sources::InMemory;
+
+        let mut sources = Sources::new();
+
+        sources.register(Box::new(InMemory::new(Vec::new(), Mode::Deny)));
+
+        let report = Report::read(sources.sources()[0].as_ref(), &sources)
+            .await
+            .unwrap();
+
+        assert_eq!(report.parsed, 0);
+        assert_eq!(report.failures, 0);
+        assert!(!report.sane());
+    }
+}